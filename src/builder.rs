@@ -0,0 +1,140 @@
+//! A builder for [`CTRConfig`], for packaging executables from other tools
+//! without going through `cargo 3ds` and its package metadata.
+
+use std::env;
+
+use camino::Utf8PathBuf;
+
+use crate::CTRConfig;
+
+/// Builds a [`CTRConfig`] for an executable programmatically, with the same
+/// settings as `[package.metadata.cargo-3ds]`. Relative paths are resolved
+/// from the [`manifest_dir`](Self::manifest_dir), which defaults to the current
+/// directory.
+///
+/// ```no_run
+/// use cargo_3ds::CTRConfig;
+///
+/// let config = CTRConfig::builder("target/armv6k-nintendo-3ds/release/app.elf")
+///     .name("My App")
+///     .authors(["Jane Doe"])
+///     .description("A homebrew application")
+///     .icon_path("assets/icon.png")
+///     .romfs_dir("assets/romfs")
+///     .build()?;
+///
+/// // Writes `app.smdh` and then `app.3dsx` next to `app.elf`
+/// config.build_smdh(false)?;
+/// config.build_3dsx()?;
+/// # Ok::<(), String>(())
+/// ```
+#[derive(Debug)]
+pub struct CTRConfigBuilder {
+    config: CTRConfig,
+}
+
+impl CTRConfigBuilder {
+    /// Start building the config for the executable at `target_path`. The name
+    /// of the application defaults to the executable's file name.
+    pub fn new(target_path: impl Into<Utf8PathBuf>) -> Self {
+        let target_path = target_path.into();
+        let name = target_path.file_stem().unwrap_or_default().to_string();
+
+        let manifest_dir = env::current_dir()
+            .ok()
+            .and_then(|dir| Utf8PathBuf::from_path_buf(dir).ok())
+            .unwrap_or_default();
+
+        Self {
+            config: CTRConfig {
+                name,
+                target_path,
+                manifest_dir,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Set the name of the application, which is the short description of the
+    /// SMDH unless [`short_description`](Self::short_description) is set.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = name.into();
+        self
+    }
+
+    /// Set the authors of the application, which form the publisher of the SMDH.
+    pub fn authors(mut self, authors: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.authors = Some(authors.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Set the description of the application, the long description of the SMDH.
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.config.description = Some(description.into());
+        self
+    }
+
+    /// Set the short description of the SMDH, instead of the name.
+    pub fn short_description(mut self, short_description: impl Into<String>) -> Self {
+        self.config.short_description = Some(short_description.into());
+        self
+    }
+
+    /// Set the path to the icon, a 48x48 PNG image.
+    pub fn icon_path(mut self, icon_path: impl Into<Utf8PathBuf>) -> Self {
+        self.config.icon_path = Some(icon_path.into());
+        self
+    }
+
+    /// Set the directory of the files to embed in the 3dsx as its romfs.
+    pub fn romfs_dir(mut self, romfs_dir: impl Into<Utf8PathBuf>) -> Self {
+        self.config.romfs_dir = Some(romfs_dir.into());
+        self
+    }
+
+    /// Set the directory relative paths are resolved from, usually the directory
+    /// of the package's `Cargo.toml`.
+    pub fn manifest_dir(mut self, manifest_dir: impl Into<Utf8PathBuf>) -> Self {
+        self.config.manifest_dir = manifest_dir.into();
+        self
+    }
+
+    /// Finish building the config.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message describing each invalid setting, as `cargo 3ds validate` would.
+    pub fn build(self) -> Result<CTRConfig, String> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_config() {
+        let config = CTRConfigBuilder::new("/project/target/app.elf")
+            .authors(["Jane Doe", "John Doe"])
+            .romfs_dir("assets")
+            .manifest_dir("/project")
+            .build()
+            .unwrap();
+
+        assert_eq!(config.short_description(), "app");
+        assert_eq!(config.publisher(), "Jane Doe, John Doe");
+        assert_eq!(config.romfs_dir(), "/project/assets");
+        assert_eq!(config.path_3dsx(), "/project/target/app.3dsx");
+        assert_eq!(config.path_smdh(), "/project/target/app.smdh");
+
+        let config = CTRConfig::builder("app.elf")
+            .name("My App")
+            .build()
+            .unwrap();
+        assert_eq!(config.short_description(), "My App");
+        let current_dir = Utf8PathBuf::try_from(env::current_dir().unwrap()).unwrap();
+        assert_eq!(config.romfs_dir(), current_dir.join("romfs"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::CTRConfig;
+
+/// Collect the files to bundle for `configs`, mapping each file name in the zip
+/// to its source file. The same file may be added by several configs (e.g. the
+/// `README` of a package with several executables), but two different files
+/// with the same name are an error.
+fn bundle_entries(configs: &[CTRConfig]) -> Result<BTreeMap<String, Utf8PathBuf>, String> {
+    let mut entries = BTreeMap::new();
+
+    for path in configs.iter().flat_map(CTRConfig::bundle_files) {
+        let name = path
+            .file_name()
+            .ok_or_else(|| format!("cannot bundle {path}, which is not a file"))?
+            .to_string();
+
+        match entries.get(&name) {
+            Some(existing) if existing != &path => {
+                return Err(format!(
+                    "cannot bundle both {existing} and {path}, since they have the same name"
+                ));
+            }
+            Some(_) => {}
+            None => {
+                entries.insert(name, path);
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Write a zip at `path` with the outputs of every config, for `--bundle`.
+pub(crate) fn write(path: &Utf8Path, configs: &[CTRConfig]) -> Result<(), String> {
+    let entries = bundle_entries(configs)?;
+
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|err| format!("unable to create {dir}: {err}"))?;
+    }
+
+    let file = File::create(path).map_err(|err| format!("unable to create {path}: {err}"))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(0o644);
+
+    for (name, source) in &entries {
+        debug!("Adding {source} to {path} as {name}");
+        let mut reader =
+            File::open(source).map_err(|err| format!("unable to read {source}: {err}"))?;
+        zip.start_file(name.as_str(), options)
+            .and_then(|()| io::copy(&mut reader, &mut zip).map_err(Into::into))
+            .map_err(|err| format!("unable to add {source} to {path}: {err}"))?;
+    }
+
+    zip.finish()
+        .map_err(|err| format!("unable to write {path}: {err}"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::tests::TestDir;
+
+    #[test]
+    fn bundle_zip() {
+        let dir = TestDir::new();
+        fs::create_dir_all(dir.join("target")).unwrap();
+
+        for file in [
+            "target/app.3dsx",
+            "target/app.smdh",
+            "icon.png",
+            "README.md",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+
+        let config = |bundle_files: &[&str]| CTRConfig {
+            target_path: dir.join("target/app.elf"),
+            manifest_dir: dir.clone(),
+            bundle_files: Some(bundle_files.iter().map(Utf8PathBuf::from).collect()),
+            ..Default::default()
+        };
+
+        // The outputs, icon and README are shared, so they are only added once
+        let zip_path = dir.join("dist/app.zip");
+        write(&zip_path, &[config(&["notes.txt"]), config(&[])]).unwrap();
+
+        let mut zip = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let mut names: Vec<_> = zip.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            ["README.md", "app.3dsx", "app.smdh", "icon.png", "notes.txt"]
+        );
+
+        let mut contents = String::new();
+        zip.by_name("app.3dsx")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "target/app.3dsx");
+
+        // Different files with the same name can't both be bundled
+        fs::write(dir.join("target/README.md"), "other").unwrap();
+        assert!(bundle_entries(&[config(&["target/README.md"])]).is_err());
+    }
+}
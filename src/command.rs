@@ -1,12 +1,26 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::io::Read;
-use std::process::{self, Stdio};
+use std::path::PathBuf;
+use std::process::{self, ExitStatus, Stdio};
 use std::sync::OnceLock;
-
-use cargo_metadata::{Message, Metadata};
-use clap::{Args, Parser, Subcommand};
-
-use crate::{build_3dsx, cargo, get_artifact_config, link, print_command, CTRConfig};
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{Message, Metadata, Package};
+use clap::{ArgAction, Args, Parser, Subcommand};
+
+use crate::deploy;
+use crate::remote::RemoteCache;
+use crate::user_config::UserConfig;
+use crate::{
+    attach_gdb, build_3dsx, bundle, cargo, cargo_metadata, configured_emulator, copy_elf,
+    copy_outputs, emulate, existing_artifact_config, gdb_device_address, get_artifact_config,
+    inspect, link, link_to, print_command, romfs, script, sha256_file, smdh, spawn_emulator,
+    validate_workspace, BuildType, CTRConfig, RunTarget, GDB_DEVICE_PORT, GDB_EMULATOR_PORT,
+};
 
 #[derive(Parser, Debug)]
 #[command(name = "cargo", bin_name = "cargo")]
@@ -15,22 +29,142 @@ pub enum Cargo {
     Input(Input),
 }
 
+impl Cargo {
+    /// Parse the command line, whether `cargo-3ds` was run by cargo as `cargo 3ds`,
+    /// which passes `3ds` as the first argument, or directly as `cargo-3ds`.
+    pub fn parse_args() -> Self {
+        Self::parse_from(Self::subcommand_args(env::args_os()))
+    }
+
+    /// Insert the `3ds` subcommand after the binary name, unless it's already there.
+    fn subcommand_args<T: Into<OsString>>(args: impl IntoIterator<Item = T>) -> Vec<OsString> {
+        let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        if args.get(1).is_none_or(|arg| arg != "3ds") {
+            args.insert(args.len().min(1), OsString::from("3ds"));
+        }
+        args
+    }
+}
+
+/// The exit codes of `cargo-3ds`, shown at the end of `--help`.
+const EXIT_CODES_HELP: &str = "\
+Exit status:
+  0    Success
+  1    Invalid configuration, or another error
+  2    Invalid command-line arguments
+  3    Unsupported environment or toolchain, e.g. an old rustc, `DEVKITPRO` not
+       set, or a missing devkitPro tool
+  4    A devkitPro packaging tool (`smdhtool` or `3dsxtool`) failed
+Otherwise, the exit code of `cargo` (e.g. 101 for a compilation failure) or of
+the executable being run is used.";
+
 #[derive(Args, Debug)]
-#[command(version, about)]
+#[command(version, about, after_long_help = EXIT_CODES_HELP)]
 pub struct Input {
     #[command(subcommand)]
     pub cmd: CargoCmd,
 
-    /// Print the exact commands `cargo-3ds` is running. Note that this does not
-    /// set the verbose flag for cargo itself. To set cargo's verbosity flag, add
-    /// `-- -v` to the end of the command line.
-    #[arg(long, short = 'v', global = true)]
-    pub verbose: bool,
+    /// Print the exact commands `cargo-3ds` is running. Pass it twice (`-vv`) to
+    /// also print the raw JSON messages and unit graph emitted by cargo. Note that
+    /// this does not set the verbose flag for cargo itself. To set cargo's
+    /// verbosity flag, add `-- -v` to the end of the command line.
+    #[arg(long, short = 'v', global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only print warnings and errors from `cargo-3ds`. Note that this does not
+    /// set the quiet flag for cargo itself. To set cargo's quiet flag, add
+    /// `-- -q` to the end of the command line.
+    ///
+    /// If neither `--verbose` nor `--quiet` is set, the `RUST_LOG` environment
+    /// variable may be used to select a level (`off`, `error`, `warn`, `info`,
+    /// `debug`, `trace`).
+    #[arg(long, short = 'q', global = true)]
+    pub quiet: bool,
+
+    /// Whether to color the output of `cargo-3ds`: `auto` (if stderr is a
+    /// terminal, respecting `NO_COLOR` and `CLICOLOR`), `always` or `never`.
+    /// This is also passed on to cargo. Defaults to cargo's `CARGO_TERM_COLOR`.
+    #[arg(long, global = true, value_name = "WHEN", value_parser = ["auto", "always", "never"])]
+    pub color: Option<String>,
 
     /// Set cargo configuration on the command line. This is equivalent to
     /// cargo's `--config` option.
     #[arg(long, global = true)]
     pub config: Vec<String>,
+
+    /// Optimize code for the given CPU, by adding `-C target-cpu=<CPU>` to the
+    /// rustflags for the 3DS target (including the standard library when using
+    /// `build-std`). The default for the 3DS target is `mpcore`.
+    ///
+    /// Note that an executable built for a CPU other than the default may not
+    /// run on every model, e.g. only on the New 3DS.
+    #[arg(long, global = true, value_name = "CPU")]
+    pub target_cpu: Option<String>,
+
+    /// Check the versions of the devkitPro tools (`smdhtool`, `3dsxtool` and
+    /// `3dslink`) used by the command before building, and warn about any
+    /// which are older than the versions known to work with `cargo-3ds`.
+    #[arg(long, global = true)]
+    pub tool_version_check: bool,
+
+    /// Only warn, instead of failing, when the toolchain isn't nightly, e.g. for
+    /// a custom or patched stable toolchain which can build the 3DS target.
+    /// `RUSTC_BOOTSTRAP=1` is then set, unless already set, since cargo-3ds
+    /// passes unstable `-Z` flags to cargo. May also be set with the
+    /// `CARGO_3DS_SKIP_TOOLCHAIN_CHECK` environment variable.
+    #[arg(long, global = true, visible_alias = "skip-toolchain-check")]
+    pub allow_stable: bool,
+
+    /// Look for a prebuilt standard library for the 3DS target in the given
+    /// sysroot, rather than the one from the `SYSROOT` environment variable or
+    /// `rustc --print sysroot`. If none is found there, `build-std` is used.
+    ///
+    /// This only affects where `cargo-3ds` looks for the standard library. To
+    /// make `rustc` use the sysroot, also pass `--sysroot` in the rustflags.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub sysroot: Option<PathBuf>,
+
+    /// Print the sysroot used to look for a prebuilt standard library, and
+    /// whether one was found or `build-std` would be used, then exit without
+    /// running the command.
+    #[arg(long, global = true)]
+    pub print_sysroot: bool,
+
+    /// Run the command for the host instead of the 3DS, without `--target`,
+    /// `build-std` or the 3DS rustflags. No 3dsx is built in host mode.
+    ///
+    /// For commands passed through to cargo, this must come before the subcommand.
+    #[arg(long, global = true)]
+    pub host: bool,
+
+    /// Path to the `Cargo.toml` of the package to build, instead of the one in
+    /// the current directory. This is passed on to cargo, and the package's
+    /// metadata, icon and romfs are read from there.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub manifest_path: Option<Utf8PathBuf>,
+
+    /// Use the libctru installed in the given directory, e.g. a locally patched
+    /// build, rather than the one in `$DEVKITPRO/libctru`. Executables are
+    /// linked against the libraries in its `lib` directory, and its
+    /// `default_icon.png` is used when a package has no icon. May also be set
+    /// with the `CARGO_3DS_LIBCTRU` environment variable.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub libctru_path: Option<Utf8PathBuf>,
+}
+
+impl Input {
+    /// Get the manifest path given with `--manifest-path`, either to `cargo-3ds`
+    /// itself or among the arguments passed through to cargo.
+    pub fn manifest_path(&self) -> Option<Utf8PathBuf> {
+        self.manifest_path.clone().or_else(|| {
+            flag_value(&self.cmd.cargo_args(), "--manifest-path").map(Utf8PathBuf::from)
+        })
+    }
+
+    /// Get the absolute path of the libctru directory given with `--libctru-path`.
+    pub fn libctru_path(&self) -> Option<Utf8PathBuf> {
+        self.libctru_path.as_deref().map(absolute_path)
+    }
 }
 
 /// Run a cargo command. COMMAND will be forwarded to the real
@@ -53,9 +187,35 @@ pub enum CargoCmd {
     /// unit tests (which require a custom test runner).
     Test(Test),
 
+    /// Builds an executable with extra flags for `rustc`, like `cargo rustc`,
+    /// and a 3dsx from it.
+    ///
+    /// Flags for `rustc` are separated from the `cargo` options by `--`, like
+    /// the arguments of an executable with `run`, and only apply to the final
+    /// crate: e.g. `cargo 3ds rustc --release -- -C opt-level=s`, or
+    /// `cargo 3ds rustc -- -- -C opt-level=s` without any `cargo` options.
+    Rustc(Build),
+
     /// Sets up a new cargo project suitable to run on a 3DS.
     New(New),
 
+    /// Expands macros with the 3DS target environment using `cargo expand`.
+    ///
+    /// This requires `cargo-expand` to be installed.
+    Expand(Expand),
+
+    /// Prints the metadata embedded in an existing 3dsx or smdh file, such as
+    /// its title, publisher, icon and romfs.
+    Inspect(Inspect),
+
+    /// Checks the `[package.metadata.cargo-3ds]` of every executable in the
+    /// workspace, including its icon and romfs, without building anything.
+    Validate(Validate),
+
+    /// Builds only the smdh of a package from its metadata, without compiling
+    /// anything, e.g. to check changes to its icon or description.
+    Smdh(Smdh),
+
     // NOTE: it seems docstring + name for external subcommands are not rendered
     // in help, but we might as well set them here in case a future version of clap
     // does include them in help text.
@@ -64,7 +224,7 @@ pub enum CargoCmd {
     Passthrough(Vec<String>),
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Clone, Debug)]
 pub struct RemainingArgs {
     /// Pass additional options through to the `cargo` command.
     ///
@@ -89,41 +249,295 @@ trait Callbacks {
     fn run_callback(&self, config: &CTRConfig) {}
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Clone, Debug)]
 pub struct Build {
-    #[arg(from_global)]
-    pub verbose: bool,
+    /// Copy the built `.elf` executable to the given path, e.g. to use it for
+    /// debugging. If the path is an existing directory, the `.elf` is copied
+    /// into it with its original file name.
+    #[arg(long, value_name = "PATH")]
+    pub elf_output: Option<Utf8PathBuf>,
+
+    /// Apply the named set of metadata overrides from
+    /// `[package.metadata.cargo-3ds.profile.<NAME>]` when building the 3dsx.
+    #[arg(long, alias = "config-profile", value_name = "NAME")]
+    pub cfg_profile: Option<String>,
+
+    /// Load settings for the 3dsx from a TOML file with the same keys as
+    /// `[package.metadata.cargo-3ds]`, e.g. to build with a different description or
+    /// icon in CI without changing `Cargo.toml`. These take precedence over
+    /// the package metadata, and relative paths are resolved from the file's directory.
+    #[arg(long, value_name = "PATH")]
+    pub config_file: Option<Utf8PathBuf>,
+
+    /// Always rebuild the smdh and 3dsx, even if they appear up to date.
+    ///
+    /// By default, `smdhtool` and `3dsxtool` are skipped if their outputs are
+    /// newer than the executable, icon, manifest and romfs files they are built
+    /// from. Use this flag if that check is unreliable, e.g. after updating the
+    /// devkitPro tools or when building on a filesystem with inaccurate timestamps.
+    #[arg(long)]
+    pub force: bool,
+
+    /// Truncate the title, description and publisher of the SMDH if they are
+    /// too long to fit, instead of failing with an error.
+    #[arg(long)]
+    pub truncate_smdh: bool,
+
+    /// Embed a prebuilt `.smdh` in the 3dsx as is, instead of building one from
+    /// the package metadata. This takes precedence over `smdh_path` in the metadata.
+    #[arg(long, value_name = "PATH")]
+    pub smdh: Option<Utf8PathBuf>,
+
+    /// Embed a prebuilt RomFS image in the 3dsx, instead of building the romfs
+    /// from a directory. This takes precedence over `romfs_image` and the other
+    /// romfs settings in the metadata.
+    #[arg(long, value_name = "PATH")]
+    pub romfs_image: Option<Utf8PathBuf>,
+
+    /// Keep the temporary directories files are staged in for the devkitPro
+    /// tools, such as a romfs assembled from a manifest or several directories,
+    /// and print their locations, e.g. to check what was embedded in the 3dsx.
+    #[arg(long)]
+    pub keep_temp: bool,
+
+    /// Fail if the romfs embedded in the 3dsx is larger than the given size,
+    /// in bytes or with a `K`, `M` or `G` suffix (e.g. `32M`), rather than only
+    /// warning about a romfs larger than 64 MiB.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_romfs_size: Option<u64>,
+
+    /// Write the cargo, `smdhtool` and `3dsxtool` commands run for the build to
+    /// an executable shell script at the given path, e.g. to reproduce the build
+    /// from another build system or in a bug report. Implies `--force`.
+    #[arg(long, value_name = "PATH")]
+    pub emit_script: Option<Utf8PathBuf>,
+
+    /// After building, print a JSON object to stdout for each executable built,
+    /// one per line, with its `name` and the paths of its `elf`, `3dsx` and `smdh`.
+    #[arg(long)]
+    pub print_artifacts: bool,
+
+    /// After building, write a zip for release to the given path, with the
+    /// `.3dsx`, `.smdh` and icon, any `README` or `LICENSE` in the package
+    /// directory, and the files listed in `bundle_files` in the package metadata.
+    #[arg(long, value_name = "PATH")]
+    pub bundle: Option<Utf8PathBuf>,
+
+    /// Build reproducibly: `SOURCE_DATE_EPOCH` is set for cargo to the time of
+    /// the latest git commit, unless already set, and the SHA-256 hashes of the
+    /// smdh and 3dsx are printed, e.g. to record `smdh_sha256` in the metadata.
+    #[arg(long)]
+    pub reproducible: bool,
 
     // Passthrough cargo options.
     #[command(flatten)]
     pub passthrough: RemainingArgs,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Clone, Debug)]
 pub struct Run {
     /// Specify the IP address of the device to send the executable to.
     ///
     /// Corresponds to 3dslink's `--address` arg, which defaults to automatically
-    /// finding the device.
+    /// finding the device. This may be given several times to send the executable
+    /// to each of the devices in turn, or all at once with `--parallel`.
     #[arg(long, short = 'a')]
-    pub address: Option<std::net::Ipv4Addr>,
+    pub address: Vec<std::net::Ipv4Addr>,
+
+    /// Specify the host name of the device to send the executable to, e.g. one
+    /// assigned by DHCP or advertised with mDNS (`my-3ds.local`), for devices
+    /// without a fixed IP address. It is resolved to an IPv4 address with the
+    /// system's resolver before running `3dslink`.
+    #[arg(long, value_name = "NAME", conflicts_with = "address")]
+    pub device_name: Option<String>,
+
+    /// Send the executable to all the devices given with `--address` at once,
+    /// rather than one after another.
+    #[arg(long, requires = "address", conflicts_with = "server")]
+    pub parallel: bool,
+
+    /// Run the executable in an emulator instead of sending it to a device.
+    /// The emulator is `azahar` by default, or the program set in the
+    /// `CARGO_3DS_EMULATOR` environment variable.
+    ///
+    /// This overrides `test_runner` in `package.metadata.cargo-3ds` and any
+    /// configured `target.armv6k-nintendo-3ds.runner`.
+    #[arg(long, conflicts_with_all = ["address", "device_name", "device", "source_address"])]
+    pub emulator: bool,
+
+    /// When running in an emulator through a wrapper script, don't embed the
+    /// romfs into the 3dsx, so it doesn't need to be rebuilt when only romfs
+    /// files change. The romfs directory is only passed in the
+    /// `CARGO_3DS_ROMFS_DIR` environment variable to the program set as
+    /// `CARGO_3DS_EMULATOR` (or as `emulator_path` in the user config), which
+    /// must mount it itself, since no emulator can be told to on its command
+    /// line.
+    ///
+    /// This has no effect with the default emulator: the romfs is still
+    /// embedded, with a warning. It is also ignored when sending the executable
+    /// to a device.
+    #[arg(long, conflicts_with_all = ["address", "device_name", "device"])]
+    pub romfs_loose: bool,
+
+    /// When running in an emulator, print a banner before its output so the
+    /// executable's console output is easy to tell apart from the build's.
+    ///
+    /// `CARGO_3DS_SHOW_CONSOLE=1` is also set for the program set as
+    /// `CARGO_3DS_EMULATOR` (or as `emulator_path` in the user config). No
+    /// emulator reads it, so showing the emulator's log window needs a wrapper
+    /// script which enables the emulator's own setting for this. Ignored when
+    /// sending the executable to a device.
+    #[arg(long, conflicts_with_all = ["address", "device_name", "device"])]
+    pub show_console: bool,
+
+    /// A hook for a wrapper script around the emulator, e.g. for golden-image
+    /// tests in CI: `cargo-3ds` doesn't take screenshots itself, and no emulator
+    /// can be told to on its command line. The path and `--screenshot-delay` are
+    /// only passed to the program set as `CARGO_3DS_EMULATOR` (or as
+    /// `emulator_path` in the user config) in the `CARGO_3DS_SCREENSHOT` and
+    /// `CARGO_3DS_SCREENSHOT_DELAY` environment variables, and that script must
+    /// save a screenshot there before exiting. It is an error if no file was
+    /// written, or if no such program is configured.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["address", "device_name", "device", "gdb"]
+    )]
+    pub capture_screenshot: Option<Utf8PathBuf>,
+
+    /// The number of seconds after launching the executable which the wrapper
+    /// script should wait before saving the screenshot for `--capture-screenshot`.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 5,
+        requires = "capture_screenshot"
+    )]
+    pub screenshot_delay: u64,
+
+    /// Send the executable to a device with `3dslink`. This is the default, but
+    /// overrides `test_runner` in `package.metadata.cargo-3ds` and any configured
+    /// `target.armv6k-nintendo-3ds.runner`. Implied by `--address`.
+    #[arg(long)]
+    pub device: bool,
+
+    /// Discover the device from the network interface with the given local IPv4
+    /// address, e.g. when automatic discovery uses the wrong interface on a machine
+    /// with a VPN or several network adapters.
+    ///
+    /// `3dslink` can't select an interface itself, so the device is discovered by
+    /// `cargo-3ds` instead, and its address is passed to `3dslink`.
+    #[arg(
+        long,
+        visible_alias = "interface",
+        value_name = "ADDRESS",
+        conflicts_with = "address"
+    )]
+    pub source_address: Option<std::net::Ipv4Addr>,
 
     /// Set the 0th argument of the executable when running it. Corresponds to
     /// 3dslink's `--argv0` argument.
     #[arg(long, short = '0')]
     pub argv0: Option<String>,
 
+    /// Read more arguments for the executable from a file, which are passed
+    /// after any given on the command line. Each line is split into arguments
+    /// like a shell would, so arguments containing spaces can be quoted.
+    /// Empty lines and lines starting with `#` are ignored.
+    #[arg(long, value_name = "PATH")]
+    pub args_file: Option<Utf8PathBuf>,
+
+    // The arguments read from the `--args-file` by `load_args_file`.
+    #[arg(skip)]
+    file_args: Vec<String>,
+
+    /// Build the executable and its 3dsx exactly as it would be run, but don't
+    /// send it to a device or emulator, e.g. to transfer it manually.
+    #[arg(long)]
+    pub no_link: bool,
+
+    /// Don't send anything, but print the romfs files which changed since they
+    /// were recorded with `--romfs-mark-deployed`, as one line of JSON per file
+    /// with its `path` in the romfs, its `source` file and its `change` (`added`,
+    /// `modified` or `removed`). Every file is listed if none were recorded.
+    ///
+    /// `3dslink` always sends the whole 3dsx, so this is meant for a tool which
+    /// can update the files on the device by itself, e.g. over FTP.
+    #[arg(long, conflicts_with_all = ["emulator", "gdb", "no_link", "capture_screenshot"])]
+    pub romfs_changes: bool,
+
+    /// Don't send anything, but record the current romfs files as deployed, once
+    /// the changes listed by `--romfs-changes` were transferred to the device.
+    /// From then on, they are also recorded whenever the 3dsx is sent.
+    #[arg(
+        long,
+        conflicts_with_all = ["emulator", "gdb", "no_link", "capture_screenshot", "romfs_changes"]
+    )]
+    pub romfs_mark_deployed: bool,
+
+    /// Don't build anything, but run the 3dsx from a previous build again.
+    /// The executable is selected with the usual `--bin`, `--example`, `--package`,
+    /// `--release`, `--profile` and `--target-dir` cargo options.
+    #[arg(long, visible_alias = "no-build-3dsx", conflicts_with = "no_link")]
+    pub link_only: bool,
+
     /// Start the 3dslink server after sending the executable. Corresponds to
     /// 3dslink's `--server` argument.
+    ///
+    /// Neither the server nor the netloader report the executable's exit code.
+    /// As a `cargo-3ds` convention, an executable may opt into reporting it by
+    /// printing a `cargo-3ds: exit code <CODE>` line.
     #[arg(long, short = 's', default_value_t = false)]
     pub server: bool,
 
+    /// Write the console output received by the 3dslink server to the given
+    /// file, in addition to printing it to the terminal. Requires `--server`.
+    #[arg(long, value_name = "FILE", requires = "server")]
+    pub stdout_to: Option<Utf8PathBuf>,
+
+    /// Stop the 3dslink server after the given number of seconds, e.g. to check
+    /// that the executable launches and runs for a while. The time is counted from
+    /// when `3dslink` is started, so it includes sending the executable (and any
+    /// `--retries`). `3dslink` is asked to exit, and killed if it is still running
+    /// a few seconds later. Stopping it this way is not a failure. Requires `--server`.
+    #[arg(long, value_name = "SECONDS", requires = "server")]
+    pub server_timeout: Option<u64>,
+
     /// Set the number of tries when connecting to the device to send the executable.
     /// Corresponds to 3dslink's `--retries` argument.
     // Can't use `short = 'r'` because that would conflict with cargo's `--release/-r`
     #[arg(long)]
     pub retries: Option<usize>,
 
+    /// Wait until a device running the Homebrew Launcher's netloader can be found
+    /// before sending the executable, rather than failing if it isn't ready yet.
+    #[arg(long)]
+    pub wait_for_device: bool,
+
+    /// The maximum number of seconds to wait for a device with `--wait-for-device`.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "wait_for_device"
+    )]
+    pub wait_timeout: u64,
+
+    /// Attach `arm-none-eabi-gdb` to the executable once it is launched, with the
+    /// symbols from the unstripped `.elf`. The debugger can be changed by setting
+    /// the `CARGO_3DS_GDB` environment variable.
+    ///
+    /// On a device, this connects to the GDB stub of Luma3DS, which requires
+    /// "Force-debug next application at launch" to be enabled in the Rosalina menu.
+    /// In an emulator, the GDB stub must be enabled in its settings.
+    #[arg(long, conflicts_with_all = ["parallel", "server", "no_link"])]
+    pub gdb: bool,
+
+    /// The port of the GDB stub to attach to with `--gdb`. Defaults to 4003 for
+    /// Luma3DS on a device, or 24689 for an emulator.
+    #[arg(long, value_name = "PORT", requires = "gdb")]
+    pub gdb_port: Option<u16>,
+
     // Passthrough `cargo build` options.
     #[command(flatten)]
     pub build_args: Build,
@@ -155,25 +569,234 @@ pub struct New {
     #[arg(required = true)]
     pub path: String,
 
+    /// The Rust edition of the new project, passed on to `cargo new`. The
+    /// starter `main.rs` needs at least the 2018 edition.
+    #[arg(long, value_name = "YEAR", value_parser = ["2018", "2021", "2024"])]
+    pub edition: Option<String>,
+
+    /// The template for the new project: `console` has a RomFS and the default
+    /// `ctru-rs` features, while `minimal` has no RomFS and disables them.
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = ["console", "minimal"],
+        default_value = "console"
+    )]
+    pub template: String,
+
     // The test command uses a superset of the same arguments as Run.
     #[command(flatten)]
     pub cargo_args: RemainingArgs,
 }
 
+#[derive(Args, Debug)]
+pub struct Expand {
+    // Passthrough `cargo expand` options.
+    #[command(flatten)]
+    pub passthrough: RemainingArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct Inspect {
+    /// The `.3dsx` or `.smdh` file to inspect.
+    pub path: Utf8PathBuf,
+
+    /// Print the metadata as JSON instead of human-readable text.
+    #[arg(long)]
+    pub json: bool,
+}
+
+impl Inspect {
+    /// Run `cargo 3ds inspect`, which reads the file itself instead of running `cargo`.
+    pub fn run(&self) {
+        if let Err(err) = inspect::inspect(&self.path, self.json) {
+            error!("{err}");
+            process::exit(1);
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Validate {
+    /// Also check the settings loaded from a TOML file, like `build --config-file`.
+    #[arg(long, value_name = "PATH")]
+    pub config_file: Option<Utf8PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct Smdh {
+    /// The package to build the smdh for, defaulting to the package in the
+    /// current directory. The smdh is titled with the package's name.
+    #[arg(long, short = 'p', value_name = "NAME")]
+    pub package: Option<String>,
+
+    /// Use the metadata overrides for release builds.
+    #[arg(long, short = 'r')]
+    pub release: bool,
+
+    /// Apply the named set of metadata overrides, like `build --cfg-profile`.
+    #[arg(long, alias = "config-profile", value_name = "NAME")]
+    pub cfg_profile: Option<String>,
+
+    /// Load settings from a TOML file, like `build --config-file`.
+    #[arg(long, value_name = "PATH")]
+    pub config_file: Option<Utf8PathBuf>,
+
+    /// Truncate the title, description and publisher if they are too long to
+    /// fit, instead of failing with an error.
+    #[arg(long)]
+    pub truncate_smdh: bool,
+
+    /// Where to write the smdh, which must end in `.smdh`. Defaults to where a
+    /// build would write it, e.g. `target/armv6k-nintendo-3ds/debug/<package>.smdh`.
+    #[arg(long, short = 'o', value_name = "PATH")]
+    pub output: Option<Utf8PathBuf>,
+}
+
+impl Smdh {
+    /// Run `cargo 3ds smdh`, which builds the smdh from the metadata alone.
+    pub fn run(&self) {
+        let Some(metadata) = cargo_metadata() else {
+            error!("unable to build the smdh without cargo metadata");
+            process::exit(1);
+        };
+
+        let mut config = self.config(metadata).unwrap_or_else(|err| {
+            error!("{err}");
+            process::exit(1);
+        });
+
+        if let Err(err) = config.validate() {
+            error!("{err}");
+            process::exit(1);
+        }
+
+        let dir = config.target_path.parent().unwrap();
+        if let Err(err) = fs::create_dir_all(dir) {
+            error!("unable to create {dir}: {err}");
+            process::exit(1);
+        }
+
+        let remote = RemoteCache::new(
+            metadata.target_directory.join("cargo-3ds").join("remote"),
+            false,
+        );
+        if let Err(err) = config.fetch_remote(&remote) {
+            error!("{err}");
+            process::exit(1);
+        }
+
+        info!("Building smdh: {}", config.path_smdh());
+        if let Err(err) = config.write_smdh(self.truncate_smdh) {
+            err.exit();
+        }
+
+        // The smdh may differ from the one a build would make, e.g. with another
+        // profile, so the next build shouldn't consider its outputs fresh
+        if self.output.is_none() {
+            config.clear_build_fingerprint();
+        }
+    }
+
+    /// Get the config of the package's executable named after it, since there
+    /// is no artifact to take the name from.
+    fn config(&self, metadata: &Metadata) -> Result<CTRConfig, String> {
+        let package = match &self.package {
+            Some(name) => metadata
+                .workspace_packages()
+                .into_iter()
+                .find(|package| &package.name == name),
+            None => metadata.root_package(),
+        }
+        .ok_or_else(|| {
+            String::from(
+                "unable to find the package to build the smdh for, select it with `--package`",
+            )
+        })?;
+
+        let build_type = if self.release {
+            BuildType::Release
+        } else {
+            BuildType::Debug
+        };
+
+        // The smdh is built next to where the `.elf` would be, and the path
+        // of the smdh is derived from it
+        let target_path = match &self.output {
+            Some(output) if output.extension() != Some("smdh") => {
+                return Err(format!("the output path {output} must end in `.smdh`"));
+            }
+            Some(output) => output.with_extension("elf"),
+            None => metadata
+                .target_directory
+                .join(CargoCmd::TARGET)
+                .join(if self.release { "release" } else { "debug" })
+                .join(format!("{}.elf", package.name)),
+        };
+
+        let mut config = existing_artifact_config(
+            package,
+            "bin",
+            &package.name,
+            build_type,
+            self.cfg_profile.as_deref(),
+            self.config_file.as_deref(),
+            target_path,
+        );
+        config.workspace_root = Some(metadata.workspace_root.clone());
+        // The smdh is always built here, even if a prebuilt one is used for builds
+        config.smdh_path = None;
+        // `--output` names the smdh itself
+        if self.output.is_some() {
+            config.filename = None;
+        }
+        Ok(config)
+    }
+}
+
+impl Validate {
+    /// Run `cargo 3ds validate`, which only reads the cargo metadata and the
+    /// files it refers to, reporting every problem found.
+    pub fn run(&self) {
+        let Some(metadata) = cargo_metadata() else {
+            error!("unable to validate `package.metadata.cargo-3ds` without cargo metadata");
+            process::exit(1);
+        };
+
+        let problems = validate_workspace(metadata, self.config_file.as_deref());
+        if problems.is_empty() {
+            info!("No problems found in `package.metadata.cargo-3ds`");
+            return;
+        }
+
+        for problem in &problems {
+            error!("{problem}");
+        }
+        process::exit(1);
+    }
+}
+
 impl CargoCmd {
-    /// Returns the additional arguments run by the "official" cargo subcommand.
+    /// Returns the additional arguments run by the "official" cargo subcommand,
+    /// without any passed after `--` to the executable or, for `rustc`, the compiler.
     pub(crate) fn cargo_args(&self) -> Vec<String> {
         match self {
             CargoCmd::Build(build) => build.passthrough.cargo_args(),
             CargoCmd::Run(run) => run.build_args.passthrough.cargo_args(),
             CargoCmd::Test(test) => test.cargo_args(),
+            CargoCmd::Rustc(rustc) => rustc.passthrough.cargo_args(),
             CargoCmd::New(new) => {
                 // We push the original path in the new command (we captured it in [`New`] to learn about the context)
                 let mut cargo_args = new.cargo_args.cargo_args();
+                if let Some(edition) = &new.edition {
+                    cargo_args.extend([String::from("--edition"), edition.clone()]);
+                }
                 cargo_args.push(new.path.clone());
 
                 cargo_args
             }
+            CargoCmd::Expand(expand) => expand.passthrough.cargo_args(),
+            CargoCmd::Inspect(_) | CargoCmd::Validate(_) | CargoCmd::Smdh(_) => Vec::new(),
             CargoCmd::Passthrough(other) => other.clone().split_off(1),
         }
     }
@@ -196,26 +819,111 @@ impl CargoCmd {
                 }
             }
             CargoCmd::Test(_) => "test",
+            CargoCmd::Rustc(_) => "rustc",
             CargoCmd::New(_) => "new",
+            CargoCmd::Expand(_) => "expand",
+            CargoCmd::Inspect(_) => "inspect",
+            CargoCmd::Validate(_) => "validate",
+            CargoCmd::Smdh(_) => "smdh",
             CargoCmd::Passthrough(cmd) => &cmd[0],
         }
     }
 
+    /// Cargo subcommands which never compile code for the 3DS, so they are passed
+    /// through without `--target` or `build-std`, which some of them reject.
+    const NON_COMPILING_SUBCOMMANDS: &'static [&'static str] = &[
+        "add",
+        "fmt",
+        "generate-lockfile",
+        "help",
+        "info",
+        "init",
+        "install",
+        "locate-project",
+        "login",
+        "logout",
+        "metadata",
+        "owner",
+        "pkgid",
+        "remove",
+        "search",
+        "tree",
+        "uninstall",
+        "update",
+        "vendor",
+        "verify-project",
+        "version",
+        "yank",
+    ];
+
     /// Whether or not this command should compile any code, and thus needs import the custom environment configuration (e.g. target spec).
     pub(crate) fn should_compile(&self) -> bool {
-        matches!(
-            self,
-            Self::Build(_) | Self::Run(_) | Self::Test(_) | Self::Passthrough(_)
-        )
+        match self {
+            Self::Build(_) | Self::Run(_) | Self::Test(_) | Self::Rustc(_) | Self::Expand(_) => {
+                true
+            }
+            Self::Passthrough(cmd) => !Self::NON_COMPILING_SUBCOMMANDS.contains(&cmd[0].as_str()),
+            Self::New(_) | Self::Inspect(_) | Self::Validate(_) | Self::Smdh(_) => false,
+        }
+    }
+
+    /// Cargo subcommands which compile code for the 3DS, but only type-check or
+    /// document it, so they never need to link against `libctru`.
+    const NON_LINKING_SUBCOMMANDS: &'static [&'static str] =
+        &["check", "clippy", "doc", "fix", "rustdoc"];
+
+    /// Whether or not this command may link an executable, and thus needs to know
+    /// which `libctru` to link against. `cargo expand` and e.g. `cargo check` compile
+    /// code, but never link it, so they skip the extra unit graph query.
+    pub(crate) fn should_link(&self) -> bool {
+        match self {
+            Self::Expand(_) => false,
+            Self::Passthrough(cmd) if Self::NON_LINKING_SUBCOMMANDS.contains(&cmd[0].as_str()) => {
+                false
+            }
+            _ => self.should_compile(),
+        }
+    }
+
+    /// Whether or not the underlying cargo command accepts `--message-format`.
+    /// Unlike other commands, `cargo expand` prints code rather than messages to stdout.
+    pub(crate) fn uses_message_format(&self) -> bool {
+        self.should_compile() && !matches!(self, Self::Expand(_))
+    }
+
+    /// Whether or not this command may build tests or benchmarks, which need the
+    /// `test` crate when using `build-std`: `test` and `bench`, or any command
+    /// selecting test or benchmark targets, e.g. `cargo 3ds clippy --all-targets`.
+    pub(crate) fn may_build_tests(&self) -> bool {
+        const TEST_TARGET_FLAGS: &[&str] =
+            &["--tests", "--test", "--benches", "--bench", "--all-targets"];
+
+        match self {
+            Self::Test(_) => true,
+            Self::Passthrough(cmd) if cmd[0] == "bench" => true,
+            _ => self.cargo_args().iter().any(|arg| {
+                let flag = arg.split_once('=').map_or(arg.as_str(), |(flag, _)| flag);
+                TEST_TARGET_FLAGS.contains(&flag)
+            }),
+        }
+    }
+
+    /// Whether or not this command may send an executable to a device with `3dslink`.
+    pub(crate) fn may_use_3dslink(&self) -> bool {
+        match self {
+            Self::Run(run) => !run.no_link,
+            Self::Test(test) => !test.no_run && !test.doc && !test.run_args.no_link,
+            _ => false,
+        }
     }
 
     /// Whether or not this command should build a 3DSX executable file.
     pub fn should_build_3dsx(&self) -> bool {
         match self {
-            Self::Build(_) | CargoCmd::Run(_) => true,
+            Self::Build(_) | CargoCmd::Run(_) | Self::Rustc(_) => true,
             &Self::Test(Test { doc, .. }) => {
                 if doc {
-                    eprintln!("Documentation tests requested, no 3dsx will be built");
+                    info!("Documentation tests requested, no 3dsx will be built");
                     false
                 } else {
                     true
@@ -225,15 +933,125 @@ impl CargoCmd {
         }
     }
 
+    /// Get the directory final artifacts are copied into, if cargo's unstable
+    /// `--artifact-dir` (formerly `--out-dir`) was passed through to it.
+    pub(crate) fn artifact_dir(&self) -> Option<Utf8PathBuf> {
+        let args = self.cargo_args();
+        flag_value(&args, "--artifact-dir")
+            .or_else(|| flag_value(&args, "--out-dir"))
+            .map(Utf8PathBuf::from)
+    }
+
+    /// The target triple of the 3DS, which is passed to cargo as `--target`.
+    pub const TARGET: &'static str = "armv6k-nintendo-3ds";
+
+    /// Check any `--target` passed through to cargo, returning whether the 3DS
+    /// target was passed, so that it isn't passed to cargo twice. Any other
+    /// target is an error, since only the 3DS target can be built for here.
+    pub(crate) fn passed_target(&self) -> Result<bool, String> {
+        let targets = flag_values(&self.cargo_args(), "--target");
+
+        match targets.iter().find(|target| *target != Self::TARGET) {
+            Some(target) => Err(format!(
+                "`--target {target}` conflicts with the `{}` target used by cargo-3ds \
+                (use `--host` to run the command without it)",
+                Self::TARGET
+            )),
+            None => Ok(!targets.is_empty()),
+        }
+    }
+
+    /// Whether the 3dsx should be built reproducibly, with `--reproducible`.
+    pub(crate) fn reproducible(&self) -> bool {
+        self.build_args().is_some_and(|build| build.reproducible)
+    }
+
+    /// Whether `-Z unstable-options` was passed through to cargo.
+    pub(crate) fn unstable_options(&self) -> bool {
+        let args = self.cargo_args();
+        args.iter().any(|arg| arg == "-Zunstable-options")
+            || args
+                .windows(2)
+                .any(|pair| pair[0] == "-Z" && pair[1] == "unstable-options")
+    }
+
+    /// Whether `--timings` was passed through to cargo, to generate an HTML report.
+    pub fn timings_requested(&self) -> bool {
+        self.cargo_args()
+            .iter()
+            .any(|arg| arg == "--timings" || arg.starts_with("--timings="))
+    }
+
+    /// Whether cargo should avoid accessing the network, either because `--offline`
+    /// or `--frozen` was passed through to cargo or `CARGO_NET_OFFLINE` is set.
+    pub(crate) fn offline(&self) -> bool {
+        env::var("CARGO_NET_OFFLINE").is_ok_and(|var| var == "true")
+            || self
+                .cargo_args()
+                .iter()
+                .any(|arg| arg == "--offline" || arg == "--frozen")
+    }
+
+    /// Get the path of the HTML report generated by cargo's `--timings` flag,
+    /// if it was requested and the report exists.
+    pub fn timings_report_path(&self, metadata: &Metadata) -> Option<Utf8PathBuf> {
+        if !self.timings_requested() {
+            return None;
+        }
+
+        let target_dir = flag_value(&self.cargo_args(), "--target-dir")
+            .map(Utf8PathBuf::from)
+            .unwrap_or_else(|| metadata.target_directory.clone());
+
+        let path = target_dir.join("cargo-timings").join("cargo-timing.html");
+        path.exists().then_some(path)
+    }
+
     pub const DEFAULT_MESSAGE_FORMAT: &'static str = "json-render-diagnostics";
 
+    /// The environment variable which may override [`Self::DEFAULT_MESSAGE_FORMAT`].
+    pub const MESSAGE_FORMAT_ENV: &'static str = "CARGO_3DS_MESSAGE_FORMAT";
+
+    /// Get the message format to use when none was passed on the command line:
+    /// `$CARGO_3DS_MESSAGE_FORMAT` if set, or `message_format` from the user
+    /// config, or [`Self::DEFAULT_MESSAGE_FORMAT`].
+    pub(crate) fn default_message_format() -> Result<String, String> {
+        Self::message_format_from(env::var(Self::MESSAGE_FORMAT_ENV).ok(), UserConfig::get())
+    }
+
+    /// Get the default message format from the value of [`Self::MESSAGE_FORMAT_ENV`]
+    /// and the `user_config`, see [`Self::default_message_format`].
+    fn message_format_from(
+        env_format: Option<String>,
+        user_config: &UserConfig,
+    ) -> Result<String, String> {
+        match env_format {
+            Some(format) if !format.is_empty() => {
+                Self::check_json_message_format(&format)
+                    .map_err(|err| format!("invalid ${}: {err}", Self::MESSAGE_FORMAT_ENV))?;
+                Ok(format)
+            }
+            _ => match &user_config.message_format {
+                Some(format) => {
+                    Self::check_json_message_format(format).map_err(|err| {
+                        format!("invalid `message_format` in the user config: {err}")
+                    })?;
+                    Ok(format.clone())
+                }
+                None => Ok(String::from(Self::DEFAULT_MESSAGE_FORMAT)),
+            },
+        }
+    }
+
     pub fn extract_message_format(&mut self) -> Result<Option<String>, String> {
         let cargo_args = match self {
-            Self::Build(build) => &mut build.passthrough.args,
+            Self::Build(build) | Self::Rustc(build) => &mut build.passthrough.args,
             Self::Run(run) => &mut run.build_args.passthrough.args,
             Self::New(new) => &mut new.cargo_args.args,
+            Self::Expand(expand) => &mut expand.passthrough.args,
             Self::Test(test) => &mut test.run_args.build_args.passthrough.args,
             Self::Passthrough(args) => args,
+            Self::Inspect(_) | Self::Validate(_) | Self::Smdh(_) => return Ok(None),
         };
 
         let format = Self::extract_message_format_from_args(cargo_args)?;
@@ -254,8 +1072,11 @@ impl CargoCmd {
     fn extract_message_format_from_args(
         cargo_args: &mut Vec<String>,
     ) -> Result<Option<String>, String> {
-        // Checks for a position within the args where '--message-format' is located
-        if let Some(pos) = cargo_args
+        let mut last_format = None;
+
+        // Checks for every position within the args where '--message-format' is located.
+        // Like cargo, the last one takes precedence if it was passed more than once.
+        while let Some(pos) = cargo_args
             .iter()
             .position(|s| s.starts_with("--message-format"))
         {
@@ -267,21 +1088,37 @@ impl CargoCmd {
             // otherwise remove next arg which is now at the same position as the original flag.
             let format = if let Some((_, format)) = arg.split_once('=') {
                 format.to_string()
-            } else {
+            } else if pos < cargo_args.len() {
                 // Also need to remove the argument to the --message-format option
                 cargo_args.remove(pos)
+            } else {
+                return Err(String::from("`--message-format` requires a value"));
             };
 
-            // Non-json formats are not supported so the executable exits.
-            if format.starts_with("json") {
-                Ok(Some(format))
-            } else {
-                Err(String::from(
-                    "error: non-JSON `message-format` is not supported",
-                ))
-            }
+            last_format = Some(format);
+        }
+
+        if let Some(format) = &last_format {
+            Self::check_json_message_format(format)?;
+        }
+
+        Ok(last_format)
+    }
+
+    /// The format may be a comma-separated list (e.g. `json,artifacts`), which is
+    /// forwarded to cargo as a whole. Non-json formats are not supported, so the
+    /// list must contain a json variant for us to parse, or the executable exits.
+    fn check_json_message_format(format: &str) -> Result<(), String> {
+        if format
+            .split(',')
+            .any(|format| format.trim().starts_with("json"))
+        {
+            Ok(())
         } else {
-            Ok(None)
+            Err(String::from(
+                "non-JSON `message-format` is not supported, it must include a JSON \
+                format such as `json`",
+            ))
         }
     }
 
@@ -296,12 +1133,31 @@ impl CargoCmd {
             .map(|metadata| self.build_callbacks(messages, metadata))
             .unwrap_or_default();
 
+        if let Some(path) = self.build_args().and_then(|b| b.emit_script.as_deref()) {
+            if let Err(err) = script::write(path) {
+                error!("{err}");
+                process::exit(1);
+            }
+            info!("Build script written to {path}");
+        }
+
         let config = match self {
+            // --no-run may produce any number of executables, which aren't run,
+            // but we report where they were built, e.g. for manual deployment
+            Self::Test(test @ Test { no_run: true, .. }) => {
+                test.report_built(&configs);
+                return;
+            }
+
             // If we produced one executable, we will attempt to run that one
             _ if configs.len() == 1 => configs.into_iter().next().unwrap(),
 
-            // --no-run may produce any number of executables, and we skip the callback
-            Self::Test(Test { no_run: true, .. }) => return,
+            // --no-link may produce any number of executables, and we skip the callback
+            Self::Test(Test {
+                run_args: Run { no_link: true, .. },
+                ..
+            })
+            | Self::Run(Run { no_link: true, .. }) => return,
 
             // If using custom runners, they may be able to handle multiple executables,
             // and we also want to skip our own callback. `cargo run` also has its own
@@ -310,6 +1166,13 @@ impl CargoCmd {
                 return
             }
 
+            // Without a custom runner, we can send each test executable to the
+            // device in turn, like `cargo test` would run them on the host.
+            Self::Test(test) if !configs.is_empty() => {
+                test.run_each(&configs);
+                return;
+            }
+
             // Config is ignored by the New callback, using default is fine.
             Self::New(_) => CTRConfig::default(),
 
@@ -317,8 +1180,8 @@ impl CargoCmd {
             Self::Test(_) | Self::Run(_) => {
                 let paths: Vec<_> = configs.into_iter().map(|c| c.path_3dsx()).collect();
                 let names: Vec<_> = paths.iter().filter_map(|p| p.file_name()).collect();
-                eprintln!(
-                    "Error: expected exactly one (1) executable to run, got {}: {names:?}",
+                error!(
+                    "expected exactly one (1) executable to run, got {}: {names:?}",
                     paths.len(),
                 );
                 process::exit(1);
@@ -336,6 +1199,12 @@ impl CargoCmd {
         let max_artifact_count = metadata.packages.iter().map(|pkg| pkg.targets.len()).sum();
         let mut configs = Vec::with_capacity(max_artifact_count);
 
+        // Remote assets are cached in the target dir, so `cargo clean` removes them too
+        let remote = RemoteCache::new(
+            metadata.target_directory.join("cargo-3ds").join("remote"),
+            self.offline(),
+        );
+
         for message in messages {
             let Message::CompilerArtifact(artifact) = message else {
                 continue;
@@ -348,19 +1217,84 @@ impl CargoCmd {
             }
 
             let package = &metadata[&artifact.package_id];
-            let config = get_artifact_config(package.clone(), artifact.clone());
+            let profile = self.build_args().and_then(|b| b.cfg_profile.as_deref());
+            let config_file = self.build_args().and_then(|b| b.config_file.as_deref());
+            let mut config = get_artifact_config(
+                package.clone(),
+                artifact.clone(),
+                &metadata.workspace_root,
+                profile,
+                config_file,
+            );
+            if let Some(smdh) = self.build_args().and_then(|b| b.smdh.as_deref()) {
+                config.smdh_path = Some(absolute_path(smdh));
+            }
+            if let Some(image) = self.build_args().and_then(|b| b.romfs_image.as_deref()) {
+                config.romfs_image = Some(absolute_path(image));
+            }
+
+            if let Err(err) = config.fetch_remote(&remote) {
+                error!("{err}");
+                process::exit(1);
+            }
 
-            self.build_callback(&config);
+            match romfs::dependency_romfs_dirs(metadata, &artifact.package_id) {
+                Ok(contributions) => config.romfs_contributions = contributions,
+                Err(err) => {
+                    error!("{err}");
+                    process::exit(1);
+                }
+            }
 
             configs.push(config);
         }
 
+        if let Err(err) = check_output_collisions(&configs) {
+            error!("{err}");
+            process::exit(1);
+        }
+
+        for config in &configs {
+            self.build_callback(config);
+        }
+
+        // Like cargo does for the executables, copy the 3DS outputs to `--artifact-dir`
+        if let Some(artifact_dir) = self.artifact_dir() {
+            for config in &configs {
+                copy_outputs(config, &artifact_dir);
+            }
+        }
+
+        if self.build_args().is_some_and(|b| b.print_artifacts) {
+            for config in &configs {
+                println!("{}", artifact_json(config));
+            }
+        }
+
+        if let Some(path) = self.build_args().and_then(|b| b.bundle.as_deref()) {
+            if let Err(err) = bundle::write(path, &configs) {
+                error!("{err}");
+                process::exit(1);
+            }
+            info!("Bundle written to {path}");
+        }
+
         configs
     }
 
+    /// Returns the `build` options of this command, if it builds a 3dsx.
+    fn build_args(&self) -> Option<&Build> {
+        match self {
+            Self::Build(build) | Self::Rustc(build) => Some(build),
+            Self::Run(run) => Some(&run.build_args),
+            Self::Test(test) => Some(&test.run_args.build_args),
+            _ => None,
+        }
+    }
+
     fn inner_callback(&self) -> Option<&dyn Callbacks> {
         match self {
-            Self::Build(cmd) => Some(cmd),
+            Self::Build(cmd) | Self::Rustc(cmd) => Some(cmd),
             Self::Run(cmd) => Some(cmd),
             Self::Test(cmd) => Some(cmd),
             _ => None,
@@ -368,16 +1302,198 @@ impl CargoCmd {
     }
 }
 
-impl Callbacks for CargoCmd {
-    fn build_callback(&self, config: &CTRConfig) {
-        if let Some(cb) = self.inner_callback() {
-            cb.build_callback(config);
+/// Parse a size in bytes, with an optional binary `K`, `M` or `G` suffix.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => size.split_at(i),
+        None => (size, ""),
+    };
+
+    let multiplier: u64 = match unit.trim().trim_end_matches(['B', 'b', 'i']) {
+        "" => 1,
+        "K" | "k" => 1024,
+        "M" | "m" => 1024 * 1024,
+        "G" | "g" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(format!(
+                "unknown size unit `{unit}`, expected `K`, `M` or `G`"
+            ))
         }
+    };
+
+    number
+        .parse::<u64>()
+        .map_err(|err| format!("invalid size `{size}`: {err}"))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size `{size}` is too large"))
+}
+
+/// Check the results of running several test executables, given as the path of
+/// each `.3dsx` and whether it passed, with an error naming those which failed.
+fn check_test_results(results: &[(Utf8PathBuf, bool)]) -> Result<(), String> {
+    let failed: Vec<_> = results
+        .iter()
+        .filter(|(_, passed)| !passed)
+        .filter_map(|(path, _)| path.file_name())
+        .collect();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} of {} test executables failed: {failed:?}",
+            failed.len(),
+            results.len()
+        ))
     }
+}
 
-    fn run_callback(&self, config: &CTRConfig) {
-        if let Some(cb) = self.inner_callback() {
-            cb.run_callback(config);
+/// Read the executable arguments from an `--args-file`, splitting each line like
+/// a shell would.
+fn read_args_file(path: &Utf8Path) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("unable to read args file {path}: {err}"))?;
+
+    let mut args = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line_args = shlex::split(line)
+            .ok_or_else(|| format!("invalid args file {path}: line {}: unmatched quote", i + 1))?;
+        args.extend(line_args);
+    }
+
+    Ok(args)
+}
+
+/// Make a path given on the command line absolute, since it's relative to the
+/// current directory rather than the package's.
+pub(crate) fn absolute_path(path: &Utf8Path) -> Utf8PathBuf {
+    env::current_dir()
+        .ok()
+        .and_then(|dir| Utf8PathBuf::from_path_buf(dir).ok())
+        .map_or_else(|| path.to_path_buf(), |dir| dir.join(path))
+}
+
+/// The flags of the libtest harness which `cargo test` doesn't have itself.
+const LIBTEST_FLAGS: &[&str] = &[
+    "--exact",
+    "--ignored",
+    "--include-ignored",
+    "--nocapture",
+    "--no-capture",
+    "--show-output",
+    "--list",
+    "--report-time",
+    "--shuffle",
+];
+
+/// The options of the libtest harness which take a value, and which `cargo test`
+/// doesn't have itself.
+const LIBTEST_OPTIONS: &[&str] = &[
+    "--skip",
+    "--test-threads",
+    "--shuffle-seed",
+    "--logfile",
+    "--format",
+];
+
+/// The options of `cargo test` which take a separate value, which must not be
+/// taken for a test name filter.
+const CARGO_TEST_OPTIONS: &[&str] = &[
+    "-p",
+    "--package",
+    "--exclude",
+    "--bin",
+    "--example",
+    "--test",
+    "--bench",
+    "-F",
+    "--features",
+    "-j",
+    "--jobs",
+    "--profile",
+    "--target",
+    "--target-dir",
+    "--artifact-dir",
+    "--out-dir",
+    "--manifest-path",
+    "--lockfile-path",
+    "--message-format",
+    "--color",
+    "--config",
+    "-Z",
+];
+
+/// Get the value of a cargo option passed as either `--flag value` or `--flag=value`.
+fn flag_value(cargo_args: &[String], flag: &str) -> Option<String> {
+    flag_values(cargo_args, flag).into_iter().next()
+}
+
+/// Get every value of a cargo option which may be passed more than once.
+fn flag_values(cargo_args: &[String], flag: &str) -> Vec<String> {
+    cargo_args
+        .iter()
+        .enumerate()
+        .filter_map(|(i, arg)| match arg.strip_prefix(flag) {
+            Some("") => cargo_args.get(i + 1).cloned(),
+            Some(value) => value.strip_prefix('=').map(String::from),
+            None => None,
+        })
+        .collect()
+}
+
+/// Describe the outputs built for `config` as a single line of JSON, for `--print-artifacts`.
+fn artifact_json(config: &CTRConfig) -> String {
+    serde_json::json!({
+        "name": config.name,
+        "elf": config.target_path,
+        "3dsx": config.path_3dsx(),
+        "smdh": config.path_smdh(),
+    })
+    .to_string()
+}
+
+/// Ensure that no two different executables would produce the same `.3dsx`,
+/// which would otherwise silently overwrite one another.
+fn check_output_collisions(configs: &[CTRConfig]) -> Result<(), String> {
+    let mut outputs: HashMap<Utf8PathBuf, &CTRConfig> = HashMap::new();
+
+    for config in configs {
+        match outputs.entry(config.path_3dsx()) {
+            Entry::Occupied(entry) if entry.get().target_path != config.target_path => {
+                return Err(format!(
+                    "`{}` and `{}` would both be written to {}\n\
+                    Build them separately, e.g. with `--bin` or `--example`",
+                    entry.get().name,
+                    config.name,
+                    entry.key(),
+                ));
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(config);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Callbacks for CargoCmd {
+    fn build_callback(&self, config: &CTRConfig) {
+        if let Some(cb) = self.inner_callback() {
+            cb.build_callback(config);
+        }
+    }
+
+    fn run_callback(&self, config: &CTRConfig) {
+        if let Some(cb) = self.inner_callback() {
+            cb.run_callback(config);
         }
     }
 }
@@ -393,6 +1509,49 @@ impl RemainingArgs {
         self.split_args().1
     }
 
+    /// Move the test name filter and libtest flags (e.g. `--exact`) from the
+    /// cargo args to the executable args, so `cargo 3ds test my_test -- --exact`
+    /// or `cargo 3ds test --ignored` run the test harness the same as `cargo test`,
+    /// without a second `--`.
+    fn route_test_args(&mut self) {
+        let (cargo_args, exe_args) = self.split_args();
+
+        let mut kept = Vec::new();
+        let mut harness_args = Vec::new();
+        let mut filter_found = false;
+        let mut args = cargo_args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+
+            if LIBTEST_FLAGS.contains(&arg.as_str()) {
+                harness_args.push(arg);
+            } else if LIBTEST_OPTIONS.contains(&name) {
+                let has_value = name != arg;
+                harness_args.push(arg);
+                if !has_value {
+                    harness_args.extend(args.next());
+                }
+            } else if CARGO_TEST_OPTIONS.contains(&arg.as_str()) {
+                kept.push(arg);
+                kept.extend(args.next());
+            } else if !filter_found && !arg.starts_with('-') {
+                // `cargo test` only takes a single test name filter
+                filter_found = true;
+                harness_args.push(arg);
+            } else {
+                kept.push(arg);
+            }
+        }
+
+        harness_args.extend(exe_args);
+        if !harness_args.is_empty() {
+            kept.push(String::from("--"));
+            kept.extend(harness_args);
+        }
+        self.args = kept;
+    }
+
     fn split_args(&self) -> (Vec<String>, Vec<String>) {
         let mut args = self.args.clone();
 
@@ -413,38 +1572,442 @@ impl Callbacks for Build {
     ///
     /// This callback handles building the application as a `.3dsx` file.
     fn build_callback(&self, config: &CTRConfig) {
-        eprintln!("Building smdh: {}", config.path_smdh());
-        config.build_smdh(self.verbose);
+        self.package(config, true);
+    }
+}
+
+impl Build {
+    /// Build the `.smdh` and `.3dsx` for `config` unless they are up to date,
+    /// embedding the romfs into the `.3dsx` if `embed_romfs` is set.
+    fn package(&self, config: &CTRConfig, embed_romfs: bool) {
+        let fingerprint = config.build_fingerprint(&format!(
+            "truncate_smdh={} embed_romfs={embed_romfs}",
+            self.truncate_smdh
+        ));
+
+        // The tools always run for `--emit-script`, so their commands are recorded
+        if !self.force && self.emit_script.is_none() && config.is_fresh(embed_romfs, &fingerprint) {
+            info!("Fresh 3dsx: {}", config.path_3dsx());
+        } else {
+            if config.prebuilt_smdh() {
+                info!("Using prebuilt smdh: {}", config.path_smdh());
+                if let Err(err) = smdh::check_smdh(&config.path_smdh()) {
+                    error!("{err}");
+                    process::exit(1);
+                }
+            } else {
+                info!("Building smdh: {}", config.path_smdh());
+                if let Err(err) = config.write_smdh(self.truncate_smdh) {
+                    err.exit();
+                }
+            }
+
+            info!("Building 3dsx: {}", config.path_3dsx());
+            if let Err(err) = build_3dsx(config, self.keep_temp, self.max_romfs_size, embed_romfs) {
+                err.exit();
+            }
+
+            if let Err(err) = config.write_build_fingerprint(&fingerprint) {
+                warn!("{err}");
+            }
+        }
+
+        if !embed_romfs {
+            if let Err(err) = config.stage_loose_romfs() {
+                error!("{err}");
+                process::exit(1);
+            }
+        }
+
+        if let Err(err) = config.check_smdh_hash() {
+            error!("{err}");
+            process::exit(1);
+        }
+
+        if self.reproducible {
+            for path in [config.path_smdh(), config.path_3dsx()] {
+                match sha256_file(&path) {
+                    Ok(hash) => info!("SHA-256 {hash}: {path}"),
+                    Err(err) => warn!("{err}"),
+                }
+            }
+        }
 
-        eprintln!("Building 3dsx: {}", config.path_3dsx());
-        build_3dsx(config, self.verbose);
+        if let Some(elf_output) = &self.elf_output {
+            copy_elf(config, elf_output);
+        }
     }
 }
 
 impl Callbacks for Run {
     fn build_callback(&self, config: &CTRConfig) {
-        self.build_args.build_callback(config);
+        self.build_args.package(config, !self.use_loose_romfs(config));
     }
 
     /// Callback for `cargo 3ds run`.
     ///
     /// This callback handles launching the application via `3dslink`.
     fn run_callback(&self, config: &CTRConfig) {
+        if self.no_link {
+            info!("Not running {} (--no-link)", config.path_3dsx());
+            return;
+        }
+
         if !self.use_custom_runner() {
-            eprintln!("Running 3dslink");
-            link(config, self, self.build_args.verbose);
+            let status = self.launch(config);
+
+            if !status.success() {
+                process::exit(status.code().unwrap_or(1));
+            }
         }
     }
 }
 
 impl Run {
-    /// Get the args to pass to `3dslink` based on these options.
-    pub(crate) fn get_3dslink_args(&self) -> Vec<String> {
-        let mut args = Vec::new();
+    /// Where executables should be run, as selected on the command line.
+    fn cli_run_target(&self) -> Option<RunTarget> {
+        if self.emulator {
+            Some(RunTarget::Emulator)
+        } else if self.device
+            || !self.address.is_empty()
+            || self.device_name.is_some()
+            || self.source_address.is_some()
+        {
+            Some(RunTarget::Device)
+        } else {
+            None
+        }
+    }
+
+    /// Where the executable for `config` should be run. The command line takes
+    /// precedence over `test_runner` in the package metadata, and otherwise
+    /// executables are sent to a device.
+    fn run_target(&self, config: &CTRConfig) -> RunTarget {
+        self.cli_run_target()
+            .or(config.test_runner)
+            .unwrap_or(RunTarget::Device)
+    }
+
+    /// Whether the romfs for `config` should be left out of the 3dsx and passed
+    /// to the emulator as a directory instead. This only works in an emulator
+    /// which can mount it, so never with the default `azahar`.
+    fn use_loose_romfs(&self, config: &CTRConfig) -> bool {
+        if !self.romfs_loose {
+            return false;
+        }
+
+        if config.romfs_image().is_some() {
+            warn!("--romfs-loose can't be used with a RomFS image, embedding the romfs");
+            return false;
+        }
+
+        if self.run_target(config) != RunTarget::Emulator {
+            warn!("--romfs-loose only applies when running in an emulator, embedding the romfs");
+            false
+        } else if configured_emulator().is_none() {
+            warn!(
+                "--romfs-loose needs an emulator which mounts the romfs in `CARGO_3DS_ROMFS_DIR`, \
+                e.g. a wrapper script set as `CARGO_3DS_EMULATOR`, embedding the romfs"
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Run the executable for `config`, either on a device or in an emulator.
+    fn launch(&self, config: &CTRConfig) -> ExitStatus {
+        if self.gdb {
+            return self.debug(config);
+        }
+
+        match self.run_target(config) {
+            RunTarget::Device if self.capture_screenshot.is_some() => {
+                error!("`--capture-screenshot` is only supported in an emulator, e.g. with `--emulator`");
+                process::exit(1);
+            }
+            RunTarget::Device if self.romfs_changes => {
+                let changes = deploy::changes(config).unwrap_or_else(|err| {
+                    error!("{err}");
+                    process::exit(1);
+                });
+                for change in changes {
+                    println!("{}", serde_json::to_string(&change).unwrap());
+                }
+                ExitStatus::default()
+            }
+            RunTarget::Device if self.romfs_mark_deployed => {
+                if let Err(err) = deploy::record(config) {
+                    error!("{err}");
+                    process::exit(1);
+                }
+                info!("Recorded the RomFS of {} as deployed", config.path_3dsx());
+                ExitStatus::default()
+            }
+            RunTarget::Device => {
+                info!("Running 3dslink: {}", config.path_3dsx());
+                let status = link(config, self);
+
+                // The romfs can only be listed when it isn't a prebuilt image
+                if status.success() && config.romfs_image().is_none() {
+                    if let Err(err) = deploy::update(config) {
+                        warn!("unable to record the deployed romfs: {err}");
+                    }
+                }
+
+                status
+            }
+            RunTarget::Emulator => {
+                // The default emulator doesn't know about `CARGO_3DS_SCREENSHOT`,
+                // so fail before launching it rather than once it has run
+                if self.capture_screenshot.is_some() && configured_emulator().is_none() {
+                    error!(
+                        "`--capture-screenshot` requires `CARGO_3DS_EMULATOR` (or `emulator_path` \
+                        in the user config) to be set to an emulator or wrapper script which \
+                        saves the screenshot to the path in `CARGO_3DS_SCREENSHOT`"
+                    );
+                    process::exit(1);
+                }
 
-        if let Some(address) = self.address {
-            args.extend(["--address".to_string(), address.to_string()]);
+                // Remove any stale screenshot, so we can tell whether a new one was written
+                if let Some(screenshot) = &self.capture_screenshot {
+                    let _ = fs::remove_file(screenshot);
+                }
+
+                info!("Running emulator: {}", config.path_3dsx());
+                let status = emulate(config, self);
+
+                if let Some(screenshot) = self
+                    .capture_screenshot
+                    .as_ref()
+                    .filter(|_| status.success())
+                {
+                    if !screenshot.is_file() {
+                        error!(
+                            "no screenshot was written to {screenshot}\n\
+                            The wrapper script set as `CARGO_3DS_EMULATOR` must save it \
+                            to the path in `CARGO_3DS_SCREENSHOT`"
+                        );
+                        process::exit(1);
+                    }
+                    info!("Found the screenshot saved by the emulator wrapper at {screenshot}");
+                }
+
+                status
+            }
+        }
+    }
+
+    /// Run the executable for `config` with `--gdb`, attaching the debugger to it
+    /// once it is launched.
+    fn debug(&self, config: &CTRConfig) -> ExitStatus {
+        match self.run_target(config) {
+            RunTarget::Device => {
+                // The device's address is needed to connect to its GDB stub,
+                // so it is discovered here rather than by `3dslink`
+                let address = gdb_device_address(self).unwrap_or_else(|err| {
+                    error!("{err}");
+                    process::exit(1);
+                });
+
+                info!("Running 3dslink: {}", config.path_3dsx());
+                let status = link_to(config, self, Some(address));
+                if !status.success() {
+                    return status;
+                }
+
+                let port = self.gdb_port.unwrap_or(GDB_DEVICE_PORT);
+                attach_gdb(config, &format!("{address}:{port}"), true)
+            }
+            RunTarget::Emulator => {
+                info!("Running emulator: {}", config.path_3dsx());
+                let mut emulator = spawn_emulator(config, self);
+
+                let port = self.gdb_port.unwrap_or(GDB_EMULATOR_PORT);
+                let status = attach_gdb(config, &format!("localhost:{port}"), false);
+
+                // The emulator is left running once gdb exits, like the device
+                let _ = emulator.wait();
+                status
+            }
+        }
+    }
+
+    /// Run the 3dsx from a previous build for `--link-only`, without running cargo.
+    /// Exits with an error if the 3dsx doesn't exist.
+    pub fn link_existing(&self) {
+        let config = cargo_metadata()
+            .ok_or_else(|| {
+                String::from("unable to find the executable to run without cargo metadata")
+            })
+            .and_then(|metadata| self.existing_config(metadata));
+
+        let config = config.unwrap_or_else(|err| {
+            error!("{err}");
+            process::exit(1);
+        });
+
+        if !config.path_3dsx().is_file() {
+            error!(
+                "{} does not exist, build it first without `--link-only`",
+                config.path_3dsx()
+            );
+            process::exit(1);
+        }
+
+        self.run_callback(&config);
+    }
+
+    /// Get the config of the executable that would be built by this command,
+    /// based on the cargo options selecting the package, target and profile.
+    fn existing_config(&self, metadata: &Metadata) -> Result<CTRConfig, String> {
+        let args = self.build_args.passthrough.cargo_args();
+
+        let package = Self::selected_package(metadata, &args).ok_or_else(|| {
+            String::from("unable to find the package to run, select it with `--package`")
+        })?;
+
+        let (kind, name) = if let Some(example) = flag_value(&args, "--example") {
+            ("example", example)
+        } else if let Some(bin) = flag_value(&args, "--bin").or(package.default_run.clone()) {
+            ("bin", bin)
+        } else {
+            let bins: Vec<_> = package
+                .targets
+                .iter()
+                .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+                .collect();
+            match bins[..] {
+                [bin] => ("bin", bin.name.clone()),
+                _ => {
+                    return Err(format!(
+                        "unable to determine which executable of `{}` to run, \
+                        select it with `--bin` or `--example`",
+                        package.name
+                    ))
+                }
+            }
+        };
+
+        let profile = Self::selected_profile(&args);
+
+        let mut target_path = flag_value(&args, "--target-dir")
+            .map(Utf8PathBuf::from)
+            .unwrap_or_else(|| metadata.target_directory.clone())
+            .join("armv6k-nintendo-3ds")
+            .join(&profile);
+        if kind == "example" {
+            target_path.push("examples");
+        }
+        target_path.push(format!("{name}.elf"));
+
+        let build_type = Self::profile_build_type(&profile);
+        let cfg_profile = self.build_args.cfg_profile.as_deref();
+        let config_file = self.build_args.config_file.as_deref();
+
+        Ok(CTRConfig {
+            workspace_root: Some(metadata.workspace_root.clone()),
+            ..existing_artifact_config(
+                package,
+                kind,
+                &name,
+                build_type,
+                cfg_profile,
+                config_file,
+                target_path,
+            )
+        })
+    }
+
+    /// Get the package selected with `--package` in the cargo `args`, or else
+    /// the package in the current directory.
+    fn selected_package<'a>(metadata: &'a Metadata, args: &[String]) -> Option<&'a Package> {
+        match flag_value(args, "--package").or_else(|| flag_value(args, "-p")) {
+            Some(name) => metadata
+                .workspace_packages()
+                .into_iter()
+                .find(|package| package.name == name),
+            None => metadata.root_package(),
+        }
+    }
+
+    /// Get the name of the profile directory selected by the cargo `args`.
+    fn selected_profile(args: &[String]) -> String {
+        if args.iter().any(|arg| arg == "--release" || arg == "-r") {
+            String::from("release")
+        } else {
+            match flag_value(args, "--profile") {
+                None => String::from("debug"),
+                Some(profile) if profile == "dev" => String::from("debug"),
+                Some(profile) => profile,
+            }
+        }
+    }
+
+    /// Get the build type of executables built with `profile`.
+    fn profile_build_type(profile: &str) -> BuildType {
+        if profile == "debug" {
+            BuildType::Debug
+        } else {
+            BuildType::Release
+        }
+    }
+
+    /// Get the `test_runner` of the targets of the selected package, resolved the
+    /// same as the config passed to [`Self::run_target`]: with the per-target
+    /// tables, build type, profile and `--config-file`. The first one set is
+    /// returned, since no custom runner can be used if any executable isn't run
+    /// with it.
+    fn metadata_run_target(&self) -> Option<RunTarget> {
+        let metadata = cargo_metadata()?;
+        let args = self.build_args.passthrough.cargo_args();
+        let package = Self::selected_package(metadata, &args)?;
+        let build_type = Self::profile_build_type(&Self::selected_profile(&args));
+
+        package.targets.iter().find_map(|target| {
+            existing_artifact_config(
+                package,
+                target.kind.first()?,
+                &target.name,
+                build_type,
+                self.build_args.cfg_profile.as_deref(),
+                self.build_args.config_file.as_deref(),
+                Utf8PathBuf::new(),
+            )
+            .test_runner
+        })
+    }
+
+    /// Get how long the 3dslink server may run, from `--server-timeout`.
+    pub(crate) fn server_timeout(&self) -> Option<Duration> {
+        self.server_timeout.map(Duration::from_secs)
+    }
+
+    /// Read the executable args from the `--args-file`, if any, once the
+    /// arguments are parsed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message if the file can't be read or is invalid.
+    pub fn load_args_file(&mut self) -> Result<(), String> {
+        if let Some(path) = &self.args_file {
+            self.file_args = read_args_file(path)?;
         }
+        Ok(())
+    }
+
+    /// Get the args to pass to the executable: those after `--` on the command
+    /// line, followed by those from `--args-file`, see [`Self::load_args_file`].
+    pub(crate) fn exe_args(&self) -> Vec<String> {
+        let mut exe_args = self.build_args.passthrough.exe_args();
+        exe_args.extend(self.file_args.iter().cloned());
+        exe_args
+    }
+
+    /// Get the args to pass to `3dslink` based on these options, except for the
+    /// address of the device, since the executable may be sent to several devices.
+    pub(crate) fn get_3dslink_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
 
         if let Some(argv0) = &self.argv0 {
             args.extend(["--arg0".to_string(), argv0.clone()]);
@@ -458,7 +2021,7 @@ impl Run {
             args.push("--server".to_string());
         }
 
-        let exe_args = self.build_args.passthrough.exe_args();
+        let exe_args = self.exe_args();
         if !exe_args.is_empty() {
             // For some reason 3dslink seems to want 2 instances of `--`, one
             // in front of all of the args like this...
@@ -487,10 +2050,31 @@ impl Run {
     /// - `.cargo/config.toml`
     /// - Environment variables
     /// - Command-line `--config` overrides
+    ///
+    /// The custom runner is not used if a run target was selected with `--emulator`,
+    /// `--device` or `--address`, or with `test_runner` for any target of the package.
+    /// It is also never used with `--no-link`, since nothing should be run at all,
+    /// or with `--link-only`, since cargo isn't run, or with `--gdb`,
+    /// `--capture-screenshot`, `--romfs-changes` or `--romfs-mark-deployed`,
+    /// which need to know how the executable is launched.
     pub(crate) fn use_custom_runner(&self) -> bool {
         static HAS_RUNNER: OnceLock<bool> = OnceLock::new();
 
+        if self.no_link
+            || self.link_only
+            || self.gdb
+            || self.capture_screenshot.is_some()
+            || self.romfs_changes
+            || self.romfs_mark_deployed
+        {
+            return false;
+        }
+
         let &custom_runner_configured = HAS_RUNNER.get_or_init(|| {
+            if self.cli_run_target().is_some() || self.metadata_run_target().is_some() {
+                return false;
+            }
+
             let mut cmd = cargo(&self.config);
             cmd.args([
                 // https://github.com/rust-lang/cargo/issues/9301
@@ -503,20 +2087,16 @@ impl Run {
             .stdout(Stdio::null())
             .stderr(Stdio::null());
 
-            if self.build_args.verbose {
-                print_command(&cmd);
-            }
+            print_command(&cmd);
 
             // `cargo config get` exits zero if the config exists, or nonzero otherwise
-            cmd.status().map_or(false, |status| status.success())
+            cmd.status().is_ok_and(|status| status.success())
         });
 
-        if self.build_args.verbose {
-            eprintln!(
-                "Custom runner is {}configured",
-                if custom_runner_configured { "" } else { "not " }
-            );
-        }
+        debug!(
+            "Custom runner is {}configured",
+            if custom_runner_configured { "" } else { "not " }
+        );
 
         custom_runner_configured
     }
@@ -538,6 +2118,51 @@ impl Callbacks for Test {
 }
 
 impl Test {
+    /// Pass the test name filter and libtest flags given to `cargo 3ds test` to
+    /// the test executables, rather than to `cargo`. Doc tests are filtered by
+    /// `cargo` itself, so their args are left as they are.
+    pub fn route_harness_args(&mut self) {
+        if !self.doc {
+            self.run_args.build_args.passthrough.route_test_args();
+        }
+    }
+
+    /// Run each of the built test executables on the device or emulator,
+    /// one after another, and exit with an error if any of them failed.
+    ///
+    /// `3dslink` exits as soon as an executable is sent, so on a device the
+    /// 3dslink server is always used: it waits for each executable to finish
+    /// before the next is sent. Since the server doesn't report exit codes, a
+    /// test executable only fails the run if it prints a nonzero one with the
+    /// `cargo-3ds: exit code <CODE>` convention, or if `3dslink` fails.
+    fn run_each(&self, configs: &[CTRConfig]) {
+        let mut run_args = self.run_args.clone();
+        let on_device = configs
+            .iter()
+            .any(|config| run_args.run_target(config) == RunTarget::Device);
+        if on_device && !run_args.server {
+            info!("Using the 3dslink server (--server) to wait for each test executable");
+            run_args.server = true;
+        }
+
+        let results: Vec<_> = configs
+            .iter()
+            .map(|config| (config.path_3dsx(), run_args.launch(config).success()))
+            .collect();
+
+        if let Err(err) = check_test_results(&results) {
+            error!("{err}");
+            process::exit(1);
+        }
+    }
+
+    /// Print the path of each built test `.3dsx`, for when they aren't run.
+    fn report_built(&self, configs: &[CTRConfig]) {
+        for config in configs {
+            info!("Test executable ({}): {}", config.name, config.path_3dsx());
+        }
+    }
+
     fn should_run(&self) -> bool {
         self.run_args.use_custom_runner() && !self.no_run
     }
@@ -579,12 +2204,30 @@ impl Test {
     }
 }
 
-const TOML_CHANGES: &str = r#"ctru-rs = { git = "https://github.com/rust3ds/ctru-rs" }
-
-[package.metadata.cargo-3ds]
-romfs_dir = "romfs"
+/// The features of `ctru-rs`, listed in the `Cargo.toml` of a new project so
+/// they are easy to find.
+const CTRU_FEATURES_COMMENT: &str = r#"# ctru-rs features, which are enabled by default:
+#   romfs     - read files from the RomFS with `ctru::services::romfs`
+#   big-stack - give the main thread a bigger stack than libctru's 32 KiB
 "#;
 
+/// Get the changes to append to the `Cargo.toml` of a new project, which ends
+/// with its `[dependencies]`, for the `template`.
+fn toml_changes(template: &str) -> String {
+    let (dependency, metadata) = match template {
+        "minimal" => (
+            r#"ctru-rs = { git = "https://github.com/rust3ds/ctru-rs", default-features = false }"#,
+            "",
+        ),
+        _ => (
+            r#"ctru-rs = { git = "https://github.com/rust3ds/ctru-rs" }"#,
+            "\n[package.metadata.cargo-3ds]\nromfs_dir = \"romfs\"\n",
+        ),
+    };
+
+    format!("{CTRU_FEATURES_COMMENT}{dependency}\n{metadata}")
+}
+
 const CUSTOM_MAIN_RS: &str = r#"use ctru::prelude::*;
 
 fn main() {
@@ -625,8 +2268,10 @@ impl Callbacks for New {
         let dummy_romfs_path = romfs_path.join("PUT_YOUR_ROMFS_FILES_HERE.txt");
 
         // Create the "romfs" directory, and place a dummy file within it.
-        fs::create_dir(romfs_path).unwrap();
-        fs::File::create(dummy_romfs_path).unwrap();
+        if self.template != "minimal" {
+            fs::create_dir(romfs_path).unwrap();
+            fs::File::create(dummy_romfs_path).unwrap();
+        }
 
         // Read the contents of `Cargo.toml` to a string
         let mut buf = String::new();
@@ -636,7 +2281,7 @@ impl Callbacks for New {
             .unwrap();
 
         // Add the custom changes to the TOML
-        let buf = buf + TOML_CHANGES;
+        let buf = buf + &toml_changes(&self.template);
         fs::write(&toml_path, buf).unwrap();
 
         // Add the custom changes to the main.rs file
@@ -646,9 +2291,12 @@ impl Callbacks for New {
 
 #[cfg(test)]
 mod tests {
+    use std::net::Ipv4Addr;
+
     use clap::CommandFactory;
 
     use super::*;
+    use crate::tests::TestDir;
 
     #[test]
     fn verify_app() {
@@ -674,6 +2322,38 @@ mod tests {
                 Some("json-render-diagnostics"),
             ),
             (&["--foo", "bar"], None),
+            (
+                &["--foo", "--message-format", "json,artifacts", "bar"],
+                Some("json,artifacts"),
+            ),
+            (
+                &[
+                    "--foo",
+                    "--message-format=json-render-diagnostics,artifacts",
+                    "bar",
+                ],
+                Some("json-render-diagnostics,artifacts"),
+            ),
+            (
+                &[
+                    "--foo",
+                    "--message-format",
+                    "human",
+                    "bar",
+                    "--message-format=json",
+                ],
+                Some("json"),
+            ),
+            (
+                &[
+                    "--message-format=json",
+                    "--foo",
+                    "--message-format",
+                    "json-render-diagnostics",
+                    "bar",
+                ],
+                Some("json-render-diagnostics"),
+            ),
         ];
 
         for (args, expected) in CASES {
@@ -681,7 +2361,19 @@ mod tests {
                 passthrough: RemainingArgs {
                     args: args.iter().map(ToString::to_string).collect(),
                 },
-                verbose: false,
+                elf_output: None,
+                cfg_profile: None,
+                config_file: None,
+                force: false,
+                truncate_smdh: false,
+                smdh: None,
+                romfs_image: None,
+                keep_temp: false,
+                max_romfs_size: None,
+                emit_script: None,
+                print_artifacts: false,
+                bundle: None,
+                reproducible: false,
             });
 
             assert_eq!(
@@ -699,18 +2391,634 @@ mod tests {
 
     #[test]
     fn extract_format_err() {
-        for args in [&["--message-format=foo"][..], &["--message-format", "foo"]] {
+        for args in [
+            &["--message-format=foo"][..],
+            &["--message-format", "foo"],
+            &["--message-format=json", "--message-format=human"],
+            &["--message-format"],
+            &["--message-format=short,artifacts"],
+        ] {
             let mut cmd = CargoCmd::Build(Build {
                 passthrough: RemainingArgs {
                     args: args.iter().map(ToString::to_string).collect(),
                 },
-                verbose: false,
+                elf_output: None,
+                cfg_profile: None,
+                config_file: None,
+                force: false,
+                truncate_smdh: false,
+                smdh: None,
+                romfs_image: None,
+                keep_temp: false,
+                max_romfs_size: None,
+                emit_script: None,
+                print_artifacts: false,
+                bundle: None,
+                reproducible: false,
             });
 
             assert!(cmd.extract_message_format().is_err());
         }
     }
 
+    #[test]
+    fn artifact_dir() {
+        for (args, dir, unstable) in [
+            (&["build"][..], None, false),
+            (
+                &["build", "--", "--artifact-dir", "out"],
+                Some("out"),
+                false,
+            ),
+            (
+                &["build", "--", "-Z", "unstable-options", "--out-dir=dist"],
+                Some("dist"),
+                true,
+            ),
+            (
+                &["run", "--", "-Zunstable-options", "--out-dir", "dist"],
+                Some("dist"),
+                true,
+            ),
+        ] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds"].iter().chain(args)).unwrap();
+            assert_eq!(
+                cmd.artifact_dir().as_deref(),
+                dir.map(Utf8Path::new),
+                "{args:?}"
+            );
+            assert_eq!(cmd.unstable_options(), unstable, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn standalone_invocation() {
+        for args in [&["build", "--release"][..], &["-v", "run", "--", "-a"], &[]] {
+            let parse = |args: Vec<&str>| {
+                Cargo::try_parse_from(Cargo::subcommand_args(args))
+                    .map(|cargo| format!("{cargo:?}"))
+            };
+
+            let subcommand = parse(["cargo-3ds", "3ds"].iter().chain(args).copied().collect());
+            let standalone = parse(["cargo-3ds"].iter().chain(args).copied().collect());
+            assert_eq!(
+                subcommand.map_err(|err| err.kind()),
+                standalone.map_err(|err| err.kind()),
+                "{args:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn verbose_levels() {
+        for (args, expected) in [
+            (&["build"][..], 0),
+            (&["build", "-v"], 1),
+            (&["-vv", "build"], 2),
+            (&["build", "--verbose", "-v"], 2),
+        ] {
+            let Cargo::Input(input) =
+                Cargo::try_parse_from(["cargo", "3ds"].iter().chain(args)).unwrap();
+            assert_eq!(input.verbose, expected, "{args:?}");
+        }
+
+        let Cargo::Input(input) =
+            Cargo::try_parse_from(["cargo", "3ds", "build", "--color", "never"]).unwrap();
+        assert_eq!(input.color.as_deref(), Some("never"));
+        assert!(input.cmd.cargo_args().is_empty());
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "--color", "sometimes", "build"]).is_err());
+
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "-v", "-q", "build"]).is_err());
+    }
+
+    #[test]
+    fn smdh_config() {
+        let metadata: Metadata = serde_json::from_value(serde_json::json!({
+            "packages": [{
+                "name": "foo",
+                "version": "0.1.0",
+                "id": "foo 0.1.0 (path+file:///project)",
+                "dependencies": [],
+                "targets": [],
+                "features": {},
+                "manifest_path": "/project/Cargo.toml",
+                "metadata": { "cargo-3ds": { "release": { "description": "Release" } } },
+            }],
+            "workspace_members": ["foo 0.1.0 (path+file:///project)"],
+            "resolve": null,
+            "workspace_root": "/project",
+            "target_directory": "/project/target",
+            "version": 1,
+        }))
+        .unwrap();
+
+        let config = |args: &[&str]| {
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Smdh(smdh),
+                ..
+            }) = Cargo::try_parse_from(["cargo", "3ds", "smdh"].iter().chain(args)).unwrap()
+            else {
+                unreachable!()
+            };
+            smdh.config(&metadata)
+        };
+
+        let debug = config(&[]).unwrap();
+        assert_eq!(debug.name, "foo");
+        assert_eq!(
+            debug.path_smdh(),
+            "/project/target/armv6k-nintendo-3ds/debug/foo.smdh"
+        );
+
+        let release = config(&["--release", "-o", "dist/foo.smdh"]).unwrap();
+        assert_eq!(release.description(), "Release");
+        assert_eq!(release.path_smdh(), "dist/foo.smdh");
+
+        assert!(config(&["-o", "dist/foo.png"]).is_err());
+        assert!(config(&["-p", "bar"]).is_err());
+    }
+
+    #[test]
+    fn passed_target() {
+        for (args, expected) in [
+            (&["build"][..], Ok(false)),
+            (&["build", "--", "--target-dir", "out"], Ok(false)),
+            (
+                &["build", "--", "--target", "armv6k-nintendo-3ds"],
+                Ok(true),
+            ),
+            (&["clippy", "--target=armv6k-nintendo-3ds"], Ok(true)),
+            (
+                &["build", "--", "--target", "x86_64-unknown-linux-gnu"],
+                Err(()),
+            ),
+            (
+                &[
+                    "check",
+                    "--target=armv6k-nintendo-3ds",
+                    "--target=thumbv7a-none-eabi",
+                ],
+                Err(()),
+            ),
+        ] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds"].iter().chain(args)).unwrap();
+            assert_eq!(cmd.passed_target().map_err(drop), expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn new_edition() {
+        for (args, expected) in [
+            (&["foo"][..], &["foo"][..]),
+            (&["foo", "--edition", "2024"], &["--edition", "2024", "foo"]),
+            (
+                &["--edition=2021", "foo", "--vcs", "none"],
+                &["--vcs", "none", "--edition", "2021", "foo"],
+            ),
+        ] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds", "new"].iter().chain(args)).unwrap();
+            assert_eq!(cmd.cargo_args(), expected, "{args:?}");
+        }
+
+        for edition in ["2015", "2027", "latest"] {
+            let err = Cargo::try_parse_from(["cargo", "3ds", "new", "foo", "--edition", edition])
+                .unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::InvalidValue);
+        }
+    }
+
+    #[test]
+    fn test_harness_args() {
+        for (args, expected_cargo, expected_exe) in [
+            (&["my_test"][..], &[][..], &["my_test"][..]),
+            (&["my_test", "--", "--exact"], &[], &["my_test", "--exact"]),
+            (
+                &["--release", "-p", "app", "--ignored", "module::"],
+                &["--release", "-p", "app"],
+                &["--ignored", "module::"],
+            ),
+            (
+                &[
+                    "--test",
+                    "integration",
+                    "--skip",
+                    "slow",
+                    "--test-threads=1",
+                ],
+                &["--test", "integration"],
+                &["--skip", "slow", "--test-threads=1"],
+            ),
+            (&["--", "--", "--nocapture"], &[], &["--nocapture"]),
+            (
+                &["--features=foo", "--verbose"],
+                &["--features=foo", "--verbose"],
+                &[],
+            ),
+        ] {
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Test(mut test),
+                ..
+            }) = Cargo::try_parse_from(["cargo", "3ds", "test"].iter().chain(args)).unwrap()
+            else {
+                panic!("not a test command");
+            };
+            test.route_harness_args();
+
+            let passthrough = &test.run_args.build_args.passthrough;
+            assert_eq!(passthrough.cargo_args(), expected_cargo, "{args:?}");
+            assert_eq!(passthrough.exe_args(), expected_exe, "{args:?}");
+        }
+
+        // Doc tests are filtered by cargo
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Test(mut test),
+            ..
+        }) = Cargo::try_parse_from(["cargo", "3ds", "test", "--doc", "my_test"]).unwrap()
+        else {
+            panic!("not a test command");
+        };
+        test.route_harness_args();
+        assert_eq!(
+            test.run_args.build_args.passthrough.cargo_args(),
+            ["my_test"]
+        );
+    }
+
+    #[test]
+    fn new_template() {
+        let template = |args: &[&str]| {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds", "new", "foo"].iter().chain(args)).unwrap();
+            // The template isn't passed on to `cargo new`
+            assert_eq!(cmd.cargo_args(), ["foo"]);
+            let CargoCmd::New(new) = cmd else {
+                panic!("not a new command");
+            };
+            template_manifest(&toml_changes(&new.template))
+        };
+
+        let console = template(&[]);
+        assert_eq!(
+            console["package"]["metadata"]["cargo-3ds"]["romfs_dir"].as_str(),
+            Some("romfs")
+        );
+        assert!(console["dependencies"]["ctru-rs"]
+            .get("default-features")
+            .is_none());
+
+        let minimal = template(&["--template", "minimal"]);
+        assert!(minimal.get("package").is_none());
+        assert_eq!(
+            minimal["dependencies"]["ctru-rs"]["default-features"].as_bool(),
+            Some(false)
+        );
+
+        assert!(
+            Cargo::try_parse_from(["cargo", "3ds", "new", "foo", "--template", "gui"]).is_err()
+        );
+    }
+
+    /// Parse the changes for a template as appended to a new `Cargo.toml`.
+    fn template_manifest(changes: &str) -> toml::Table {
+        toml::from_str(&format!("[dependencies]\n{changes}")).unwrap()
+    }
+
+    #[test]
+    fn default_format_from_env() {
+        let no_config = UserConfig::default();
+        assert_eq!(
+            CargoCmd::message_format_from(None, &no_config).unwrap(),
+            CargoCmd::DEFAULT_MESSAGE_FORMAT
+        );
+        assert_eq!(
+            CargoCmd::message_format_from(Some(String::new()), &no_config).unwrap(),
+            CargoCmd::DEFAULT_MESSAGE_FORMAT
+        );
+        assert_eq!(
+            CargoCmd::message_format_from(Some("json-diagnostic-rendered-ansi".into()), &no_config)
+                .unwrap(),
+            "json-diagnostic-rendered-ansi"
+        );
+        assert!(CargoCmd::message_format_from(Some("short".into()), &no_config).is_err());
+
+        // The environment variable takes precedence over the user config
+        let user_config = UserConfig {
+            message_format: Some("json-diagnostic-short".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            CargoCmd::message_format_from(None, &user_config).unwrap(),
+            "json-diagnostic-short"
+        );
+        assert_eq!(
+            CargoCmd::message_format_from(Some("json".into()), &user_config).unwrap(),
+            "json"
+        );
+    }
+
+    #[test]
+    fn stdout_to_requires_server() {
+        let parse = |args: &[&str]| {
+            Cargo::try_parse_from(["cargo", "3ds", "run"].iter().chain(args).copied())
+        };
+
+        assert!(parse(&["--stdout-to", "out.txt"]).is_err());
+
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(run),
+            ..
+        }) = parse(&["--server", "--stdout-to", "out.txt"]).unwrap()
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+        assert_eq!(run.stdout_to.as_deref(), Some("out.txt".into()));
+
+        assert!(parse(&["--server-timeout", "10"]).is_err());
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(run),
+            ..
+        }) = parse(&["--server", "--server-timeout", "10"]).unwrap()
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+        assert_eq!(run.server_timeout(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn multiple_addresses() {
+        let parse = |args: &[&str]| {
+            Cargo::try_parse_from(["cargo", "3ds", "run"].iter().chain(args).copied())
+        };
+
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(run),
+            ..
+        }) = parse(&[
+            "-a",
+            "192.168.0.2",
+            "--address",
+            "192.168.0.3",
+            "--parallel",
+        ])
+        .unwrap()
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+        assert_eq!(
+            run.address,
+            [Ipv4Addr::new(192, 168, 0, 2), Ipv4Addr::new(192, 168, 0, 3)]
+        );
+        assert!(run.parallel);
+        assert!(!run.get_3dslink_args().contains(&String::from("--address")));
+
+        assert!(parse(&["--parallel"]).is_err());
+        assert!(parse(&["-a", "192.168.0.2", "--parallel", "--server"]).is_err());
+    }
+
+    #[test]
+    fn force_rebuild() {
+        for subcommand in ["build", "run", "test"] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds", subcommand, "--force"]).unwrap();
+
+            assert!(cmd.build_args().unwrap().force, "{subcommand}");
+        }
+    }
+
+    #[test]
+    fn cli_run_target() {
+        for (args, expected) in [
+            (&[][..], None),
+            (&["--emulator"], Some(RunTarget::Emulator)),
+            (&["--device"], Some(RunTarget::Device)),
+            (&["--address", "192.168.0.2"], Some(RunTarget::Device)),
+            (&["--interface", "192.168.0.10"], Some(RunTarget::Device)),
+        ] {
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Run(run),
+                ..
+            }) = Cargo::try_parse_from(["cargo", "3ds", "run"].iter().chain(args)).unwrap()
+            else {
+                panic!("parsed as something other than `run` subcommand")
+            };
+
+            assert_eq!(run.cli_run_target(), expected, "{args:?}");
+        }
+
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "run", "--emulator", "--device"]).is_err());
+    }
+
+    #[test]
+    fn cargo_flag_value() {
+        let args: Vec<String> = ["--example", "foo", "--target-dir=out", "--bin"]
+            .map(String::from)
+            .into();
+
+        assert_eq!(flag_value(&args, "--example").as_deref(), Some("foo"));
+        assert_eq!(flag_value(&args, "--target-dir").as_deref(), Some("out"));
+        assert_eq!(flag_value(&args, "--bin"), None);
+        assert_eq!(flag_value(&args, "--target"), None);
+        assert_eq!(flag_value(&args, "--profile"), None);
+
+        assert!(
+            Cargo::try_parse_from(["cargo", "3ds", "run", "--link-only", "--no-link"]).is_err()
+        );
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "run", "--no-build-3dsx"]).is_ok());
+    }
+
+    #[test]
+    fn max_romfs_size() {
+        for (size, expected) in [
+            ("1024", Some(1024)),
+            ("32M", Some(32 * 1024 * 1024)),
+            ("2 GiB", Some(2 * 1024 * 1024 * 1024)),
+            ("512k", Some(512 * 1024)),
+            ("M", None),
+            ("10T", None),
+            ("-1", None),
+        ] {
+            assert_eq!(parse_size(size).ok(), expected, "{size}");
+        }
+    }
+
+    #[test]
+    fn args_file() {
+        let dir = TestDir::new();
+        let path = dir.join("args.txt");
+        fs::write(
+            &path,
+            "# Test harness configuration\n--filter 'net tests'\n\n--threads=1\n",
+        )
+        .unwrap();
+
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(mut run),
+            ..
+        }) = Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            "run",
+            "--args-file",
+            path.as_str(),
+            "--",
+            "--",
+            "-v",
+        ])
+        .unwrap()
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+
+        // The file is only read once the arguments are parsed
+        assert_eq!(run.exe_args(), ["-v"]);
+        run.load_args_file().unwrap();
+        assert_eq!(
+            run.exe_args(),
+            ["-v", "--filter", "net tests", "--threads=1"]
+        );
+
+        fs::write(&path, "--filter 'unterminated\n").unwrap();
+        assert!(read_args_file(&path).is_err());
+    }
+
+    #[test]
+    fn build_std_test_crate() {
+        for (args, expected) in [
+            (&["build"][..], false),
+            (&["run", "--example", "foo"], false),
+            (&["check"], false),
+            (&["test", "--no-run"], true),
+            (&["bench"], true),
+            (&["clippy", "--all-targets"], true),
+            (&["build", "--test=integration"], true),
+        ] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds"].iter().chain(args)).unwrap();
+
+            assert_eq!(cmd.may_build_tests(), expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn rustc_flags() {
+        let Cargo::Input(Input { cmd, .. }) = Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            "rustc",
+            "--release",
+            "--",
+            "-C",
+            "opt-level=s",
+        ])
+        .unwrap();
+
+        assert!(matches!(cmd, CargoCmd::Rustc(_)));
+        assert!(cmd.should_compile());
+        assert!(cmd.should_build_3dsx());
+        assert_eq!(cmd.subcommand_name(), "rustc");
+        assert_eq!(cmd.cargo_args(), ["--release"]);
+
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(["cargo", "3ds", "rustc", "--bin", "app"]).unwrap();
+        assert_eq!(cmd.cargo_args(), ["--bin", "app"]);
+
+        // flags for rustc aren't mistaken for cargo's own
+        let Cargo::Input(Input { cmd, .. }) = Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            "rustc",
+            "--release",
+            "--",
+            "-Z",
+            "unstable-options",
+            "--target",
+            "x86_64-unknown-linux-gnu",
+        ])
+        .unwrap();
+        assert!(!cmd.unstable_options());
+        assert_eq!(cmd.passed_target(), Ok(false));
+    }
+
+    #[test]
+    fn expand_no_3dsx() {
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(["cargo", "3ds", "expand", "--lib"]).unwrap();
+
+        assert!(matches!(cmd, CargoCmd::Expand(_)));
+        assert!(cmd.should_compile());
+        assert!(!cmd.should_link());
+        assert!(!cmd.uses_message_format());
+        assert!(!cmd.should_build_3dsx());
+        assert_eq!(cmd.subcommand_name(), "expand");
+        assert_eq!(cmd.cargo_args(), ["--lib"]);
+    }
+
+    #[test]
+    fn passthrough_compile_env() {
+        for (subcommand, compiles, links) in [
+            ("check", true, false),
+            ("clippy", true, false),
+            ("doc", true, false),
+            ("bench", true, true),
+            ("fmt", false, false),
+            ("tree", false, false),
+            ("metadata", false, false),
+        ] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds", subcommand]).unwrap();
+
+            assert!(matches!(cmd, CargoCmd::Passthrough(_)), "{subcommand}");
+            assert_eq!(cmd.should_compile(), compiles, "{subcommand}");
+            assert_eq!(cmd.should_link(), links, "{subcommand}");
+        }
+    }
+
+    #[test]
+    fn print_artifacts() {
+        let config = CTRConfig {
+            name: String::from("foo - bar example"),
+            target_path: "target/debug/examples/foo.elf".into(),
+            ..Default::default()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&artifact_json(&config)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "foo - bar example",
+                "elf": "target/debug/examples/foo.elf",
+                "3dsx": "target/debug/examples/foo.3dsx",
+                "smdh": "target/debug/examples/foo.smdh",
+            })
+        );
+        assert!(!artifact_json(&config).contains('\n'));
+    }
+
+    #[test]
+    fn output_collisions() {
+        let config = |name: &str, path: &str| CTRConfig {
+            name: name.to_string(),
+            target_path: path.into(),
+            ..Default::default()
+        };
+
+        assert!(check_output_collisions(&[
+            config("foo", "debug/foo.elf"),
+            config("foo - foo example", "debug/examples/foo.elf"),
+            config("foo", "debug/foo.elf"),
+        ])
+        .is_ok());
+
+        assert!(check_output_collisions(&[
+            config("foo", "debug/foo.elf"),
+            config("foo tests", "debug/foo"),
+        ])
+        .is_err());
+    }
+
     #[test]
     fn split_run_args() {
         struct TestParam {
@@ -760,4 +3068,18 @@ mod tests {
             assert_eq!(build_args.passthrough.exe_args(), param.expected_exe);
         }
     }
+
+    #[test]
+    fn test_results() {
+        let result =
+            |name: &str, passed| (Utf8PathBuf::from(format!("target/{name}.3dsx")), passed);
+
+        assert!(check_test_results(&[result("a", true), result("b", true)]).is_ok());
+        assert_eq!(
+            check_test_results(&[result("a", false), result("b", true), result("c", false)]),
+            Err(String::from(
+                r#"2 of 3 test executables failed: ["a.3dsx", "c.3dsx"]"#
+            ))
+        );
+    }
 }
@@ -0,0 +1,223 @@
+//! Tracking of the romfs files sent to a device, so that tools which can update
+//! the files of an installed executable (e.g. over FTP, with a loader on the
+//! device which reads them from the SD card) only need to transfer the changes.
+//!
+//! `3dslink` itself can only send a whole 3dsx, so `cargo-3ds` doesn't transfer
+//! anything incrementally: `run --romfs-changes` lists the changes since the
+//! hash of every romfs file was recorded with `run --romfs-mark-deployed`. Once
+//! recorded, the hashes are also updated whenever a whole 3dsx is sent.
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{sha256_file, CTRConfig};
+
+/// The romfs files of a deployed executable, mapping each path in the romfs to
+/// the SHA-256 hash of its contents.
+#[derive(Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RomfsState {
+    files: BTreeMap<Utf8PathBuf, String>,
+}
+
+/// How a romfs file changed since the last deploy.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// A romfs file which changed since the last deploy.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct RomfsChange {
+    /// The path of the file in the romfs.
+    pub(crate) path: Utf8PathBuf,
+    /// The file to transfer, unless it was removed.
+    pub(crate) source: Option<Utf8PathBuf>,
+    pub(crate) change: ChangeKind,
+}
+
+impl RomfsState {
+    /// Hash the romfs `files`, given as pairs of (source, destination).
+    pub(crate) fn scan(files: &[(Utf8PathBuf, Utf8PathBuf)]) -> Result<Self, String> {
+        let files = files
+            .iter()
+            .map(|(src, dest)| Ok((dest.clone(), sha256_file(src)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Self { files })
+    }
+
+    /// Read the state recorded at `path`, or `None` if nothing was recorded yet.
+    pub(crate) fn load(path: &Utf8Path) -> Result<Option<Self>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("unable to read {path}: {err}"))?;
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|err| format!("invalid romfs deploy state {path}: {err}"))
+    }
+
+    /// Record the state at `path`, replacing any previous one.
+    pub(crate) fn save(&self, path: &Utf8Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, contents).map_err(|err| format!("unable to write {path}: {err}"))
+    }
+
+    /// List the changes from the `previous` state to this one, for the romfs
+    /// `files` this state was scanned from. Every file is added if there is no
+    /// previous state.
+    pub(crate) fn changes_since(
+        &self,
+        previous: Option<&Self>,
+        files: &[(Utf8PathBuf, Utf8PathBuf)],
+    ) -> Vec<RomfsChange> {
+        let empty = Self::default();
+        let previous = previous.unwrap_or(&empty);
+        let source = |path: &Utf8Path| {
+            files
+                .iter()
+                .find(|(_, dest)| dest == path)
+                .map(|(src, _)| src.clone())
+        };
+
+        let mut changes: Vec<_> = self
+            .files
+            .iter()
+            .filter_map(|(path, hash)| {
+                let change = match previous.files.get(path) {
+                    None => ChangeKind::Added,
+                    Some(previous_hash) if previous_hash != hash => ChangeKind::Modified,
+                    Some(_) => return None,
+                };
+                Some(RomfsChange {
+                    path: path.clone(),
+                    source: source(path),
+                    change,
+                })
+            })
+            .collect();
+
+        changes.extend(
+            previous
+                .files
+                .keys()
+                .filter(|path| !self.files.contains_key(*path))
+                .map(|path| RomfsChange {
+                    path: path.clone(),
+                    source: None,
+                    change: ChangeKind::Removed,
+                }),
+        );
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        changes
+    }
+}
+
+/// Record the romfs of `config` as deployed, for `run --romfs-mark-deployed`.
+pub(crate) fn record(config: &CTRConfig) -> Result<(), String> {
+    let files = config.romfs_contents()?;
+    RomfsState::scan(&files)?.save(&config.path_romfs_deploy_state())
+}
+
+/// Record the romfs of `config` as deployed after its 3dsx was sent to a device,
+/// but only if it was recorded before, so that the files aren't hashed on every
+/// run for the users who never list the changes.
+pub(crate) fn update(config: &CTRConfig) -> Result<(), String> {
+    if config.path_romfs_deploy_state().exists() {
+        record(config)
+    } else {
+        Ok(())
+    }
+}
+
+/// List the romfs files of `config` which changed since it was last recorded as
+/// deployed, for `run --romfs-changes`. They are only recorded as deployed with
+/// [`record`], once they have actually been transferred.
+pub(crate) fn changes(config: &CTRConfig) -> Result<Vec<RomfsChange>, String> {
+    let files = config.romfs_contents()?;
+    let state = RomfsState::scan(&files)?;
+    let previous = RomfsState::load(&config.path_romfs_deploy_state())?;
+    Ok(state.changes_since(previous.as_ref(), &files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    #[test]
+    fn romfs_changes() {
+        let dir = TestDir::new();
+
+        let file = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            (path, Utf8PathBuf::from(name))
+        };
+
+        let before = [
+            file("kept.txt", "kept"),
+            file("changed.txt", "old"),
+            file("removed.txt", "x"),
+        ];
+        let previous = RomfsState::scan(&before).unwrap();
+
+        let state_path = dir.join("state.json");
+        assert_eq!(RomfsState::load(&state_path).unwrap(), None);
+        previous.save(&state_path).unwrap();
+        let previous = RomfsState::load(&state_path).unwrap().unwrap();
+
+        let after = [
+            file("kept.txt", "kept"),
+            file("changed.txt", "new"),
+            file("added.txt", "y"),
+        ];
+        let state = RomfsState::scan(&after).unwrap();
+
+        let listed: Vec<_> = state
+            .changes_since(Some(&previous), &after)
+            .into_iter()
+            .map(|change| (change.path, change.source.is_some(), change.change))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("added.txt".into(), true, ChangeKind::Added),
+                ("changed.txt".into(), true, ChangeKind::Modified),
+                ("removed.txt".into(), false, ChangeKind::Removed),
+            ]
+        );
+
+        // Without a previous deploy, everything is new
+        assert_eq!(state.changes_since(None, &after).len(), 3);
+        assert!(state.changes_since(Some(&state), &after).is_empty());
+
+        // Listing the changes doesn't record them, and sending a 3dsx only
+        // updates a record which already exists
+        fs::create_dir_all(dir.join("romfs")).unwrap();
+        fs::write(dir.join("romfs/data.bin"), "data").unwrap();
+        let config = CTRConfig {
+            manifest_dir: dir.clone(),
+            target_path: dir.join("app.elf"),
+            ..Default::default()
+        };
+        assert_eq!(changes(&config).unwrap().len(), 1);
+        update(&config).unwrap();
+        assert!(!config.path_romfs_deploy_state().exists());
+
+        record(&config).unwrap();
+        assert!(changes(&config).unwrap().is_empty());
+        fs::write(dir.join("romfs/data.bin"), "changed").unwrap();
+        assert_eq!(changes(&config).unwrap()[0].change, ChangeKind::Modified);
+        update(&config).unwrap();
+        assert!(changes(&config).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The port the Homebrew Launcher's netloader listens on, for both discovery
+/// (UDP) and receiving executables (TCP).
+const NETLOADER_PORT: u16 = 17491;
+
+/// The message `3dslink` sends to discover a device, and the expected reply.
+const DISCOVERY_MESSAGE: &[u8] = b"3dsboot";
+const DISCOVERY_REPLY: &[u8] = b"boot3ds";
+
+/// How long to wait for a reply to a single discovery message.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often to print a message while waiting for a device.
+const WAITING_MESSAGE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Resolve the host name of a device to its IPv4 address, for `--device-name`.
+/// Whether mDNS names (e.g. `my-3ds.local`) can be resolved depends on the
+/// system's resolver.
+pub(crate) fn resolve(name: &str) -> Result<Ipv4Addr, String> {
+    let addresses = (name, NETLOADER_PORT)
+        .to_socket_addrs()
+        .map_err(|err| format!("unable to resolve device name `{name}`: {err}"))?;
+
+    addresses
+        .filter_map(|address| match address {
+            SocketAddr::V4(address) => Some(*address.ip()),
+            SocketAddr::V6(_) => None,
+        })
+        .next()
+        .ok_or_else(|| format!("device name `{name}` has no IPv4 address"))
+}
+
+/// Send a discovery message the same way `3dslink` does, and return the address
+/// of the device which replied. If `address` is given, only that device is probed,
+/// otherwise the message is broadcast to the local network. The message is sent
+/// from the network interface with the local address `source`, which may be
+/// [`Ipv4Addr::UNSPECIFIED`] to let the OS choose.
+fn probe(address: Option<Ipv4Addr>, source: Ipv4Addr) -> io::Result<Option<Ipv4Addr>> {
+    let socket = UdpSocket::bind((source, 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_read_timeout(Some(PROBE_TIMEOUT))?;
+
+    let target = address.unwrap_or(Ipv4Addr::BROADCAST);
+    socket.send_to(DISCOVERY_MESSAGE, (target, NETLOADER_PORT))?;
+
+    let mut buf = [0; 16];
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    while Instant::now() < deadline {
+        match socket.recv_from(&mut buf) {
+            Ok((len, SocketAddr::V4(from))) if buf[..len].starts_with(DISCOVERY_REPLY) => {
+                if address.is_none_or(|address| address == *from.ip()) {
+                    return Ok(Some(*from.ip()));
+                }
+            }
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Try to discover a device running the netloader from the interface with the
+/// local address `source` up to `tries` times, like `3dslink` does with its
+/// `--retries`. Returns the address of the device that was found.
+///
+/// # Errors
+///
+/// Returns an error message if no device was found in any of the tries.
+pub(crate) fn discover(
+    address: Option<Ipv4Addr>,
+    source: Ipv4Addr,
+    tries: usize,
+) -> Result<Ipv4Addr, String> {
+    for _ in 0..tries.max(1) {
+        match probe(address, source) {
+            Ok(Some(found)) => {
+                debug!("Found device at {found}");
+                return Ok(found);
+            }
+            Ok(None) => {}
+            Err(err) => debug!("Device discovery failed: {err}"),
+        }
+    }
+
+    Err(format!("no device found after {tries} tries"))
+}
+
+/// Poll until a device running the netloader responds to discovery from the
+/// interface with the local address `source`, or `timeout` elapses. Returns the
+/// address of the device that was found.
+///
+/// # Errors
+///
+/// Returns an error message if no device was found before the timeout.
+pub(crate) fn wait_for_device(
+    address: Option<Ipv4Addr>,
+    source: Ipv4Addr,
+    timeout: Duration,
+) -> Result<Ipv4Addr, String> {
+    let start = Instant::now();
+    let mut last_message = None;
+
+    loop {
+        match probe(address, source) {
+            Ok(Some(found)) => {
+                debug!("Found device at {found}");
+                return Ok(found);
+            }
+            Ok(None) => {}
+            Err(err) => debug!("Device discovery failed: {err}"),
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(format!(
+                "no device found after waiting {} seconds",
+                timeout.as_secs()
+            ));
+        }
+
+        if last_message.is_none_or(|last: Instant| last.elapsed() >= WAITING_MESSAGE_INTERVAL) {
+            match address {
+                Some(address) => info!("Waiting for device at {address}..."),
+                None => info!("Waiting for device..."),
+            }
+            last_message = Some(Instant::now());
+        }
+
+        thread::sleep(PROBE_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_device_name() {
+        // IP literals are parsed without a DNS lookup, which would make the test
+        // depend on the network and resolver
+        assert_eq!(resolve("192.168.0.2"), Ok(Ipv4Addr::new(192, 168, 0, 2)));
+    }
+
+    #[test]
+    fn discover_tries() {
+        // Nothing replies to discovery on the loopback interface
+        let err = discover(Some(Ipv4Addr::LOCALHOST), Ipv4Addr::LOCALHOST, 1).unwrap_err();
+        assert_eq!(err, "no device found after 1 tries");
+    }
+}
@@ -1,11 +1,12 @@
 use std::error::Error;
-use std::io::Read;
-use std::process::{Command, Stdio};
+use std::fmt;
+use std::io;
+use std::process::Command;
 
 use cargo_metadata::Target;
 use serde::Deserialize;
 
-use crate::print_command;
+use crate::{output_with_timeout, print_command, probe_timeout};
 
 /// In lieu of <https://github.com/oli-obk/cargo_metadata/issues/107>
 /// and to avoid pulling in the real `cargo`
@@ -23,7 +24,7 @@ impl UnitGraph {
     /// build and the graph is output instead.
     ///
     /// See <https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#unit-graph>.
-    pub(crate) fn from_cargo(cargo_cmd: &Command, verbose: bool) -> Result<Self, Box<dyn Error>> {
+    pub(crate) fn from_cargo(cargo_cmd: &Command) -> Result<Self, GraphError> {
         // Since Command isn't Clone, copy it "by hand", by copying its args and envs
         let mut cmd = Command::new(cargo_cmd.get_program());
 
@@ -33,48 +34,93 @@ impl UnitGraph {
             // place is to just stick them immediately after the first arg (subcommand)
             .args(["-Z", "unstable-options", "--unit-graph"])
             .args(args)
-            .envs(cargo_cmd.get_envs().filter_map(|(k, v)| Some((k, v?))))
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .envs(cargo_cmd.get_envs().filter_map(|(k, v)| Some((k, v?))));
 
-        if verbose {
-            print_command(&cmd);
-        }
+        print_command(&cmd);
 
-        let mut proc = cmd.spawn()?;
-        let stdout = proc.stdout.take().unwrap();
-        let mut stderr = proc.stderr.take().unwrap();
+        let output = output_with_timeout(&mut cmd, probe_timeout()).map_err(GraphError::Spawn)?;
 
-        let result: Self = serde_json::from_reader(stdout).map_err(|err| {
-            let mut stderr_str = String::new();
-            let _ = stderr.read_to_string(&mut stderr_str);
+        let json = String::from_utf8_lossy(&output.stdout);
+        trace!("{json}");
 
-            let _ = proc.wait();
-            format!("unable to parse `--unit-graph` json: {err}\nstderr: `{stderr_str}`")
+        let result: Self = serde_json::from_str(&json).map_err(|err| GraphError::Parse {
+            source: err,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
         })?;
 
-        let _status = proc.wait()?;
+        let _status = output.status;
         // TODO: with cargo 1.74.0-nightly (b4ddf95ad 2023-09-18),
         // `cargo run --unit-graph` panics at src/cargo/ops/cargo_run.rs:83:5
         // It seems to have been fixed as of cargo 1.76.0-nightly (71cd3a926 2023-11-20)
         // so maybe we can stop ignoring it once we bump the minimum toolchain version,
-        // and certainly we should once `--unit-graph` is ever stabilized.
+        // and certainly we should once `--unit-graph` is ever stabilized, with a
+        // new `GraphError` variant for the exit status:
         //
         // if !status.success() {
-        //     return Err(format!("`cargo --unit-graph` exited with status {status:?}").into());
+        //     return Err(GraphError::Failed(status));
         // }
 
         if result.version == 1 {
             Ok(result)
         } else {
-            Err(format!(
-                "unknown `cargo --unit-graph` output version {}",
-                result.version
-            ))?
+            Err(GraphError::UnsupportedVersion(result.version))
+        }
+    }
+}
+
+/// The ways collecting the unit graph with [`UnitGraph::from_cargo`] can fail.
+#[derive(Debug)]
+pub enum GraphError {
+    /// `cargo` could not be run, or it didn't exit before the probe timeout.
+    Spawn(io::Error),
+    /// The output of `cargo` was not a valid unit graph, usually because `cargo`
+    /// itself failed. Its stderr is kept to explain why.
+    Parse {
+        source: serde_json::Error,
+        stderr: String,
+    },
+    /// The unit graph has a version this crate doesn't know how to read.
+    UnsupportedVersion(i32),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Spawn(err) => write!(f, "unable to run `cargo --unit-graph`: {err}"),
+            Self::Parse { source, stderr } => {
+                write!(f, "unable to parse `--unit-graph` json: {source}")?;
+                let stderr = stderr.trim();
+                if !stderr.is_empty() {
+                    write!(f, "\nstderr: `{stderr}`")?;
+                }
+                Ok(())
+            }
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unknown `cargo --unit-graph` output version {version}")
+            }
+        }
+    }
+}
+
+impl Error for GraphError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Spawn(err) => Some(err),
+            Self::Parse { source, .. } => Some(source),
+            Self::UnsupportedVersion(_) => None,
         }
     }
 }
 
+impl UnitGraph {
+    /// Find the unit for building the `ctru-sys` library, if it is part of the build.
+    pub(crate) fn ctru_sys_unit(&self) -> Option<&Unit> {
+        self.units
+            .iter()
+            .find(|unit| unit.target.name == "ctru_sys")
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Unit {
     pub target: Target,
@@ -86,5 +132,98 @@ pub struct Unit {
 /// really care about `debuginfo` anyway.
 #[derive(Deserialize)]
 pub struct Profile {
-    pub debuginfo: Option<u32>,
+    /// The name of the profile, e.g. `dev`, `release` or a custom profile.
+    #[serde(default)]
+    pub name: String,
+    pub debuginfo: Option<DebugInfo>,
+}
+
+impl Profile {
+    /// Whether this profile builds with any debuginfo. This matches the `DEBUG`
+    /// environment variable cargo passes to build scripts such as `ctru-sys`'s.
+    pub fn has_debuginfo(&self) -> bool {
+        match &self.debuginfo {
+            None | Some(DebugInfo::Level(0)) => false,
+            Some(DebugInfo::Level(_)) => true,
+            Some(DebugInfo::Named(name)) => name != "none",
+        }
+    }
+}
+
+/// Cargo serializes debuginfo levels as numbers, except for the named levels
+/// like `line-tables-only` which have no numeric equivalent.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum DebugInfo {
+    Level(u32),
+    Named(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_graph(profile: &str) -> UnitGraph {
+        let json = format!(
+            r#"{{
+                "version": 1,
+                "units": [{{
+                    "target": {{
+                        "name": "ctru_sys",
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "required-features": [],
+                        "src_path": "/ctru-sys/src/lib.rs",
+                        "edition": "2021",
+                        "doctest": true,
+                        "test": true,
+                        "doc": true
+                    }},
+                    "profile": {profile}
+                }}],
+                "roots": [0]
+            }}"#
+        );
+
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn graph_error_display() {
+        let source = serde_json::from_str::<serde_json::Value>("").unwrap_err();
+        let err = GraphError::Parse {
+            source,
+            stderr: String::from("error: the `-Z` flag is only accepted on the nightly channel\n"),
+        };
+        assert!(err.source().is_some());
+        assert!(err
+            .to_string()
+            .ends_with("\nstderr: `error: the `-Z` flag is only accepted on the nightly channel`"));
+
+        let err = GraphError::UnsupportedVersion(2);
+        assert_eq!(
+            err.to_string(),
+            "unknown `cargo --unit-graph` output version 2"
+        );
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn custom_profile_debuginfo() {
+        for (profile, expected) in [
+            (r#"{ "name": "dev", "debuginfo": 2 }"#, true),
+            (r#"{ "name": "release", "debuginfo": 0 }"#, false),
+            (r#"{ "name": "release", "debuginfo": null }"#, false),
+            // e.g. `[profile.foo]` with `inherits = "release"` and `debug = true`
+            (r#"{ "name": "foo", "debuginfo": 2 }"#, true),
+            (
+                r#"{ "name": "foo", "debuginfo": "line-tables-only" }"#,
+                true,
+            ),
+        ] {
+            let graph = unit_graph(profile);
+            let unit = graph.ctru_sys_unit().unwrap();
+            assert_eq!(unit.profile.has_debuginfo(), expected, "{profile}");
+        }
+    }
 }
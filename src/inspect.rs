@@ -0,0 +1,173 @@
+use std::fs;
+
+use camino::Utf8Path;
+use serde::Serialize;
+
+use crate::smdh::{self, Smdh};
+
+/// The magic number at the start of every 3DSX file.
+pub(crate) const MAGIC_3DSX: &[u8] = b"3DSX";
+
+/// The size of a 3DSX header with the extended header, which holds the offsets
+/// of the embedded SMDH and romfs. See <https://www.3dbrew.org/wiki/3DSX_Format>.
+pub(crate) const EXTENDED_HEADER_SIZE: usize = 0x2C;
+
+/// The metadata of an existing 3DSX file, as shown by `cargo 3ds inspect`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct Info3dsx {
+    code_size: u32,
+    rodata_size: u32,
+    data_size: u32,
+    bss_size: u32,
+    /// The embedded SMDH, if any.
+    smdh: Option<Smdh>,
+    /// The size of the embedded romfs in bytes, if any.
+    romfs_size: Option<u64>,
+}
+
+impl Info3dsx {
+    /// Parse the contents of a 3DSX file.
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(MAGIC_3DSX) {
+            return Err(String::from("missing 3DSX magic number"));
+        }
+
+        let u32_at = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(|| String::from("3DSX header is truncated"))
+        };
+
+        let header_size = data
+            .get(4..6)
+            .map(|bytes| usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+            .ok_or_else(|| String::from("3DSX header is truncated"))?;
+
+        let mut info = Self {
+            code_size: u32_at(0x10)?,
+            rodata_size: u32_at(0x14)?,
+            data_size: u32_at(0x18)?,
+            bss_size: u32_at(0x1C)?,
+            smdh: None,
+            romfs_size: None,
+        };
+
+        // Without the extended header, there is no SMDH or romfs
+        if header_size < EXTENDED_HEADER_SIZE {
+            return Ok(info);
+        }
+
+        let smdh_offset = u32_at(0x20)? as usize;
+        let smdh_size = u32_at(0x24)? as usize;
+        let romfs_offset = u32_at(0x28)? as usize;
+
+        if smdh_offset != 0 && smdh_size != 0 {
+            let smdh = data
+                .get(smdh_offset..smdh_offset + smdh_size)
+                .ok_or_else(|| String::from("embedded SMDH is truncated"))?;
+            info.smdh = Some(Smdh::parse(smdh).map_err(|err| format!("embedded {err}"))?);
+        }
+
+        // The romfs always extends to the end of the file
+        if romfs_offset != 0 {
+            let romfs_size = data
+                .len()
+                .checked_sub(romfs_offset)
+                .ok_or_else(|| String::from("embedded romfs is truncated"))?;
+            info.romfs_size = Some(romfs_size as u64);
+        }
+
+        Ok(info)
+    }
+}
+
+/// Print the metadata of the `.3dsx` or `.smdh` file at `path`, as human-readable
+/// text or as JSON. The kind of file is detected from its contents.
+pub(crate) fn inspect(path: &Utf8Path, json: bool) -> Result<(), String> {
+    let data = fs::read(path).map_err(|err| format!("unable to read {path}: {err}"))?;
+
+    if data.starts_with(smdh::MAGIC) {
+        let smdh = Smdh::parse(&data).map_err(|err| format!("invalid SMDH {path}: {err}"))?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&smdh).unwrap());
+        } else {
+            print_smdh(&smdh);
+        }
+    } else if data.starts_with(MAGIC_3DSX) {
+        let info = Info3dsx::parse(&data).map_err(|err| format!("invalid 3DSX {path}: {err}"))?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&info).unwrap());
+        } else {
+            print_3dsx(&info);
+        }
+    } else {
+        return Err(format!("{path} is neither a 3DSX nor an SMDH file"));
+    }
+
+    Ok(())
+}
+
+fn print_smdh(smdh: &Smdh) {
+    println!("Title:        {}", smdh.title);
+    println!("Description:  {}", smdh.description);
+    println!("Publisher:    {}", smdh.publisher);
+    println!("SMDH version: {}", smdh.version);
+    println!("Regions:      {}", list_or_none(&smdh.regions));
+    println!("Flags:        {}", list_or_none(&smdh.flags));
+    println!(
+        "Icon:         {}",
+        if smdh.has_icon { "present" } else { "empty" }
+    );
+}
+
+fn print_3dsx(info: &Info3dsx) {
+    println!("Code:         {} bytes", info.code_size);
+    println!("Rodata:       {} bytes", info.rodata_size);
+    println!("Data:         {} bytes", info.data_size);
+    println!("BSS:          {} bytes", info.bss_size);
+    match info.romfs_size {
+        Some(size) => println!("RomFS:        {size} bytes"),
+        None => println!("RomFS:        none"),
+    }
+    match &info.smdh {
+        Some(smdh) => print_smdh(smdh),
+        None => println!("SMDH:         none"),
+    }
+}
+
+fn list_or_none(items: &[&str]) -> String {
+    if items.is_empty() {
+        String::from("none")
+    } else {
+        items.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_3dsx() {
+        let mut data = vec![0; EXTENDED_HEADER_SIZE];
+        data[..4].copy_from_slice(MAGIC_3DSX);
+        data[4..6].copy_from_slice(&(EXTENDED_HEADER_SIZE as u16).to_le_bytes());
+        data[0x10..0x14].copy_from_slice(&0x1000u32.to_le_bytes());
+
+        // No SMDH, and a 16 byte romfs at the end
+        data[0x28..0x2C].copy_from_slice(&(EXTENDED_HEADER_SIZE as u32).to_le_bytes());
+        data.extend([0; 16]);
+
+        let info = Info3dsx::parse(&data).unwrap();
+        assert_eq!(info.code_size, 0x1000);
+        assert_eq!(info.smdh, None);
+        assert_eq!(info.romfs_size, Some(16));
+
+        // A basic header without the extended header
+        data[4..6].copy_from_slice(&0x20u16.to_le_bytes());
+        assert_eq!(Info3dsx::parse(&data).unwrap().romfs_size, None);
+
+        assert!(Info3dsx::parse(&data[..0x12]).is_err());
+        assert!(Info3dsx::parse(b"SMDH").is_err());
+    }
+}
@@ -1,21 +1,49 @@
+#[macro_use]
+pub mod log;
+
+mod builder;
+mod bundle;
 pub mod command;
+mod deploy;
+mod device;
 mod graph;
+mod inspect;
+mod remote;
+mod romfs;
+mod script;
+mod smdh;
+mod user_config;
 
-use std::ffi::OsStr;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use std::process::{Command, ExitStatus, Stdio};
-use std::{env, fmt, io, process};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use std::{env, fmt, io, process, thread};
 
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine as _;
 use camino::{Utf8Path, Utf8PathBuf};
-use cargo_metadata::{Artifact, Message, Package};
+use cargo_metadata::{Artifact, Message, Metadata, MetadataCommand, Package};
+use cargo_platform::{Cfg, Platform};
 use rustc_version::Channel;
 use semver::Version;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tee::TeeReader;
 
-use crate::command::{CargoCmd, Input, Run, Test};
+pub use crate::builder::CTRConfigBuilder;
+use crate::command::{absolute_path, CargoCmd, Input, Run, Test};
 use crate::graph::UnitGraph;
+use crate::remote::RemoteCache;
+use crate::romfs::RomfsManifest;
+use crate::smdh::SmdhFlags;
+use crate::user_config::UserConfig;
 
 /// Build a command using [`make_cargo_build_command`] and execute it,
 /// parsing and returning the messages from the spawned process.
@@ -23,31 +51,20 @@ use crate::graph::UnitGraph;
 /// For commands that produce an executable output, this function will build the
 /// `.elf` binary that can be used to create other 3ds files.
 pub fn run_cargo(input: &Input, message_format: Option<String>) -> (ExitStatus, Vec<Message>) {
-    let mut command = make_cargo_command(input, &message_format);
-
     // The unit graph is needed only when compiling a program.
-    if input.cmd.should_compile() {
-        let libctru = if should_use_ctru_debuginfo(&command, input.verbose) {
+    let libctru = (input.cmd.should_link() && !input.host).then(|| {
+        let command = make_cargo_command(input, &message_format, None);
+        if should_use_ctru_debuginfo(&command) {
             "ctrud"
         } else {
             "ctru"
-        };
-
-        let rustflags = command
-            .get_envs()
-            .find(|(var, _)| var == &OsStr::new("RUSTFLAGS"))
-            .and_then(|(_, flags)| flags)
-            .unwrap_or_default()
-            .to_string_lossy();
-
-        let rustflags = format!("{rustflags} -l{libctru}");
+        }
+    });
 
-        command.env("RUSTFLAGS", rustflags);
-    }
+    let mut command = make_cargo_command(input, &message_format, libctru);
 
-    if input.verbose {
-        print_command(&command);
-    }
+    print_command(&command);
+    script::record(&command);
 
     let mut process = command.spawn().unwrap();
     let command_stdout = process.stdout.take().unwrap();
@@ -63,7 +80,9 @@ pub fn run_cargo(input: &Input, message_format: Option<String>) -> (ExitStatus,
         // Rustdoc unfortunately prints to stdout for compile errors, so
         // we also use a tee when building doc tests too.
         // Possibly related: https://github.com/rust-lang/rust/issues/75135
-        (None, CargoCmd::Test(Test { doc: true, .. })) => {
+        (None, CargoCmd::Test(Test { doc: true, .. })) |
+        // `cargo expand` prints the expanded code to stdout.
+        (None, CargoCmd::Expand(_)) => {
             tee_reader = BufReader::new(TeeReader::new(command_stdout, io::stdout()));
             &mut tee_reader
         }
@@ -73,7 +92,15 @@ pub fn run_cargo(input: &Input, message_format: Option<String>) -> (ExitStatus,
         }
     };
 
-    let messages = Message::parse_stream(buf_reader)
+    // The raw messages are echoed with `-vv`, before they are parsed
+    let messages = buf_reader
+        .lines()
+        .map(|line| {
+            let line = line?;
+            trace!("{line}");
+            let message = Message::parse_stream(line.as_bytes()).next().transpose()?;
+            Ok(message.unwrap_or(Message::TextLine(line)))
+        })
         .collect::<io::Result<_>>()
         .unwrap();
 
@@ -81,23 +108,27 @@ pub fn run_cargo(input: &Input, message_format: Option<String>) -> (ExitStatus,
 }
 
 /// Ensure that we use the same `-lctru[d]` flag that `ctru-sys` is using in its build.
-fn should_use_ctru_debuginfo(cargo_cmd: &Command, verbose: bool) -> bool {
-    match UnitGraph::from_cargo(cargo_cmd, verbose) {
+///
+/// The unit graph is queried with the same arguments as the real build, so
+/// `--release` or `--profile <name>` (including custom profiles) are taken into account.
+fn should_use_ctru_debuginfo(cargo_cmd: &Command) -> bool {
+    match UnitGraph::from_cargo(cargo_cmd) {
         Ok(unit_graph) => {
-            let Some(unit) = unit_graph
-                .units
-                .iter()
-                .find(|unit| unit.target.name == "ctru_sys")
-            else {
-                eprintln!("Warning: unable to check if `ctru` debuginfo should be linked: `ctru-sys` not found");
+            let Some(unit) = unit_graph.ctru_sys_unit() else {
+                warn!("unable to check if `ctru` debuginfo should be linked: `ctru-sys` not found");
                 return false;
             };
 
-            let debuginfo = unit.profile.debuginfo.unwrap_or(0);
-            debuginfo > 0
+            let debuginfo = unit.profile.has_debuginfo();
+            debug!(
+                "`ctru-sys` is built with profile `{}` {} debuginfo",
+                unit.profile.name,
+                if debuginfo { "with" } else { "without" }
+            );
+            debuginfo
         }
         Err(err) => {
-            eprintln!("Warning: unable to check if `ctru` debuginfo should be linked: {err}");
+            warn!("unable to check if `ctru` debuginfo should be linked: {err}");
             false
         }
     }
@@ -107,63 +138,117 @@ fn should_use_ctru_debuginfo(cargo_cmd: &Command, verbose: bool) -> bool {
 ///
 /// For "build" commands (which compile code, such as `cargo 3ds build` or `cargo 3ds clippy`),
 /// if there is no pre-built std detected in the sysroot, `build-std` will be used instead.
-pub(crate) fn make_cargo_command(input: &Input, message_format: &Option<String>) -> Command {
-    let devkitpro =
-        env::var("DEVKITPRO").expect("DEVKITPRO is not defined as an environment variable");
-    // TODO: should we actually prepend the user's RUSTFLAGS for linking order? not sure
-    let rustflags =
-        env::var("RUSTFLAGS").unwrap_or_default() + &format!(" -L{devkitpro}/libctru/lib");
-
+///
+/// The flags to link `libctru` and select the `--target-cpu` only apply to the
+/// 3DS target, so they don't leak into build scripts or proc macros built for
+/// the host. With `--host`, neither they nor `--target` are added at all.
+pub(crate) fn make_cargo_command(
+    input: &Input,
+    message_format: &Option<String>,
+    libctru: Option<&str>,
+) -> Command {
     let cargo_cmd = &input.cmd;
 
     let mut command = cargo(&input.config);
-    command
-        .arg(cargo_cmd.subcommand_name())
-        .env("RUSTFLAGS", rustflags);
+
+    // With `--host`, the command runs without any of the 3DS environment
+    if !input.host {
+        add_target_rustflags(&mut command, input, libctru);
+    }
+
+    command.arg(cargo_cmd.subcommand_name());
+
+    // A `--manifest-path` passed through already reaches cargo with the other args
+    if let Some(manifest_path) = &input.manifest_path {
+        command.arg("--manifest-path").arg(manifest_path);
+    }
+
+    if let Some(color) = &input.color {
+        command.arg("--color").arg(color);
+    }
 
     // Any command that needs to compile code will run under this environment.
-    // Even `clippy` and `check` need this kind of context, so we'll just assume any other `Passthrough` command uses it too.
+    // Even `clippy` and `check` need this kind of context, so we'll just assume any other `Passthrough` command uses it too,
+    // unless it's known not to compile anything (e.g. `fmt` or `tree`).
     if cargo_cmd.should_compile() {
-        command
-            .arg("--target")
-            .arg("armv6k-nintendo-3ds")
-            .arg("--message-format")
-            .arg(
-                message_format
-                    .as_deref()
-                    .unwrap_or(CargoCmd::DEFAULT_MESSAGE_FORMAT),
-            );
+        // A `--target` passed through is only allowed if it's the 3DS target,
+        // in which case it isn't passed to cargo a second time.
+        if !input.host {
+            let passed_target = cargo_cmd.passed_target().unwrap_or_else(|err| {
+                error!("{err}");
+                process::exit(1);
+            });
+            if !passed_target {
+                command.arg("--target").arg(CargoCmd::TARGET);
+            }
+        }
+
+        if cargo_cmd.uses_message_format() {
+            let message_format = match message_format {
+                Some(format) => format.clone(),
+                None => CargoCmd::default_message_format().unwrap_or_else(|err| {
+                    error!("{err}");
+                    process::exit(1);
+                }),
+            };
+            command.arg("--message-format").arg(message_format);
+        }
+
+        if input.host {
+            // The host's own std is used
+        } else if has_prebuilt_std(&find_sysroot(input.sysroot.as_deref())) {
+            debug!("Using the prebuilt std from the sysroot");
+        } else if build_std_configured(&input.config) {
+            // The user's own `[unstable] build-std` takes precedence over ours
+            debug!("Using build-std as configured by `unstable.build-std` in the cargo config");
+        } else {
+            // The test crate is only built when tests or benchmarks may be, since
+            // building it for every command would slow down the first build a lot.
+            let build_std = if cargo_cmd.may_build_tests() {
+                "build-std=std,test"
+            } else {
+                "build-std=std"
+            };
 
-        let sysroot = find_sysroot();
-        if !sysroot.join("lib/rustlib/armv6k-nintendo-3ds").exists() {
             // Under most circumstances, the user will just use build-std for convenience.
             // As such, we warn about the use of build-std only if really asked for.
-            if input.verbose {
-                eprintln!("No pre-build std found, using build-std");
-            }
-
-            // Always building the test crate is not ideal, but we don't know if the
-            // crate being built uses #![feature(test)], so we build it just in case.
-            command.arg("-Z").arg("build-std=std,test");
+            debug!("No prebuilt std found, using -Z {build_std}");
+            command.arg("-Z").arg(build_std);
         }
     }
 
+    // Build scripts and proc macros commonly embed the current time unless
+    // `SOURCE_DATE_EPOCH` is set, which cargo passes on if the user set it already.
+    if cargo_cmd.reproducible() && env::var_os("SOURCE_DATE_EPOCH").is_none() {
+        let manifest_path = input.manifest_path();
+        let package_dir = manifest_path
+            .as_deref()
+            .and_then(Utf8Path::parent)
+            .filter(|dir| !dir.as_str().is_empty());
+        command.env("SOURCE_DATE_EPOCH", source_date_epoch(package_dir));
+    }
+
     if let CargoCmd::Test(test) = cargo_cmd {
         // RUSTDOCFLAGS is simply ignored if --doc wasn't passed, so we always set it.
         let rustdoc_flags = std::env::var("RUSTDOCFLAGS").unwrap_or_default() + test.rustdocflags();
         command.env("RUSTDOCFLAGS", rustdoc_flags);
     }
 
-    command.args(cargo_cmd.cargo_args());
-
-    if let CargoCmd::Run(run) | CargoCmd::Test(Test { run_args: run, .. }) = &cargo_cmd {
-        if run.use_custom_runner() {
-            command
-                .arg("--")
-                .args(run.build_args.passthrough.exe_args());
-        }
+    // `--artifact-dir` is unstable, so the flag to use it is added if missing,
+    // since nightly is needed for the 3DS target anyway.
+    if cargo_cmd.artifact_dir().is_some() && !cargo_cmd.unstable_options() {
+        command.arg("-Z").arg("unstable-options");
     }
 
+    let custom_runner = match cargo_cmd {
+        CargoCmd::Run(run) | CargoCmd::Test(Test { run_args: run, .. }) => run.use_custom_runner(),
+        _ => false,
+    };
+    command.args(cargo_command_args(cargo_cmd, custom_runner));
+
+    // Only stdout is piped, to parse the JSON messages. cargo writes its status
+    // and progress bar to stderr, and decides whether to show the progress bar
+    // from stderr alone, so it must stay inherited for long builds to show progress.
     command
         .stdout(Stdio::piped())
         .stdin(Stdio::inherit())
@@ -172,6 +257,303 @@ pub(crate) fn make_cargo_command(input: &Input, message_format: &Option<String>)
     command
 }
 
+/// Add the rustflags for the 3DS target to `command`: the flags to link `libctru`
+/// (using `-l{libctru}`, if given) and select the `--target-cpu`. The libctru
+/// given with `--libctru-path` in `input` takes precedence over [`libctru_dir`].
+///
+/// `ctru-sys`'s build script also adds `$DEVKITPRO/libctru/lib` to the search
+/// path, and the linker's search order between that and our `-L` isn't
+/// guaranteed, so a custom libctru may be shadowed by `$DEVKITPRO/libctru`.
+fn add_target_rustflags(command: &mut Command, input: &Input, libctru: Option<&str>) {
+    let libctru_lib = input.libctru_path().unwrap_or_else(libctru_dir).join("lib");
+    let mut target_flags = vec![format!("-L{libctru_lib}")];
+    target_flags.extend(libctru.map(|lib| format!("-l{lib}")));
+    target_flags.extend(
+        input
+            .target_cpu
+            .as_ref()
+            .map(|cpu| format!("-Ctarget-cpu={cpu}")),
+    );
+
+    // If set, RUSTFLAGS would take precedence over any rustflags from the cargo
+    // config, so we have to add our flags to it instead. With `--target`, cargo
+    // doesn't pass RUSTFLAGS to host artifacts anyway.
+    //
+    // The flags are always added after the user's in the same order, rather than
+    // sorted, since the order of `-L`, `-l` and `-C` flags matters. This keeps the
+    // flags identical between builds, so a `RUSTC_WRAPPER` such as `sccache`
+    // (which is left untouched) gets stable cache keys.
+    // TODO: should we actually prepend the user's RUSTFLAGS for linking order? not sure
+    if let Ok(rustflags) = env::var("CARGO_ENCODED_RUSTFLAGS") {
+        let mut flags: Vec<_> = rustflags
+            .split('\x1f')
+            .filter(|flag| !flag.is_empty())
+            .map(String::from)
+            .collect();
+        flags.extend(target_flags);
+        command.env("CARGO_ENCODED_RUSTFLAGS", flags.join("\x1f"));
+    } else if let Ok(rustflags) = env::var("RUSTFLAGS") {
+        command.env(
+            "RUSTFLAGS",
+            format!("{rustflags} {}", target_flags.join(" ")),
+        );
+    } else {
+        // Cargo merges our `--config` with any target rustflags from the cargo
+        // config, but ignores `build.rustflags` entirely once target rustflags are
+        // set, so those have to be added to ours (unless the cargo config has
+        // target rustflags of its own, in which case cargo ignored them anyway).
+        let mut flags = config_build_rustflags(&input.config).to_vec();
+        flags.extend(target_flags);
+
+        let flags = toml::Value::Array(flags.into_iter().map(Into::into).collect());
+        command.arg(format!(
+            "--config=target.armv6k-nintendo-3ds.rustflags={flags}"
+        ));
+    }
+}
+
+/// Get the `build.rustflags` from the cargo config, which would apply to the 3DS
+/// target if `cargo-3ds` didn't set its own target rustflags. They are empty if
+/// target rustflags for the 3DS are also set in the config, see
+/// [`has_target_rustflags`], since cargo ignores `build.rustflags` then anyway.
+fn config_build_rustflags(config: &[String]) -> &'static [String] {
+    static RUSTFLAGS: OnceLock<Vec<String>> = OnceLock::new();
+
+    RUSTFLAGS.get_or_init(|| {
+        let targets = cargo_config_get(config, "target");
+        if let Some(toml::Value::Table(targets)) = &targets {
+            if has_target_rustflags(targets, &target_cfgs()) {
+                return Vec::new();
+            }
+        }
+
+        let rustflags = cargo_config_get(config, "build.rustflags")
+            .map(|value| parse_config_rustflags(&value))
+            .unwrap_or_default();
+        if !rustflags.is_empty() {
+            debug!("Adding build.rustflags from the cargo config: {rustflags:?}");
+        }
+        rustflags
+    })
+}
+
+/// Whether any of the `[target]` tables of the cargo config which apply to the 3DS
+/// target set rustflags: either `[target.armv6k-nintendo-3ds]`, or a
+/// `[target.'cfg(...)']` matching the `cfgs` of the 3DS target.
+fn has_target_rustflags(targets: &toml::Table, cfgs: &[Cfg]) -> bool {
+    targets.iter().any(|(key, table)| {
+        table.get("rustflags").is_some()
+            && key
+                .parse::<Platform>()
+                .is_ok_and(|platform| platform.matches(CargoCmd::TARGET, cfgs))
+    })
+}
+
+/// Get the cfgs of the 3DS target from `rustc --print cfg`, e.g. `target_os="horizon"`.
+/// They are empty if `rustc` can't be run, so that only `[target.armv6k-nintendo-3ds]`
+/// is known to apply.
+fn target_cfgs() -> Vec<Cfg> {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let mut command = Command::new(rustc);
+    command.args(["--print", "cfg", "--target", CargoCmd::TARGET]);
+
+    print_command(&command);
+
+    match command.output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.parse().ok())
+            .collect(),
+        _ => {
+            warn!("unable to get the cfgs of the 3DS target from rustc");
+            Vec::new()
+        }
+    }
+}
+
+/// Parse rustflags from the cargo config, which may be either an array of flags
+/// or a single string of flags separated by whitespace.
+fn parse_config_rustflags(value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::String(flags) => flags.split_whitespace().map(String::from).collect(),
+        toml::Value::Array(flags) => flags
+            .iter()
+            .filter_map(|flag| flag.as_str().map(String::from))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Get the arguments to pass to the cargo subcommand of `cargo_cmd`. With a
+/// `custom_runner`, the executable's arguments are passed through to it after a
+/// single `--`, the same as with `cargo run -- <args>`.
+fn cargo_command_args(cargo_cmd: &CargoCmd, custom_runner: bool) -> Vec<String> {
+    let mut args = cargo_cmd.cargo_args();
+
+    let passed_args = match cargo_cmd {
+        CargoCmd::Run(run) | CargoCmd::Test(Test { run_args: run, .. }) if custom_runner => {
+            run.exe_args()
+        }
+        // Unlike an executable's arguments, the `rustc` flags are always passed through `cargo`
+        CargoCmd::Rustc(rustc) => rustc.passthrough.exe_args(),
+        _ => Vec::new(),
+    };
+    if !passed_args.is_empty() {
+        args.push(String::from("--"));
+        args.extend(passed_args);
+    }
+
+    args
+}
+
+/// Get the devkitPro install directory from `$DEVKITPRO`, exiting with
+/// [`EXIT_ENVIRONMENT`] if it isn't set.
+fn devkitpro() -> String {
+    env::var("DEVKITPRO").unwrap_or_else(|_| {
+        error!(
+            "DEVKITPRO is not defined as an environment variable\n\
+            See https://devkitpro.org/wiki/Getting_Started to install devkitPro"
+        );
+        process::exit(EXIT_ENVIRONMENT);
+    })
+}
+
+/// The libctru directory given with `--libctru-path`, if any.
+static LIBCTRU_PATH: OnceLock<Utf8PathBuf> = OnceLock::new();
+
+/// Use the libctru installed in `path` rather than the one in `$DEVKITPRO`,
+/// for `--libctru-path`. This takes precedence over [`LIBCTRU_ENV`].
+pub fn set_libctru_path(path: Utf8PathBuf) {
+    if LIBCTRU_PATH.set(path).is_err() {
+        warn!("the libctru path was already set");
+    }
+}
+
+/// Get the libctru install directory, with its `lib` directory and
+/// `default_icon.png`: the one given with `--libctru-path` or [`LIBCTRU_ENV`],
+/// or else `$DEVKITPRO/libctru`.
+fn libctru_dir() -> Utf8PathBuf {
+    libctru_override(LIBCTRU_PATH.get(), env::var(LIBCTRU_ENV).ok())
+        .unwrap_or_else(|| Utf8PathBuf::from(devkitpro()).join("libctru"))
+}
+
+/// Get the libctru directory set with the `--libctru-path` `flag` or the
+/// [`LIBCTRU_ENV`] environment variable's `value`, if either is set. A relative
+/// `value` is resolved from the current directory, like the flag, since the
+/// `-L` flag is passed to rustc running in another directory.
+fn libctru_override(flag: Option<&Utf8PathBuf>, value: Option<String>) -> Option<Utf8PathBuf> {
+    flag.cloned().or_else(|| {
+        value
+            .filter(|value| !value.is_empty())
+            .map(|value| absolute_path(Utf8Path::new(&value)))
+    })
+}
+
+/// The `Cargo.toml` of the project given with `--manifest-path`, if any.
+static MANIFEST_PATH: OnceLock<Utf8PathBuf> = OnceLock::new();
+
+/// Use the project at `manifest_path` rather than the one in the current
+/// directory, for `--manifest-path`. This must be set before [`cargo_metadata`]
+/// is first called.
+pub fn set_manifest_path(manifest_path: Utf8PathBuf) {
+    if MANIFEST_PATH.set(manifest_path).is_err() {
+        warn!("the manifest path was already set");
+    }
+}
+
+/// Create the `cargo metadata` command for the project at `manifest_path`, or
+/// in the current directory.
+fn metadata_command(manifest_path: Option<&Utf8Path>) -> MetadataCommand {
+    // Dependencies are included, since they may contribute files to the romfs
+    let mut command = MetadataCommand::new();
+    if let Some(manifest_path) = manifest_path {
+        command.manifest_path(manifest_path);
+    }
+    command
+}
+
+/// Get the `cargo metadata` for the current project, including its dependencies.
+/// It is only gathered once, and shared by everything that needs it, so that
+/// every part of `cargo-3ds` sees the same metadata.
+pub fn cargo_metadata() -> Option<&'static Metadata> {
+    static METADATA: OnceLock<Option<Metadata>> = OnceLock::new();
+
+    METADATA
+        .get_or_init(|| {
+            let command = metadata_command(MANIFEST_PATH.get().map(Utf8PathBuf::as_path));
+            print_command(&command.cargo_command());
+
+            let result = exec_metadata(&command).or_else(|err| {
+                if !is_transient_metadata_error(&err) {
+                    return Err(err);
+                }
+
+                warn!("`cargo metadata` failed, retrying: {err}");
+                thread::sleep(METADATA_RETRY_DELAY);
+                exec_metadata(&command)
+            });
+
+            match result {
+                Ok(metadata) => Some(metadata),
+                Err(err) => {
+                    warn!(
+                        "failed to gather cargo metadata for the project: {err}\n\
+                        `package.metadata.cargo-3ds` will not be applied, and no 3dsx will be built"
+                    );
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Run `command` the same as [`MetadataCommand::exec`], but giving up after the
+/// [`probe_timeout`] rather than waiting forever for a stalled `cargo`.
+fn exec_metadata(command: &MetadataCommand) -> Result<Metadata, cargo_metadata::Error> {
+    let output = output_with_timeout(&mut command.cargo_command(), probe_timeout())?;
+    if !output.status.success() {
+        return Err(cargo_metadata::Error::CargoMetadata {
+            stderr: String::from_utf8(output.stderr)?,
+        });
+    }
+
+    let json = std::str::from_utf8(&output.stdout)?
+        .lines()
+        .find(|line| line.starts_with('{'))
+        .ok_or(cargo_metadata::Error::NoJson)?;
+    MetadataCommand::parse(json)
+}
+
+/// How long to wait before retrying `cargo metadata` after a transient failure.
+const METADATA_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether a `cargo metadata` failure may succeed if retried, e.g. because of
+/// a network error while updating the registry or contention on a file lock.
+fn is_transient_metadata_error(err: &cargo_metadata::Error) -> bool {
+    const TRANSIENT_ERRORS: &[&str] = &[
+        "spurious network error",
+        "failed to download",
+        "failed to fetch",
+        "failed to update",
+        "timed out",
+        "failed to acquire",
+        "Resource temporarily unavailable",
+    ];
+
+    match err {
+        cargo_metadata::Error::CargoMetadata { stderr } => TRANSIENT_ERRORS
+            .iter()
+            .any(|transient| stderr.contains(transient)),
+        // A `TimedOut` error is from the `probe_timeout`, and retrying would only
+        // make the wait twice as long
+        cargo_metadata::Error::Io(err) => matches!(
+            err.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+        ),
+        _ => false,
+    }
+}
+
 /// Build a `cargo` command with the given `--config` flags.
 fn cargo(config: &[String]) -> Command {
     let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
@@ -180,27 +562,52 @@ fn cargo(config: &[String]) -> Command {
     cmd
 }
 
+/// Print a command and its environment at the debug log level.
 fn print_command(command: &Command) {
-    let mut cmd_str = vec![command.get_program().to_string_lossy().to_string()];
-    cmd_str.extend(command.get_args().map(|s| s.to_string_lossy().to_string()));
+    if !log::enabled(log::Level::Debug) {
+        return;
+    }
+
+    let (envs, cmd_str) = quote_command(command);
 
     eprintln!("Running command:");
-    for (k, v) in command.get_envs() {
-        let v = v.map(|v| v.to_string_lossy().to_string());
-        eprintln!(
-            "   {}={} \\",
-            k.to_string_lossy(),
-            v.map_or_else(String::new, |s| shlex::try_quote(&s).unwrap().to_string())
-        );
+    for env in envs {
+        eprintln!("   {env} \\");
     }
-    eprintln!(
-        "   {}\n",
-        shlex::try_join(cmd_str.iter().map(String::as_str)).unwrap()
-    );
+    eprintln!("   {cmd_str}\n");
+}
+
+/// Quote a command for a shell, as the `KEY=value` assignments of its environment
+/// and the command line itself.
+pub(crate) fn quote_command(command: &Command) -> (Vec<String>, String) {
+    let envs = command
+        .get_envs()
+        .map(|(k, v)| {
+            let v = v.map(|v| v.to_string_lossy().to_string());
+            format!(
+                "{}={}",
+                k.to_string_lossy(),
+                v.map_or_else(String::new, |s| shlex::try_quote(&s).unwrap().to_string())
+            )
+        })
+        .collect();
+
+    let mut cmd_str = vec![command.get_program().to_string_lossy().to_string()];
+    cmd_str.extend(command.get_args().map(|s| s.to_string_lossy().to_string()));
+
+    (
+        envs,
+        shlex::try_join(cmd_str.iter().map(String::as_str)).unwrap(),
+    )
 }
 
-/// Finds the sysroot path of the current toolchain
-pub(crate) fn find_sysroot() -> PathBuf {
+/// Finds the sysroot path of the current toolchain, unless overridden by `sysroot`
+/// (from `--sysroot`) or the `SYSROOT` environment variable, in that order.
+pub(crate) fn find_sysroot(sysroot: Option<&Path>) -> PathBuf {
+    if let Some(sysroot) = sysroot {
+        return sysroot.to_path_buf();
+    }
+
     let sysroot = env::var("SYSROOT").ok().unwrap_or_else(|| {
         let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
 
@@ -215,21 +622,126 @@ pub(crate) fn find_sysroot() -> PathBuf {
     PathBuf::from(sysroot.trim())
 }
 
+/// Whether `sysroot` contains a prebuilt standard library for the 3DS target.
+fn has_prebuilt_std(sysroot: &Path) -> bool {
+    sysroot.join("lib/rustlib/armv6k-nintendo-3ds").exists()
+}
+
+/// Whether `build-std` is already configured in the `[unstable]` table of the
+/// cargo config (or a `--config` override), in which case cargo-3ds doesn't
+/// pass its own `-Z build-std`. The result is cached for the whole run.
+fn build_std_configured(config: &[String]) -> bool {
+    static CONFIGURED: OnceLock<bool> = OnceLock::new();
+
+    *CONFIGURED.get_or_init(|| cargo_config_get(config, "unstable.build-std").is_some())
+}
+
+/// Get the value of the dotted `key` from the cargo config, if it is set.
+fn cargo_config_get(config: &[String], key: &str) -> Option<toml::Value> {
+    let mut cmd = cargo(config);
+    // https://github.com/rust-lang/cargo/issues/9301
+    cmd.args(["-Z", "unstable-options", "config", "get", key])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    print_command(&cmd);
+
+    // `cargo config get` exits nonzero if the config isn't set, and otherwise
+    // prints it as TOML, e.g. `build.rustflags = ["-Cdebuginfo=0"]`
+    let output = cmd.output().ok().filter(|output| output.status.success())?;
+    let table: toml::Table = toml::from_str(&String::from_utf8_lossy(&output.stdout)).ok()?;
+
+    let mut keys = key.split('.');
+    let first = table.get(keys.next()?)?;
+    keys.try_fold(first, |value, key| value.get(key)).cloned()
+}
+
+/// Print the sysroot used for the command on stdout for `--print-sysroot`, and
+/// whether a prebuilt standard library was found in it.
+pub fn print_sysroot(input: &Input) {
+    let sysroot = find_sysroot(input.sysroot.as_deref());
+    println!("{}", sysroot.display());
+
+    if has_prebuilt_std(&sysroot) {
+        info!("A prebuilt std for the 3DS target was found in the sysroot");
+    } else {
+        info!(
+            "No prebuilt std for the 3DS target was found in the sysroot, build-std will be used"
+        );
+    }
+}
+
+/// The exit code used when the environment or toolchain can't be used to build,
+/// e.g. an unsupported rustc, `DEVKITPRO` not being set, or a missing devkitPro tool.
+/// Exit code 2 is left to `clap`, which uses it for invalid arguments.
+pub const EXIT_ENVIRONMENT: i32 = 3;
+
+/// The exit code used when one of the devkitPro packaging tools (`smdhtool` or
+/// `3dsxtool`) fails.
+pub const EXIT_TOOL: i32 = 4;
+
+/// An error while packaging an executable, with the code `cargo-3ds` exits with
+/// for it: [`EXIT_ENVIRONMENT`] if a devkitPro tool is missing, [`EXIT_TOOL`] if
+/// it failed, and 1 otherwise. The library API only returns its message.
+#[derive(Debug)]
+pub(crate) struct PackageError {
+    message: String,
+    code: i32,
+}
+
+impl PackageError {
+    fn new(message: String, code: i32) -> Self {
+        Self { message, code }
+    }
+
+    /// Print the error and exit with its code.
+    pub(crate) fn exit(self) -> ! {
+        error!("{}", self.message);
+        process::exit(self.code);
+    }
+}
+
+impl From<String> for PackageError {
+    fn from(message: String) -> Self {
+        Self::new(message, 1)
+    }
+}
+
+impl From<PackageError> for String {
+    fn from(err: PackageError) -> Self {
+        err.message
+    }
+}
+
 /// Checks the current rust version and channel.
-/// Exits if the minimum requirement is not met.
+/// Exits with [`EXIT_ENVIRONMENT`] if the minimum requirement is not met.
 pub fn check_rust_version(input: &Input) {
     let rustc_version = rustc_version::version_meta().unwrap();
 
     // If the channel isn't nightly, we can't make use of the required unstable tools.
     // However, `cargo 3ds new` doesn't have these requirements.
     if rustc_version.channel > Channel::Nightly && input.cmd.should_compile() {
-        eprintln!("building with cargo-3ds requires a nightly rustc version.");
-        eprintln!(
-            "Please run `rustup override set nightly` to use nightly in the \
-            current directory, or use `cargo +nightly 3ds` to use it for a \
-            single invocation."
-        );
-        process::exit(1);
+        if allow_stable(input.allow_stable, env::var_os(SKIP_TOOLCHAIN_CHECK_ENV)) {
+            // cargo-3ds always passes `-Z` flags to cargo (e.g. `-Z build-std` and
+            // `--unit-graph`), which a non-nightly cargo only accepts with this set.
+            if env::var_os("RUSTC_BOOTSTRAP").is_none() {
+                env::set_var("RUSTC_BOOTSTRAP", "1");
+            }
+            warn!(
+                "building with a non-nightly rustc version, which cargo-3ds doesn't support.\n\
+                `RUSTC_BOOTSTRAP=1` is set to allow the unstable flags cargo-3ds needs, \
+                but the build may still fail."
+            );
+        } else {
+            error!(
+                "building with cargo-3ds requires a nightly rustc version.\n\
+                Please run `rustup override set nightly` to use nightly in the \
+                current directory, or use `cargo +nightly 3ds` to use it for a \
+                single invocation.\n\
+                To try building anyway, pass `--allow-stable` or set `{SKIP_TOOLCHAIN_CHECK_ENV}`."
+            );
+            process::exit(EXIT_ENVIRONMENT);
+        }
     }
 
     let old_version = MINIMUM_RUSTC_VERSION
@@ -239,273 +751,4071 @@ pub fn check_rust_version(input: &Input) {
             ..rustc_version.semver.clone()
         };
 
-    let old_commit = match rustc_version.commit_date {
-        None => false,
-        Some(date) => {
-            MINIMUM_COMMIT_DATE
-                > CommitDate::parse(&date).expect("could not parse `rustc --version` commit date")
-        }
-    };
+    let commit_date = rustc_version.commit_date.as_deref().map(|date| {
+        CommitDate::parse(date).expect("could not parse `rustc --version` commit date")
+    });
+    let old_commit = commit_date
+        .as_ref()
+        .is_some_and(|date| MINIMUM_COMMIT_DATE > *date);
 
     if old_version || old_commit {
-        eprintln!("cargo-3ds requires rustc nightly version >= {MINIMUM_COMMIT_DATE}");
-        eprintln!("Please run `rustup update nightly` to upgrade your nightly version");
+        error!(
+            "cargo-3ds requires rustc nightly version >= {MINIMUM_COMMIT_DATE}\n\
+            Please run `rustup update nightly` to upgrade your nightly version"
+        );
 
-        process::exit(1);
+        process::exit(EXIT_ENVIRONMENT);
+    }
+
+    // Only a hint, since most new nightlies work fine, but it helps when a
+    // regression in a nightly breaks `build-std` or the 3DS target.
+    let untested = commit_date
+        .is_some_and(|date| date > LAST_TESTED_COMMIT_DATE.months_later(UNTESTED_NIGHTLY_MONTHS));
+    if untested && input.cmd.should_compile() && env::var_os(ALLOW_UNTESTED_NIGHTLY_ENV).is_none() {
+        warn!(
+            "rustc nightly is much newer than {LAST_TESTED_COMMIT_DATE}, the newest version \
+            cargo-3ds was tested with. If the build fails unexpectedly, a regression in \
+            nightly may be the cause; try an older nightly, e.g. with `rustup override set \
+            nightly-{LAST_TESTED_COMMIT_DATE}`.\n\
+            Set `{ALLOW_UNTESTED_NIGHTLY_ENV}` to hide this warning"
+        );
     }
 }
 
-/// Parses messages returned by "build" cargo commands (such as `cargo 3ds build` or `cargo 3ds run`).
-/// The returned [`CTRConfig`] is then used for further building in and execution
-/// in [`CTRConfig::build_smdh`], [`build_3dsx`], and [`link`].
-pub(crate) fn get_artifact_config(package: Package, artifact: Artifact) -> CTRConfig {
-    // For now, assume a single "kind" per artifact. It seems to be the case
-    // when a single executable is built anyway but maybe not in all cases.
-    let name = match artifact.target.kind[0].as_ref() {
-        "bin" | "lib" | "rlib" | "dylib" if artifact.target.test => {
-            format!("{} tests", artifact.target.name)
-        }
-        "example" => {
-            format!("{} - {} example", artifact.target.name, package.name)
+/// Whether a non-nightly toolchain should only be warned about, with the
+/// `--allow-stable` flag or a non-empty `value` of [`SKIP_TOOLCHAIN_CHECK_ENV`].
+fn allow_stable(flag: bool, value: Option<OsString>) -> bool {
+    flag || value.is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+/// With `--tool-version-check`, warn about any devkitPro tool used by the command
+/// which is older than the minimum version in [`MINIMUM_TOOL_VERSIONS`], since
+/// older tools may not understand the arguments `cargo-3ds` passes to them.
+pub fn check_tool_versions(input: &Input) {
+    if !input.tool_version_check {
+        return;
+    }
+
+    let builds_3dsx = input.cmd.should_build_3dsx();
+    let uses_3dslink = input.cmd.may_use_3dslink();
+
+    for &(tool, ref minimum) in &MINIMUM_TOOL_VERSIONS {
+        let used = match tool {
+            "3dslink" => uses_3dslink,
+            _ => builds_3dsx,
+        };
+        if !used {
+            continue;
         }
-        _ => artifact.target.name,
-    };
 
-    // TODO(#62): need to break down by target kind and name, e.g.
-    // [package.metadata.cargo-3ds.example.hello-world]
-    // Probably fall back to top level as well.
-    let config = package
-        .metadata
-        .get("cargo-3ds")
-        .and_then(|c| CTRConfig::deserialize(c).ok())
-        .unwrap_or_default();
+        let mut command = Command::new(UserConfig::get().tool(tool));
+        command.arg("--version");
+        print_command(&command);
 
-    CTRConfig {
-        name,
-        authors: config.authors.or(Some(package.authors)),
-        description: config.description.or(package.description),
-        manifest_dir: package.manifest_path.parent().unwrap().into(),
-        target_path: artifact.executable.unwrap(),
-        ..config
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                warn!("{}", missing_tool_help(tool));
+                continue;
+            }
+            Err(err) => {
+                warn!("unable to check the version of `{tool}`: {err}");
+                continue;
+            }
+        };
+
+        // Some of the tools print their version along with their usage on stderr
+        let text =
+            String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+
+        match parse_tool_version(&text) {
+            Some(version) if version < *minimum => warn!(
+                "`{tool}` {version} is older than the minimum supported version {minimum}, \
+                and may fail with the arguments used by cargo-3ds\n\
+                Please run `dkp-pacman -Syu` to update the devkitPro tools"
+            ),
+            Some(version) => debug!("`{tool}` version {version} is supported"),
+            // Some releases don't know `--version` and only print their usage
+            None => warn!(
+                "`{tool}` doesn't report its version, so it can't be checked against the \
+                minimum supported version {minimum}\n\
+                If it fails with the arguments used by cargo-3ds, please run \
+                `dkp-pacman -Syu` to update the devkitPro tools"
+            ),
+        }
     }
 }
 
-/// Builds the 3dsx using `3dsxtool`.
-/// This will fail if `3dsxtool` is not within the running directory or in a directory found in $PATH
-pub(crate) fn build_3dsx(config: &CTRConfig, verbose: bool) {
-    let mut command = Command::new("3dsxtool");
-    command
-        .arg(&config.target_path)
-        .arg(config.path_3dsx())
-        .arg(format!("--smdh={}", config.path_smdh()));
+/// Find the first version number in the output of a devkitPro tool, e.g.
+/// `3dsxtool v1.3.1`. A missing patch version is treated as `0`.
+fn parse_tool_version(output: &str) -> Option<Version> {
+    output.split_whitespace().find_map(|word| {
+        let word = word
+            .trim_start_matches(['v', 'V'])
+            .trim_end_matches(|c: char| !c.is_ascii_digit());
 
-    let romfs = config.romfs_dir();
-    if romfs.is_dir() {
-        eprintln!("Adding RomFS from {romfs}");
-        command.arg(format!("--romfs={romfs}"));
-    } else if config.romfs_dir.is_some() {
-        eprintln!("Could not find configured RomFS dir: {romfs}");
-        process::exit(1);
-    }
+        let mut parts = word.split('.').map(|part| part.parse::<u64>().ok());
+        let major = parts.next()??;
+        let minor = parts.next()??;
+        let patch = parts.next().unwrap_or(Some(0))?;
+        if parts.next().is_some() {
+            return None;
+        }
 
-    if verbose {
-        print_command(&command);
-    }
+        Some(Version::new(major, minor, patch))
+    })
+}
 
-    let mut process = command
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .expect("3dsxtool command failed, most likely due to '3dsxtool' not being in $PATH");
+/// Get the title of the application built from `artifact`, which is used in its SMDH.
+fn artifact_title(package_name: &str, artifact: &Artifact) -> String {
+    // For now, assume a single "kind" per artifact. It seems to be the case
+    // when a single executable is built anyway but maybe not in all cases.
+    //
+    // `target.test` only says whether the target is tested by default, whereas
+    // the profile says whether this artifact is actually a test harness.
+    target_title(
+        package_name,
+        &artifact.target.kind[0],
+        &artifact.target.name,
+        artifact.profile.test,
+    )
+}
 
-    let status = process.wait().unwrap();
+/// Get the title of the application built from the target `name` of the given
+/// `kind`, as a test harness if `harness` is set. Each kind of target gets a
+/// distinct title, so e.g. a binary and an example with the same name can be
+/// told apart with `--all-targets`.
+fn target_title(package_name: &str, kind: &str, name: &str, harness: bool) -> String {
+    let title = match kind {
+        "example" => format!("{name} - {package_name} example"),
+        "bench" => format!("{name} bench"),
+        _ => name.to_string(),
+    };
 
-    if !status.success() {
-        process::exit(status.code().unwrap_or(1));
+    // Integration tests and benches are only ever built as harnesses
+    if harness && !matches!(kind, "test" | "bench") {
+        format!("{title} tests")
+    } else {
+        title
     }
 }
 
-/// Link the generated 3dsx to a 3ds to execute and test using `3dslink`.
-/// This will fail if `3dslink` is not within the running directory or in a directory found in $PATH
-pub(crate) fn link(config: &CTRConfig, run_args: &Run, verbose: bool) {
-    let mut command = Command::new("3dslink");
-    command
-        .arg(config.path_3dsx())
-        .args(run_args.get_3dslink_args())
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit());
+/// Parses messages returned by "build" cargo commands (such as `cargo 3ds build` or `cargo 3ds run`).
+/// The returned [`CTRConfig`] is then used for further building in and execution
+/// in [`CTRConfig::build_smdh`], [`build_3dsx`], and [`link`].
+///
+/// If `profile` is set, the matching `[package.metadata.cargo-3ds.profile.<name>]`
+/// table takes precedence over the rest of the package metadata, and the settings
+/// of `config_file` (from `--config-file`) take precedence over all of it.
+///
+/// Artifacts built without debug assertions (e.g. with `--release`) use the
+/// `[package.metadata.cargo-3ds.release]` overrides, and others use
+/// `[package.metadata.cargo-3ds.debug]`.
+pub(crate) fn get_artifact_config(
+    package: Package,
+    artifact: Artifact,
+    workspace_root: &Utf8Path,
+    profile: Option<&str>,
+    config_file: Option<&Utf8Path>,
+) -> CTRConfig {
+    let name = artifact_title(&package.name, &artifact);
 
-    if verbose {
-        print_command(&command);
+    let metadata = PackageMetadata::of(&package).with_config_file(config_file);
+
+    let build_type = BuildType::of(&artifact);
+    let config = CTRConfig {
+        workspace_root: Some(workspace_root.to_path_buf()),
+        ..metadata.target_config(
+            &artifact.target.kind[0],
+            &artifact.target.name,
+            build_type,
+            profile,
+        )
+    };
+
+    if let Err(err) = config.validate() {
+        error!("{err}");
+        process::exit(1);
     }
 
-    let status = command.spawn().unwrap().wait().unwrap();
+    CTRConfig {
+        name,
+        target_path: artifact.executable.unwrap(),
+        ..config.with_package_defaults(&package)
+    }
+}
 
-    if !status.success() {
-        process::exit(status.code().unwrap_or(1));
+/// Get the config for an executable from a previous build, which is run again
+/// with `--link-only` without running cargo, so there is no [`Artifact`] for it.
+pub(crate) fn existing_artifact_config(
+    package: &Package,
+    kind: &str,
+    name: &str,
+    build_type: BuildType,
+    profile: Option<&str>,
+    config_file: Option<&Utf8Path>,
+    target_path: Utf8PathBuf,
+) -> CTRConfig {
+    let config = PackageMetadata::of(package)
+        .with_config_file(config_file)
+        .target_config(kind, name, build_type, profile);
+
+    CTRConfig {
+        name: target_title(&package.name, kind, name, false),
+        target_path,
+        ..config.with_package_defaults(package)
     }
 }
 
-#[derive(Default, Debug, Deserialize, PartialEq, Eq)]
-pub struct CTRConfig {
-    /// The authors of the application, which will be joined by `", "` to form
-    /// the `Publisher` field in the SMDH format. If not specified, a single author
-    /// of "Unspecified Author" will be used.
-    authors: Option<Vec<String>>,
+/// Check the `[package.metadata.cargo-3ds]` of every executable target in the
+/// workspace without building anything, as it would be resolved for either build
+/// type and with any named profile, along with the settings of `config_file`.
+///
+/// Every problem found is returned, rather than only the first.
+pub(crate) fn validate_workspace(
+    metadata: &Metadata,
+    config_file: Option<&Utf8Path>,
+) -> Vec<String> {
+    const EXECUTABLE_KINDS: &[&str] = &["bin", "example", "test", "bench"];
 
-    /// A description of the application, also called `Long Description` in the
-    /// SMDH format. The following values will be used in order of precedence:
-    /// - `cargo-3ds` metadata field
-    /// - `package.description` in Cargo.toml
-    /// - "Homebrew Application"
-    description: Option<String>,
+    let mut problems = Vec::new();
 
-    /// The path to the app icon, defaulting to `$CARGO_MANIFEST_DIR/icon.png`
-    /// if it exists. If not specified, the devkitPro default icon is used.
-    icon_path: Option<Utf8PathBuf>,
+    let (config_file, file_profiles) = match config_file.map(PackageMetadata::load) {
+        Some(Ok(file)) => (config_file, file.profile.into_keys().collect()),
+        Some(Err(err)) => {
+            problems.push(err);
+            (None, Vec::new())
+        }
+        None => (None, Vec::new()),
+    };
 
-    /// The path to the romfs directory, defaulting to `$CARGO_MANIFEST_DIR/romfs`
-    /// if it exists, or unused otherwise. If a path is specified but does not
-    /// exist, an error occurs.
-    #[serde(alias = "romfs-dir")]
-    romfs_dir: Option<Utf8PathBuf>,
+    for package in metadata.workspace_packages() {
+        let mut profiles: Vec<String> = match PackageMetadata::parse(package) {
+            Ok(package_metadata) => package_metadata.profile.into_keys().collect(),
+            Err(err) => {
+                problems.push(format!("package `{}` has {err}", package.name));
+                continue;
+            }
+        };
+        profiles.extend(file_profiles.iter().cloned());
+        profiles.sort();
+        profiles.dedup();
+        let profiles: Vec<_> = [None]
+            .into_iter()
+            .chain(profiles.iter().map(|profile| Some(profile.as_str())))
+            .collect();
 
-    // Remaining fields come from cargo metadata / build artifact output and
-    // cannot be customized by users in `package.metadata.cargo-3ds`. I suppose
-    // in theory we could allow name to be customizable if we wanted...
-    #[serde(skip)]
-    name: String,
-    #[serde(skip)]
-    target_path: Utf8PathBuf,
-    #[serde(skip)]
-    manifest_dir: Utf8PathBuf,
+        for target in &package.targets {
+            let Some(kind) = target
+                .kind
+                .iter()
+                .find(|kind| EXECUTABLE_KINDS.contains(&kind.as_str()))
+            else {
+                continue;
+            };
+
+            // The same problem is usually found for every build type and profile
+            let mut target_problems = BTreeSet::new();
+            for build_type in [BuildType::Debug, BuildType::Release] {
+                for &profile in &profiles {
+                    let config = CTRConfig {
+                        workspace_root: Some(metadata.workspace_root.clone()),
+                        ..existing_artifact_config(
+                            package,
+                            kind,
+                            &target.name,
+                            build_type,
+                            profile,
+                            config_file,
+                            Utf8PathBuf::new(),
+                        )
+                    };
+                    target_problems.extend(config.problems());
+                    target_problems.extend(config.file_problems());
+                }
+            }
+
+            problems.extend(target_problems.into_iter().map(|problem| {
+                format!("{kind} `{}` of `{}`: {problem}", target.name, package.name)
+            }));
+        }
+    }
+
+    problems
 }
 
-impl CTRConfig {
-    /// Get the path to the output `.3dsx` file.
-    pub(crate) fn path_3dsx(&self) -> Utf8PathBuf {
-        self.target_path.with_extension("3dsx")
+/// Builds the 3dsx using `3dsxtool`.
+/// This will fail if `3dsxtool` is not in a directory found in $PATH. See [`tool_command`]
+/// for the directory it is run in.
+///
+/// If `embed_romfs` is not set, the romfs is left out of the 3dsx, to be loaded
+/// by an emulator from [`CTRConfig::stage_loose_romfs`] instead. Otherwise, a
+/// staged romfs is removed after use, unless `--keep-temp` is set.
+pub(crate) fn build_3dsx(
+    config: &CTRConfig,
+    keep_temp: bool,
+    max_romfs_size: Option<u64>,
+    embed_romfs: bool,
+) -> Result<(), PackageError> {
+    let mut command = tool_command("3dsxtool", config);
+    command
+        .arg(&config.target_path)
+        .arg(config.path_3dsx())
+        .arg(format!("--smdh={}", config.path_smdh()));
+
+    // A loose romfs is staged separately by `CTRConfig::stage_loose_romfs`
+    let staged_romfs = match config.staged_romfs_manifest().filter(|_| embed_romfs) {
+        Some(manifest) => {
+            let staged_romfs = config.path_staged_romfs();
+            match config.romfs_manifest() {
+                Some(manifest_path) => {
+                    info!("Staging RomFS from {manifest_path} into {staged_romfs}");
+                }
+                None => info!("Staging RomFS into {staged_romfs}"),
+            }
+
+            manifest?.stage(&staged_romfs)?;
+            Some(StagingDir::new(staged_romfs, keep_temp))
+        }
+        None => None,
+    };
+
+    let romfs = config.romfs_dir();
+    let romfs_image = config.romfs_image();
+    if let Some(image) = &romfs_image {
+        // `3dsxtool` can only build a romfs from a directory, so the image is
+        // appended to its output instead
+        info!("Adding RomFS image {image}");
+    } else if !embed_romfs {
+        if let Some(romfs) = config.loose_romfs_dir() {
+            info!("Not embedding RomFS from {romfs} (--romfs-loose)");
+        }
+    } else if let Some(staged_romfs) = &staged_romfs {
+        check_romfs_size(staged_romfs.path(), max_romfs_size)?;
+        command.arg(format!("--romfs={}", staged_romfs.path()));
+    } else if romfs.is_dir() {
+        info!("Adding RomFS from {romfs}");
+        check_romfs_size(&romfs, max_romfs_size)?;
+        command.arg(format!("--romfs={romfs}"));
+    } else if config.romfs_dir.is_some() {
+        if let Some(package_romfs) = &config.package_romfs_dir {
+            let package_romfs = config.resolve_romfs_dir(package_romfs);
+            if !package_romfs.is_dir() {
+                warn!(
+                    "the default RomFS dir {package_romfs} from {} does not exist either",
+                    ConfigSource::Package
+                );
+            }
+        }
+
+        return Err(format!(
+            "Could not find RomFS dir {romfs}, configured by `romfs_dir` in {}",
+            config.romfs_dir_source
+        )
+        .into());
+    }
+
+    print_command(&command);
+    script::record(&command);
+
+    // Remove any stale output so we can tell whether the tool actually wrote a new one
+    let _ = fs::remove_file(config.path_3dsx());
+
+    command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+
+    let mut process = spawn_tool(&mut command)?;
+
+    let status = process.wait().unwrap();
+
+    if !status.success() {
+        return Err(PackageError::new(
+            format!("`3dsxtool` failed ({status})"),
+            EXIT_TOOL,
+        ));
     }
 
-    /// Get the path to the output `.smdh` file.
-    pub(crate) fn path_smdh(&self) -> Utf8PathBuf {
-        self.target_path.with_extension("smdh")
+    check_tool_output("3dsxtool", &config.path_3dsx())?;
+
+    if let Some(image) = &romfs_image {
+        romfs::embed_image(&config.path_3dsx(), image)?;
     }
 
-    /// Get the absolute path to the romfs directory, defaulting to `romfs` if not specified.
-    pub(crate) fn romfs_dir(&self) -> Utf8PathBuf {
-        self.manifest_dir
-            .join(self.romfs_dir.as_deref().unwrap_or(Utf8Path::new("romfs")))
+    // Record how the 3dsx was built, so switching modes rebuilds it
+    let marker = config.path_loose_romfs_marker();
+    let marked = if embed_romfs {
+        fs::remove_file(&marker).or_else(|err| match err.kind() {
+            io::ErrorKind::NotFound => Ok(()),
+            _ => Err(err),
+        })
+    } else {
+        File::create(&marker).map(drop)
+    };
+    if let Err(err) = marked {
+        warn!("unable to update {marker}: {err}");
     }
 
-    // as standard with the devkitPRO toolchain
-    const DEFAULT_AUTHOR: &'static str = "Unspecified Author";
-    const DEFAULT_DESCRIPTION: &'static str = "Homebrew Application";
+    Ok(())
+}
 
-    /// Builds the smdh using `smdhtool`.
-    /// This will fail if `smdhtool` is not within the running directory or in a directory found in $PATH
-    pub(crate) fn build_smdh(&self, verbose: bool) {
-        let description = self
-            .description
-            .as_deref()
-            .unwrap_or(Self::DEFAULT_DESCRIPTION);
+/// The romfs size above which a warning is printed, unless `--max-romfs-size`
+/// is given. A romfs this large usually contains build artifacts or source
+/// assets by mistake.
+const DEFAULT_ROMFS_WARN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Check the total size of the files in the romfs directory `romfs` before it
+/// is embedded. It is an error to exceed `max_size` (from `--max-romfs-size`),
+/// otherwise a warning is printed if it exceeds [`DEFAULT_ROMFS_WARN_SIZE`].
+fn check_romfs_size(romfs: &Utf8Path, max_size: Option<u64>) -> Result<(), String> {
+    let size = match romfs_size(romfs) {
+        Ok(size) => size,
+        Err(err) => {
+            warn!("unable to compute the size of the RomFS {romfs}: {err}");
+            return Ok(());
+        }
+    };
+
+    match max_size {
+        Some(max_size) if size > max_size => {
+            return Err(format!(
+                "RomFS {romfs} is {}, more than the maximum of {} set by `--max-romfs-size`",
+                format_size(size),
+                format_size(max_size)
+            ));
+        }
+        None if size > DEFAULT_ROMFS_WARN_SIZE => warn!(
+            "RomFS {romfs} is {}, which is unusually large for a 3dsx. \
+            Check that it doesn't include build artifacts or source assets by mistake",
+            format_size(size)
+        ),
+        _ => debug!("RomFS {romfs} is {}", format_size(size)),
+    }
+
+    Ok(())
+}
+
+/// Get the total size in bytes of the files in the romfs directory `romfs`.
+fn romfs_size(romfs: &Utf8Path) -> io::Result<u64> {
+    let mut size = 0;
+    for path in walk_files(romfs)? {
+        let metadata = fs::metadata(path)?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Format a size in bytes in MiB, with one decimal place.
+fn format_size(size: u64) -> String {
+    format!("{:.1} MiB", size as f64 / (1024.0 * 1024.0))
+}
+
+/// Build a command to run one of the devkitPro tools for `config`.
+///
+/// The tools are always run in the package's manifest directory (where relative
+/// paths in `package.metadata.cargo-3ds` are resolved from), rather than wherever
+/// `cargo-3ds` was invoked, so they behave the same regardless of the current
+/// directory. All paths passed to them are absolute.
+fn tool_command(tool: &str, config: &CTRConfig) -> Command {
+    let mut command = Command::new(UserConfig::get().tool(tool));
+    // A config from the library API may have no manifest directory
+    if !config.manifest_dir.as_str().is_empty() {
+        command.current_dir(&config.manifest_dir);
+    }
+    command
+}
 
-        let publisher = if let Some(authors) = self.authors.as_ref() {
-            authors.join(", ")
+/// Spawn one of the devkitPro tools, with an actionable error message if it
+/// couldn't be run. A missing tool is an [`EXIT_ENVIRONMENT`] error, and any
+/// other failure an [`EXIT_TOOL`] one.
+fn spawn_tool(command: &mut Command) -> Result<Child, PackageError> {
+    command.spawn().map_err(|err| {
+        // The program may be a path from the user config's `tool_paths`
+        let program = Path::new(command.get_program());
+        let tool = program
+            .file_stem()
+            .unwrap_or(program.as_os_str())
+            .to_string_lossy();
+        if err.kind() == io::ErrorKind::NotFound {
+            PackageError::new(missing_tool_help(&tool), EXIT_ENVIRONMENT)
         } else {
-            Self::DEFAULT_AUTHOR.to_string()
-        };
+            PackageError::new(format!("failed to run `{tool}`: {err}"), EXIT_TOOL)
+        }
+    })
+}
 
-        let icon_path = self.icon_path().unwrap_or_else(|err_path| {
-            eprintln!("Icon at {err_path} does not exist");
-            process::exit(1);
-        });
+/// Explain how to install a missing devkitPro tool, and whether the devkitPro
+/// tools directory is missing from `$PATH`.
+fn missing_tool_help(tool: &str) -> String {
+    let package = match tool {
+        "3dslink" => "3dslink",
+        _ => "3dstools",
+    };
+
+    let mut help = format!(
+        "`{tool}` could not be found in $PATH\n\
+        It is provided by the devkitPro `{package}` package, which can be installed \
+        with `dkp-pacman -S {package}` (or `dkp-pacman -S 3ds-dev` for the full 3DS toolchain)"
+    );
+
+    if let Ok(devkitpro) = env::var("DEVKITPRO") {
+        let tools_bin = PathBuf::from(devkitpro).join("tools").join("bin");
+        let on_path = env::var_os("PATH")
+            .is_some_and(|path| env::split_paths(&path).any(|dir| dir == tools_bin));
+
+        if tools_bin.is_dir() && !on_path {
+            help += &format!(
+                "\nNote: {} exists but is not in $PATH, try adding it with \
+                `export PATH=\"$DEVKITPRO/tools/bin:$PATH\"`",
+                tools_bin.display()
+            );
+        }
+    }
+
+    help
+}
+
+/// A directory of files staged for one of the devkitPro tools, e.g. a romfs
+/// assembled from several sources. It is removed when dropped, unless
+/// `--keep-temp` was given to inspect what the tool was given.
+pub(crate) struct StagingDir {
+    path: Utf8PathBuf,
+    keep: bool,
+}
+
+impl StagingDir {
+    pub(crate) fn new(path: Utf8PathBuf, keep: bool) -> Self {
+        Self { path, keep }
+    }
+
+    pub(crate) fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if self.keep {
+            info!("Keeping staged files in {} (--keep-temp)", self.path);
+        } else if let Err(err) = fs::remove_dir_all(&self.path) {
+            debug!("unable to remove {}: {err}", self.path);
+        }
+    }
+}
+
+/// Recursively list all the files (and directories) within `dir`, sorted so
+/// that the order doesn't depend on the filesystem, for reproducible builds.
+pub(crate) fn walk_files(dir: &Utf8Path) -> io::Result<Vec<Utf8PathBuf>> {
+    let mut files = Vec::new();
+
+    for entry in dir.read_dir_utf8()? {
+        let path = entry?.into_path();
+        if path.is_dir() {
+            files.extend(walk_files(&path)?);
+        }
+        files.push(path);
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Write `contents` to the file at `path`, unless it already has exactly those
+/// contents, so that derived files which haven't changed keep their timestamps.
+/// Returns whether the file was written.
+fn write_if_changed(path: &Utf8Path, contents: &[u8]) -> io::Result<bool> {
+    if fs::read(path).is_ok_and(|existing| existing == contents) {
+        debug!("Reusing unchanged {path}");
+        return Ok(false);
+    }
+
+    fs::write(path, contents)?;
+    Ok(true)
+}
 
-        let mut command = Command::new("smdhtool");
+/// Get the SHA-256 hash of the file at `path`, as lowercase hexadecimal.
+pub(crate) fn sha256_file(path: &Utf8Path) -> Result<String, String> {
+    let data = fs::read(path).map_err(|err| format!("unable to read {path}: {err}"))?;
+    Ok(sha256_hex(&data))
+}
+
+/// Get the SHA-256 hash of `data` as a lowercase hex string.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Get the value to set `SOURCE_DATE_EPOCH` to for `--reproducible`: the time of
+/// the latest git commit of the repository containing `package_dir` (or the
+/// current directory), or 0 outside of a git repository.
+fn source_date_epoch(package_dir: Option<&Utf8Path>) -> &'static str {
+    static EPOCH: OnceLock<String> = OnceLock::new();
+
+    EPOCH.get_or_init(|| {
+        let mut command = Command::new("git");
+        if let Some(dir) = package_dir {
+            command.current_dir(dir);
+        }
         command
-            .arg("--create")
-            .arg(&self.name)
-            .arg(description)
-            .arg(publisher)
-            .arg(icon_path)
-            .arg(self.path_smdh())
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit());
+            .args(["log", "-1", "--format=%ct"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|epoch| epoch.trim().to_string())
+            .filter(|epoch| !epoch.is_empty())
+            .unwrap_or_else(|| String::from("0"))
+    })
+}
+
+/// Ensure that an external tool which exited successfully actually wrote its output,
+/// so a silent failure doesn't surface later as a confusing missing-file error.
+/// It is an [`EXIT_TOOL`] error if it didn't.
+fn check_tool_output(tool: &str, path: &Utf8Path) -> Result<(), PackageError> {
+    let message = match fs::metadata(path) {
+        Ok(metadata) if metadata.len() > 0 => return Ok(()),
+        Ok(_) => format!("`{tool}` exited successfully but wrote an empty file: {path}"),
+        Err(err) => format!("`{tool}` exited successfully but did not write {path}: {err}"),
+    };
+    Err(PackageError::new(message, EXIT_TOOL))
+}
+
+/// Copy the built `.elf` executable to `output`, or into it if `output` is a directory.
+pub(crate) fn copy_elf(config: &CTRConfig, output: &Utf8Path) {
+    let dest = if output.is_dir() {
+        output.join(config.target_path.file_name().unwrap())
+    } else {
+        output.to_path_buf()
+    };
+
+    info!("Copying elf: {dest}");
+    if let Err(err) = fs::copy(&config.target_path, &dest) {
+        error!("unable to copy {} to {dest}: {err}", config.target_path);
+        process::exit(1);
+    }
+}
+
+/// Copy the `.3dsx` and `.smdh` built for `config` into the directory `dir`.
+pub(crate) fn copy_outputs(config: &CTRConfig, dir: &Utf8Path) {
+    if let Err(err) = fs::create_dir_all(dir) {
+        error!("unable to create {dir}: {err}");
+        process::exit(1);
+    }
 
-        if verbose {
-            print_command(&command);
+    for output in [config.path_3dsx(), config.path_smdh()] {
+        if !output.is_file() {
+            continue;
         }
 
-        let mut process = command
-            .spawn()
-            .expect("smdhtool command failed, most likely due to 'smdhtool' not being in $PATH");
+        let dest = dir.join(output.file_name().unwrap());
+        debug!("Copying {output} to {dest}");
+        if let Err(err) = fs::copy(&output, &dest) {
+            error!("unable to copy {output} to {dest}: {err}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Replace each `${NAME}` in `value` with the value of the environment variable `NAME`.
+fn expand_env(value: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
 
-        let status = process.wait().unwrap();
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let (name, after) = rest[start + 2..]
+            .split_once('}')
+            .ok_or_else(|| format!("unclosed `${{` in {value:?}"))?;
+        let var = env::var(name)
+            .map_err(|_| format!("environment variable `{name}` is not set in {value:?}"))?;
+        expanded.push_str(&var);
+        rest = after;
+    }
 
-        if !status.success() {
-            process::exit(status.code().unwrap_or(1));
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Standard base64, with the padding at the end optional.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Decode standard base64, ignoring whitespace so that long strings may be wrapped.
+fn decode_base64(input: &str) -> Result<Vec<u8>, String> {
+    let input: String = input.split_ascii_whitespace().collect();
+    BASE64
+        .decode(input)
+        .map_err(|err| format!("invalid base64: {err}"))
+}
+
+/// The number of times `3dslink` tries to discover a device by default.
+const DEFAULT_3DSLINK_RETRIES: usize = 10;
+
+/// Link the generated 3dsx to a 3ds to execute and test using `3dslink`.
+/// This will fail if `3dslink` is not in a directory found in $PATH. See [`tool_command`]
+/// for the directory it is run in.
+///
+/// If `--stdout-to` was given, the output of `3dslink` (including the console
+/// output received by its server) is also copied into that file.
+pub(crate) fn link(config: &CTRConfig, run_args: &Run) -> ExitStatus {
+    if run_args.address.len() > 1 {
+        return link_all(config, run_args);
+    }
+
+    let address = match &run_args.device_name {
+        Some(name) => Some(device::resolve(name).unwrap_or_else(|err| {
+            error!("{err}");
+            process::exit(1);
+        })),
+        None => run_args
+            .address
+            .first()
+            .copied()
+            .or(UserConfig::get().default_address),
+    };
+    link_to(config, run_args, address)
+}
+
+/// Send the executable for `config` to the device at `address` with `3dslink`,
+/// or to the device found by discovery if there is none.
+pub(crate) fn link_to(config: &CTRConfig, run_args: &Run, address: Option<Ipv4Addr>) -> ExitStatus {
+    let mut args = run_args.get_3dslink_args();
+    if let Some(address) = address {
+        args.extend(["--address".to_string(), address.to_string()]);
+    }
+
+    match discover_device(run_args, address) {
+        // Skip discovery in 3dslink, since we already found the device
+        Some(Ok(found)) if address.is_none() => {
+            args.extend(["--address".to_string(), found.to_string()]);
+        }
+        Some(Ok(_)) | None => {}
+        Some(Err(err)) => {
+            error!("{err}");
+            process::exit(1);
         }
     }
 
-    /// Get the path to the icon to be used for the SMDH output.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if the specified (or fallback) path does not exist.
-    /// The contained path is the path we tried to use.
-    fn icon_path(&self) -> Result<Utf8PathBuf, Utf8PathBuf> {
-        let path = if let Some(path) = &self.icon_path {
-            self.manifest_dir.join(path)
-        } else {
-            let path = self.manifest_dir.join("icon.png");
-            if path.exists() {
-                return Ok(path);
-            }
+    let output_file = create_output_file(run_args);
+    let process = spawn_3dslink(config, args, output_file.is_some() || run_args.server);
+    wait_3dslink(
+        process,
+        output_file,
+        run_args.server.then(ExitCodeScanner::default),
+        run_args.server_timeout(),
+    )
+}
 
-            Utf8PathBuf::from(env::var("DEVKITPRO").unwrap())
-                .join("libctru")
-                .join("default_icon.png")
-        };
+/// Get the address of the device to debug with `--gdb`: the one given with
+/// `--address` or `--device-name`, or else the one found by discovery, since
+/// it's needed to connect to the device's GDB stub.
+pub(crate) fn gdb_device_address(run_args: &Run) -> Result<Ipv4Addr, String> {
+    if let Some(name) = &run_args.device_name {
+        return device::resolve(name);
+    }
 
-        if path.exists() {
-            Ok(path)
-        } else {
-            Err(path)
+    match run_args.address[..] {
+        [] => {
+            if let Some(address) = UserConfig::get().default_address {
+                return Ok(address);
+            }
         }
+        [address] => return Ok(address),
+        _ => return Err(String::from("`--gdb` can only debug one device at a time")),
     }
+
+    discover_device(run_args, None).unwrap_or_else(|| {
+        let source = run_args.source_address.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        device::discover(
+            None,
+            source,
+            run_args.retries.unwrap_or(DEFAULT_3DSLINK_RETRIES),
+        )
+    })
 }
 
-#[derive(Ord, PartialOrd, PartialEq, Eq, Debug)]
-pub struct CommitDate {
-    year: i32,
-    month: i32,
-    day: i32,
+/// Find the device (at `address`, if given) ourselves rather than leaving it to
+/// `3dslink`: with `--wait-for-device`, for up to `--wait-timeout` seconds, or with
+/// `--source-address`, since `3dslink` can't be told which interface to use, trying
+/// as many times as it would. Returns `None` if `3dslink` can discover it itself.
+fn discover_device(run_args: &Run, address: Option<Ipv4Addr>) -> Option<Result<Ipv4Addr, String>> {
+    let source = run_args.source_address.unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+    if run_args.wait_for_device {
+        let timeout = Duration::from_secs(run_args.wait_timeout);
+        Some(device::wait_for_device(address, source, timeout))
+    } else if run_args.source_address.is_some() {
+        let tries = run_args.retries.unwrap_or(DEFAULT_3DSLINK_RETRIES);
+        Some(device::discover(address, source, tries))
+    } else {
+        None
+    }
 }
 
-impl CommitDate {
-    fn parse(date: &str) -> Option<Self> {
-        let mut iter = date.split('-');
+/// Send the executable for `config` to each of the devices given with `--address`,
+/// one after another, or all at once with `--parallel`. Every device is tried,
+/// even if sending to some of them fails.
+///
+/// Returns the status of the first `3dslink` which failed, if any.
+fn link_all(config: &CTRConfig, run_args: &Run) -> ExitStatus {
+    let device_args = |address: Ipv4Addr| {
+        let mut args = run_args.get_3dslink_args();
+        args.extend(["--address".to_string(), address.to_string()]);
+        args
+    };
 
-        let year = iter.next()?.parse().ok()?;
-        let month = iter.next()?.parse().ok()?;
-        let day = iter.next()?.parse().ok()?;
+    // Each device is discovered the same way as a single one would be
+    for &address in &run_args.address {
+        if let Some(Err(err)) = discover_device(run_args, Some(address)) {
+            error!("{err}");
+            process::exit(1);
+        }
+    }
 
-        Some(Self { year, month, day })
+    let statuses: Vec<_> = if run_args.parallel {
+        // The output of each `3dslink` is interleaved, since none of them start a server
+        let processes: Vec<_> = run_args
+            .address
+            .iter()
+            .map(|&address| (address, spawn_3dslink(config, device_args(address), false)))
+            .collect();
+        processes
+            .into_iter()
+            .map(|(address, process)| (address, wait_3dslink(process, None, None, None)))
+            .collect()
+    } else {
+        // The output of every device is written to the same `--stdout-to` file
+        let output_file = create_output_file(run_args);
+        run_args
+            .address
+            .iter()
+            .map(|&address| {
+                info!("Sending to {address}");
+                let output_file = output_file.as_ref().map(|file| file.try_clone().unwrap());
+                let process = spawn_3dslink(
+                    config,
+                    device_args(address),
+                    output_file.is_some() || run_args.server,
+                );
+                (
+                    address,
+                    wait_3dslink(
+                        process,
+                        output_file,
+                        run_args.server.then(ExitCodeScanner::default),
+                        run_args.server_timeout(),
+                    ),
+                )
+            })
+            .collect()
+    };
+
+    let failed: Vec<_> = statuses
+        .iter()
+        .filter(|(_, status)| !status.success())
+        .collect();
+
+    for (address, status) in &statuses {
+        if status.success() {
+            info!("Sent {} to {address}", config.path_3dsx());
+        } else {
+            error!("`3dslink` failed to send to {address} ({status})");
+        }
+    }
+
+    match failed.first() {
+        Some((_, status)) => {
+            let addresses: Vec<_> = failed.iter().map(|(address, _)| address).collect();
+            error!(
+                "{} of {} devices failed: {addresses:?}",
+                failed.len(),
+                statuses.len()
+            );
+            *status
+        }
+        None => statuses[0].1,
     }
 }
 
-impl fmt::Display for CommitDate {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+/// Create the `--stdout-to` file for the output of the `3dslink` server, if any.
+fn create_output_file(run_args: &Run) -> Option<File> {
+    run_args.stdout_to.as_ref().map(|path| {
+        File::create(path).unwrap_or_else(|err| {
+            error!("unable to create output file {path}: {err}");
+            process::exit(1);
+        })
+    })
+}
+
+/// Start `3dslink` to send the 3dsx for `config`, with its stdout piped if it
+/// should be captured, i.e. to a file or to find the executable's exit code.
+fn spawn_3dslink(config: &CTRConfig, args: Vec<String>, capture_stdout: bool) -> Child {
+    let mut command = tool_command("3dslink", config);
+    command
+        .arg(config.path_3dsx())
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit());
+
+    if capture_stdout {
+        command.stdout(Stdio::piped());
+    } else {
+        command.stdout(Stdio::inherit());
     }
+
+    print_command(&command);
+
+    spawn_tool(&mut command).unwrap_or_else(|err| err.exit())
 }
 
-const MINIMUM_COMMIT_DATE: CommitDate = CommitDate {
-    year: 2023,
-    month: 5,
+/// Wait for a `3dslink` started by [`spawn_3dslink`] to exit, copying its output
+/// to `output_file` as well as stdout. If `server_timeout` is given, `3dslink`
+/// is stopped once it has passed since it was started, which counts as a success.
+/// This includes the time taken to send the executable, since `3dslink` doesn't
+/// reliably report when the transfer is done and its server has started.
+///
+/// With the 3dslink server running, the executable's exit code is taken from
+/// its output by the `exit_code` scanner, and returned if `3dslink` itself succeeded.
+fn wait_3dslink(
+    mut process: Child,
+    output_file: Option<File>,
+    exit_code: Option<ExitCodeScanner>,
+    server_timeout: Option<Duration>,
+) -> ExitStatus {
+    let server = exit_code.is_some();
+
+    // The output is copied on another thread, so the timeout can be enforced meanwhile
+    let capture = (output_file.is_some() || server).then(|| {
+        let stdout = process.stdout.take().unwrap();
+        thread::spawn(move || {
+            let mut scanner = exit_code.unwrap_or_default();
+            let mut reader = TeeReader::new(stdout, &mut scanner);
+            let result = match output_file {
+                Some(output_file) => {
+                    io::copy(&mut TeeReader::new(reader, output_file), &mut io::stdout())
+                }
+                None => io::copy(&mut reader, &mut io::stdout()),
+            };
+            if let Err(err) = result {
+                warn!("failed to capture 3dslink output: {err}");
+            }
+            scanner.finish()
+        })
+    });
+
+    let status = match server_timeout {
+        Some(timeout) => match wait_until(&mut process, Instant::now() + timeout) {
+            Some(status) => status,
+            None => {
+                info!("Stopping the 3dslink server after {}s", timeout.as_secs());
+                stop_process(&mut process);
+                ExitStatus::default()
+            }
+        },
+        None => process.wait().unwrap(),
+    };
+
+    let exit_code = capture.and_then(|capture| capture.join().ok().flatten());
+
+    match exit_code {
+        Some(code) if server && status.success() && code != 0 => {
+            error!("the executable exited with code {code}");
+            exit_status(code)
+        }
+        _ => status,
+    }
+}
+
+/// The prefix of the line an executable may print to report its exit code to
+/// the 3dslink server, e.g. `cargo-3ds: exit code 1`. This is a convention of
+/// `cargo-3ds` only, which executables have to opt into: neither the netloader
+/// nor `3dslink` report the exit code of an executable.
+const EXIT_CODE_PREFIX: &str = "cargo-3ds: exit code ";
+
+/// Finds the exit code of an executable in the output it sends to the 3dslink
+/// server, given by a line starting with [`EXIT_CODE_PREFIX`]. The first nonzero
+/// exit code is kept.
+#[derive(Default)]
+struct ExitCodeScanner {
+    line: Vec<u8>,
+    code: Option<i32>,
+}
+
+impl ExitCodeScanner {
+    fn scan_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let code = line
+            .trim()
+            .strip_prefix(EXIT_CODE_PREFIX)
+            .and_then(|code| code.trim().parse().ok());
+        if code.is_some() && self.code.is_none_or(|code| code == 0) {
+            self.code = code;
+        }
+
+        self.line.clear();
+    }
+
+    /// Get the exit code found in the output, once all of it has been written.
+    fn finish(mut self) -> Option<i32> {
+        self.scan_line();
+        self.code
+    }
+}
+
+impl io::Write for ExitCodeScanner {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                self.scan_line();
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Make an [`ExitStatus`] for a process which exited with `code`. On unix, only
+/// the low 8 bits of an exit code are kept, so a nonzero code which would become
+/// 0 (e.g. 256) is 1 instead, to still be a failure.
+fn exit_status(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        let code = match code & 0xff {
+            0 if code != 0 => 1,
+            code => code,
+        };
+        ExitStatus::from_raw(code << 8)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+}
+
+/// The environment variable with the number of seconds to wait for `cargo`
+/// when gathering information about the build, e.g. `cargo metadata`.
+const PROBE_TIMEOUT_ENV: &str = "CARGO_3DS_PROBE_TIMEOUT";
+
+/// How long to wait for `cargo` when gathering information about the build, if
+/// [`PROBE_TIMEOUT_ENV`] isn't set.
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Get how long to wait for the `cargo` commands run to gather information
+/// about the build, which may otherwise hang forever, e.g. waiting on a lock.
+pub(crate) fn probe_timeout() -> Duration {
+    match env::var(PROBE_TIMEOUT_ENV) {
+        Ok(secs) => secs
+            .trim()
+            .parse()
+            .map(Duration::from_secs)
+            .unwrap_or_else(|_| {
+                warn!("ignoring invalid {PROBE_TIMEOUT_ENV}={secs}, expected a number of seconds");
+                DEFAULT_PROBE_TIMEOUT
+            }),
+        Err(_) => DEFAULT_PROBE_TIMEOUT,
+    }
+}
+
+/// Run `command` to completion and collect its output, like [`Command::output`],
+/// but stop it and fail with [`io::ErrorKind::TimedOut`] if it runs for longer
+/// than `timeout`.
+pub(crate) fn output_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<Output> {
+    let mut process = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The pipes are read while waiting, so a full pipe can't block the process
+    let read_pipe = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    };
+    let stdout = read_pipe(process.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = read_pipe(process.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+    let Some(status) = wait_until(&mut process, Instant::now() + timeout) else {
+        stop_process(&mut process);
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "timed out after {}s (set {PROBE_TIMEOUT_ENV} to wait longer)",
+                timeout.as_secs()
+            ),
+        ));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Wait for `process` to exit until `deadline`, returning `None` if it is still running.
+fn wait_until(process: &mut Child, deadline: Instant) -> Option<ExitStatus> {
+    loop {
+        if let Some(status) = process.try_wait().unwrap() {
+            return Some(status);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Stop `process` gracefully: ask it to exit with `SIGTERM` where supported,
+/// and kill it if it is still running a few seconds later.
+fn stop_process(process: &mut Child) {
+    #[cfg(unix)]
+    {
+        /// How long the process is given to exit after `SIGTERM`.
+        const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+        let terminated = Command::new("kill")
+            .args(["-TERM", &process.id().to_string()])
+            .status()
+            .is_ok_and(|status| status.success());
+        if terminated && wait_until(process, Instant::now() + SHUTDOWN_GRACE).is_some() {
+            return;
+        }
+    }
+
+    debug!("Killing process {}", process.id());
+    let _ = process.kill();
+    let _ = process.wait();
+}
+
+/// Run the generated 3dsx in an emulator. The emulator is `azahar` by default,
+/// or the program set in the `CARGO_3DS_EMULATOR` environment variable.
+///
+/// With `--romfs-loose`, the romfs directory is passed to the emulator in the
+/// `CARGO_3DS_ROMFS_DIR` environment variable.
+pub(crate) fn emulate(config: &CTRConfig, run_args: &Run) -> ExitStatus {
+    let (emulator, mut command) = emulator_command(config, run_args);
+    print_command(&command);
+    print_console_banner(run_args);
+
+    command
+        .status()
+        .unwrap_or_else(|err| emulator_failed(&emulator, err))
+}
+
+/// The banner printed before the output of the emulator with `--show-console`.
+const CONSOLE_BANNER: &str = "=== Device output ===";
+
+/// Print the [`CONSOLE_BANNER`] if `--show-console` is set. Like the rest of
+/// `cargo-3ds`'s messages, it is hidden by `--quiet`.
+fn print_console_banner(run_args: &Run) {
+    if run_args.show_console {
+        info!("{CONSOLE_BANNER}");
+    }
+}
+
+/// Start the emulator for `config` without waiting for it to exit, e.g. to
+/// attach a debugger to it.
+pub(crate) fn spawn_emulator(config: &CTRConfig, run_args: &Run) -> Child {
+    let (emulator, mut command) = emulator_command(config, run_args);
+    print_command(&command);
+    print_console_banner(run_args);
+
+    command
+        .spawn()
+        .unwrap_or_else(|err| emulator_failed(&emulator, err))
+}
+
+/// The emulator executables are run in, unless another is configured.
+const DEFAULT_EMULATOR: &str = "azahar";
+
+/// Get the emulator set with `CARGO_3DS_EMULATOR` or in the user config, if any.
+/// Only a configured emulator, e.g. a wrapper script, can act on the environment
+/// variables `cargo-3ds` sets for it, such as `CARGO_3DS_ROMFS_DIR`.
+pub(crate) fn configured_emulator() -> Option<String> {
+    env::var("CARGO_3DS_EMULATOR")
+        .ok()
+        .filter(|emulator| !emulator.is_empty())
+        .or_else(|| UserConfig::get().emulator_path.clone())
+}
+
+/// Create the command to run `config` in the emulator, returning the name of
+/// the emulator along with it.
+fn emulator_command(config: &CTRConfig, run_args: &Run) -> (String, Command) {
+    let emulator = configured_emulator().unwrap_or_else(|| DEFAULT_EMULATOR.to_string());
+
+    if !run_args.exe_args().is_empty() {
+        warn!("arguments cannot be passed to an executable run in an emulator");
+    }
+
+    let mut command = Command::new(&emulator);
+    command.arg(config.path_3dsx());
+
+    if run_args.romfs_loose {
+        if let Some(romfs) = config.loose_romfs_dir() {
+            command.env("CARGO_3DS_ROMFS_DIR", romfs);
+        }
+    }
+
+    if run_args.show_console {
+        command.env("CARGO_3DS_SHOW_CONSOLE", "1");
+    }
+
+    if let Some(screenshot) = &run_args.capture_screenshot {
+        command.env("CARGO_3DS_SCREENSHOT", screenshot).env(
+            "CARGO_3DS_SCREENSHOT_DELAY",
+            run_args.screenshot_delay.to_string(),
+        );
+    }
+
+    command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+
+    (emulator, command)
+}
+
+fn emulator_failed(emulator: &str, err: io::Error) -> ! {
+    error!(
+        "failed to run emulator `{emulator}`: {err}\n\
+        Set the `CARGO_3DS_EMULATOR` environment variable to choose a different emulator"
+    );
+    process::exit(EXIT_ENVIRONMENT);
+}
+
+/// The port of the GDB stub Luma3DS uses to debug the next application launched,
+/// once "Force-debug next application at launch" is enabled in the Rosalina menu.
+pub(crate) const GDB_DEVICE_PORT: u16 = 4003;
+
+/// The default port of the GDB stub of Azahar (and Citra), which has to be
+/// enabled in the emulator's settings.
+pub(crate) const GDB_EMULATOR_PORT: u16 = 24689;
+
+/// How many seconds gdb keeps retrying to connect to the GDB stub, since an
+/// emulator may take a while to start listening.
+const GDB_CONNECT_TIMEOUT: u64 = 30;
+
+/// Create the command to attach `arm-none-eabi-gdb` (or the debugger set in
+/// `CARGO_3DS_GDB`) to the GDB stub at `remote`, loading the symbols from the
+/// unstripped `.elf` built for `config`. Luma3DS supports `extended-remote`,
+/// which lets gdb attach to other processes, while emulators only support `remote`.
+fn gdb_command(config: &CTRConfig, remote: &str, extended: bool) -> Command {
+    let gdb =
+        env::var_os("CARGO_3DS_GDB").unwrap_or_else(|| UserConfig::get().tool("arm-none-eabi-gdb"));
+    let target = if extended {
+        "extended-remote"
+    } else {
+        "remote"
+    };
+
+    let mut command = Command::new(gdb);
+    command
+        .arg("-q")
+        .arg(&config.target_path)
+        .arg("-ex")
+        .arg(format!("set tcp connect-timeout {GDB_CONNECT_TIMEOUT}"))
+        .arg("-ex")
+        .arg(format!("target {target} {remote}"))
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+    command
+}
+
+/// Attach gdb to the executable for `config`, running at the GDB stub at `remote`.
+/// The gdb command is always printed, so it can be run manually if needed.
+pub(crate) fn attach_gdb(config: &CTRConfig, remote: &str, extended: bool) -> ExitStatus {
+    let mut command = gdb_command(config, remote, extended);
+    let (_, cmd_str) = quote_command(&command);
+    info!("Attaching debugger to {remote}: {cmd_str}");
+
+    command.status().unwrap_or_else(|err| {
+        error!(
+            "failed to run `{}`: {err}\n\
+            Install the devkitARM gdb or set the `CARGO_3DS_GDB` environment variable \
+            to choose a different debugger",
+            command.get_program().to_string_lossy()
+        );
+        process::exit(EXIT_ENVIRONMENT);
+    })
+}
+
+/// Where executables built by `cargo 3ds run` or `cargo 3ds test` are run.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunTarget {
+    /// Send the executable to a device with `3dslink`.
+    Device,
+    /// Run the executable in an emulator.
+    Emulator,
+}
+
+/// Whether an artifact was built for debugging or for release, which selects
+/// between the `debug` and `release` metadata overrides.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BuildType {
+    Debug,
+    Release,
+}
+
+impl BuildType {
+    /// Get the build type of an artifact. Like `cfg!(debug_assertions)`, this
+    /// works for custom profiles too, unlike checking for `--release`.
+    fn of(artifact: &Artifact) -> Self {
+        if artifact.profile.debug_assertions {
+            Self::Debug
+        } else {
+            Self::Release
+        }
+    }
+}
+
+impl fmt::Display for BuildType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Debug => write!(f, "debug"),
+            Self::Release => write!(f, "release"),
+        }
+    }
+}
+
+/// The contents of `[package.metadata.cargo-3ds]`. Any of the [`CTRConfig`] fields
+/// can be set at the top level as defaults for the whole package, overridden
+/// for debug or release builds in `[package.metadata.cargo-3ds.debug]` or
+/// `[package.metadata.cargo-3ds.release]`, and overridden per target in a table
+/// keyed by target kind and name, e.g. `[package.metadata.cargo-3ds.example.hello-world]`,
+/// or by a named profile selected with `--cfg-profile`, e.g.
+/// `[package.metadata.cargo-3ds.profile.release-dist]`.
+#[derive(Default, Debug, Deserialize)]
+struct PackageMetadata {
+    #[serde(flatten)]
+    default: CTRConfig,
+    debug: Option<CTRConfig>,
+    release: Option<CTRConfig>,
+    #[serde(default)]
+    bin: HashMap<String, CTRConfig>,
+    #[serde(default)]
+    example: HashMap<String, CTRConfig>,
+    #[serde(default)]
+    test: HashMap<String, CTRConfig>,
+    #[serde(default)]
+    bench: HashMap<String, CTRConfig>,
+    #[serde(default)]
+    lib: HashMap<String, CTRConfig>,
+    #[serde(default)]
+    profile: HashMap<String, CTRConfig>,
+    /// The settings loaded from `--config-file`, and the path they came from.
+    #[serde(skip)]
+    file: Option<(Utf8PathBuf, Box<PackageMetadata>)>,
+}
+
+impl PackageMetadata {
+    /// Get the `[package.metadata.cargo-3ds]` of `package`, ignoring it with a
+    /// warning if it is invalid.
+    fn of(package: &Package) -> Self {
+        Self::parse(package).unwrap_or_else(|err| {
+            warn!("ignoring {err}");
+            Self::default()
+        })
+    }
+
+    /// Get the `[package.metadata.cargo-3ds]` of `package`, if it is valid.
+    fn parse(package: &Package) -> Result<Self, String> {
+        match package.metadata.get("cargo-3ds") {
+            Some(metadata) => Self::deserialize(metadata)
+                .map_err(|err| format!("invalid `package.metadata.cargo-3ds`: {err}")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Add the settings from the `--config-file` at `path`, if any, exiting
+    /// with an error if it can't be loaded.
+    fn with_config_file(self, path: Option<&Utf8Path>) -> Self {
+        let Some(path) = path else {
+            return self;
+        };
+
+        match Self::load(path) {
+            Ok(file) => Self {
+                file: Some((path.to_path_buf(), Box::new(file))),
+                ..self
+            },
+            Err(err) => {
+                error!("{err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Load the settings of a `--config-file`, which has the same keys and tables
+    /// as `[package.metadata.cargo-3ds]`. Relative paths in it are resolved from
+    /// the directory containing the file.
+    fn load(path: &Utf8Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("unable to read config file {path}: {err}"))?;
+        let mut metadata: Self = toml::from_str(&contents)
+            .map_err(|err| format!("invalid config file {path}: {err}"))?;
+
+        let dir = path
+            .canonicalize_utf8()
+            .map_err(|err| format!("unable to resolve config file {path}: {err}"))?;
+        let dir = dir.parent().unwrap();
+
+        let assets_root = metadata.default.assets_root.is_some();
+        [&mut metadata.default]
+            .into_iter()
+            .chain(metadata.debug.as_mut())
+            .chain(metadata.release.as_mut())
+            .chain(metadata.bin.values_mut())
+            .chain(metadata.example.values_mut())
+            .chain(metadata.test.values_mut())
+            .chain(metadata.bench.values_mut())
+            .chain(metadata.lib.values_mut())
+            .chain(metadata.profile.values_mut())
+            .for_each(|config| config.resolve_paths(dir, assets_root));
+
+        Ok(metadata)
+    }
+
+    /// Get the config for a single target. Each field is taken from the
+    /// `--config-file` if set there, resolved the same way as the rest of the
+    /// metadata, then from the selected profile, then from the target's own
+    /// table, then from the overrides for the build type, and finally from the
+    /// package defaults.
+    fn target_config(
+        mut self,
+        kind: &str,
+        name: &str,
+        build_type: BuildType,
+        profile: Option<&str>,
+    ) -> CTRConfig {
+        let targets = match kind {
+            "bin" => Some(&mut self.bin),
+            "example" => Some(&mut self.example),
+            "test" => Some(&mut self.test),
+            "bench" => Some(&mut self.bench),
+            "lib" | "rlib" | "dylib" => Some(&mut self.lib),
+            _ => None,
+        };
+
+        let mut layers = Vec::new();
+        let mut misplaced = self.default.misplaced_fields(&ConfigSource::Package);
+
+        let build_type_config = match build_type {
+            BuildType::Debug => self.debug.take(),
+            BuildType::Release => self.release.take(),
+        };
+        if let Some(config) = build_type_config {
+            layers.push((ConfigSource::BuildType(build_type), config));
+        }
+
+        if let Some(target) = targets.and_then(|targets| targets.remove(name)) {
+            let source = ConfigSource::Target {
+                kind: kind.to_string(),
+                name: name.to_string(),
+            };
+            layers.push((source, target));
+        }
+
+        if let Some(profile) = profile {
+            let in_file = self
+                .file
+                .as_ref()
+                .is_some_and(|(_, file)| file.profile.contains_key(profile));
+            match self.profile.remove(profile) {
+                Some(config) => layers.push((ConfigSource::Profile(profile.to_string()), config)),
+                None if in_file => {}
+                None => warn!("config profile `{profile}` not found, using the default config"),
+            }
+        }
+
+        if let Some((path, file)) = self.file.take() {
+            // The profile may only be defined in the package metadata
+            let file_profile = profile.filter(|profile| file.profile.contains_key(*profile));
+            let mut config = file.target_config(kind, name, build_type, file_profile);
+            for problem in &mut config.misplaced {
+                *problem = format!("{path}: {problem}");
+            }
+            misplaced.append(&mut config.misplaced);
+            layers.push((ConfigSource::File(path), config));
+        }
+
+        let package_romfs_dir = self.default.romfs_dir.clone();
+        let mut config = self.default;
+
+        // Layers are in increasing order of precedence
+        for (source, layer) in layers {
+            if !matches!(source, ConfigSource::Target { .. } | ConfigSource::File(_)) {
+                misplaced.extend(layer.misplaced_fields(&source));
+            }
+            if layer.romfs_dir.is_some() {
+                config.romfs_dir_source = source;
+            }
+            config = layer.or(config);
+        }
+
+        if config.romfs_dir_source != ConfigSource::Package {
+            config.package_romfs_dir = package_romfs_dir;
+        }
+        config.misplaced = misplaced;
+
+        config
+    }
+}
+
+/// The metadata table a [`CTRConfig`] value was configured in.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ConfigSource {
+    /// The package defaults, `[package.metadata.cargo-3ds]`.
+    #[default]
+    Package,
+    /// A single target, e.g. `[package.metadata.cargo-3ds.example.hello-world]`.
+    Target { kind: String, name: String },
+    /// The overrides for debug or release builds, e.g. `[package.metadata.cargo-3ds.release]`.
+    BuildType(BuildType),
+    /// A named profile, e.g. `[package.metadata.cargo-3ds.profile.release-dist]`.
+    Profile(String),
+    /// A separate file given with `--config-file`.
+    File(Utf8PathBuf),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Package => write!(f, "`[package.metadata.cargo-3ds]`"),
+            Self::Target { kind, name } => {
+                write!(f, "`[package.metadata.cargo-3ds.{kind}.{name}]`")
+            }
+            Self::BuildType(build_type) => write!(f, "`[package.metadata.cargo-3ds.{build_type}]`"),
+            Self::Profile(name) => write!(f, "`[package.metadata.cargo-3ds.profile.{name}]`"),
+            Self::File(path) => write!(f, "{path} (`--config-file`)"),
+        }
+    }
+}
+
+#[derive(Default, Debug, Deserialize, PartialEq, Eq)]
+pub struct CTRConfig {
+    /// The authors of the application, which will be joined by `", "` to form
+    /// the `Publisher` field in the SMDH format. If not specified, a single author
+    /// of "Unspecified Author" will be used.
+    /// The publisher may be at most 64 UTF-16 code units long.
+    authors: Option<Vec<String>>,
+
+    /// A description of the application, also called `Long Description` in the
+    /// SMDH format. The following values will be used in order of precedence:
+    /// - `cargo-3ds` metadata field
+    /// - `package.description` in Cargo.toml
+    /// - "Homebrew Application"
+    ///
+    /// The description may be at most 128 UTF-16 code units long.
+    description: Option<String>,
+
+    /// A short description of the application, also called the title, which is
+    /// shown in the HOME Menu. Defaults to the name of the executable. The short
+    /// description may be at most 64 UTF-16 code units long.
+    #[serde(alias = "short-description")]
+    short_description: Option<String>,
+
+    /// The directory that relative `icon_path`, `romfs_dir` and `romfs_base`
+    /// paths are resolved from, instead of the package directory, e.g. an assets
+    /// directory shared by a monorepo. `${NAME}` is replaced by the value of the
+    /// environment variable `NAME`, and a relative root is resolved from the
+    /// package directory.
+    #[serde(alias = "assets-root")]
+    assets_root: Option<String>,
+
+    /// The path to the app icon, defaulting to `$CARGO_MANIFEST_DIR/icon.png`
+    /// if it exists. If not specified, the devkitPro default icon is used.
+    /// This may also be an `http(s)://` URL, which is downloaded and cached.
+    /// A relative path is resolved from the `assets_root`, if set.
+    icon_path: Option<Utf8PathBuf>,
+
+    /// The app icon as a base64-encoded 48x48 PNG image, to keep small projects
+    /// self-contained in `Cargo.toml`. This is used instead of the default icons,
+    /// but `icon_path` takes precedence over it if set.
+    #[serde(alias = "icon-base64")]
+    icon_base64: Option<String>,
+
+    /// The path to the romfs directory, defaulting to `$CARGO_MANIFEST_DIR/romfs`
+    /// if it exists, or unused otherwise. If a path is specified but does not
+    /// exist, an error occurs. A path starting with `//` is relative to the
+    /// workspace root instead of the package (or the `assets_root`, if set).
+    /// This may also be an `http(s)://` URL to an archive, which is downloaded,
+    /// cached and extracted.
+    #[serde(alias = "romfs-dir")]
+    romfs_dir: Option<Utf8PathBuf>,
+
+    /// The path to a romfs directory shared by several targets, which is placed
+    /// underneath the romfs: any file of `romfs_dir` (or `romfs_manifest`) with
+    /// the same path overrides the file from the base. A path starting with `//`
+    /// is relative to the workspace root, like `romfs_dir`.
+    #[serde(alias = "romfs-base")]
+    romfs_base: Option<Utf8PathBuf>,
+
+    /// The path to a romfs manifest mapping source files to paths within the
+    /// romfs, which is used instead of `romfs_dir` if set. See [`RomfsManifest`]
+    /// for the format.
+    #[serde(alias = "romfs-manifest")]
+    romfs_manifest: Option<Utf8PathBuf>,
+
+    /// Extra files to place in the romfs, mapping each destination path within
+    /// the romfs to a source file. These are added on top of `romfs_dir` or
+    /// `romfs_manifest`, or make up the whole romfs if neither is used.
+    #[serde(alias = "romfs-files")]
+    romfs_files: Option<BTreeMap<Utf8PathBuf, Utf8PathBuf>>,
+
+    /// The path to a prebuilt RomFS image to embed in the 3dsx, e.g. one built
+    /// with a dedicated tool. This takes precedence over every other romfs setting.
+    /// `--romfs-image` takes precedence over it.
+    #[serde(alias = "romfs-image")]
+    romfs_image: Option<Utf8PathBuf>,
+
+    /// The path to a prebuilt `.smdh` to embed in the 3dsx as is, instead of
+    /// building one from the other settings. `--smdh` takes precedence over it.
+    #[serde(alias = "smdh-path")]
+    smdh_path: Option<Utf8PathBuf>,
+
+    /// The file name (without extension) of the `.3dsx` and `.smdh` written next
+    /// to the executable, instead of the executable's own name. This can only be
+    /// set for a single target, e.g. in `[package.metadata.cargo-3ds.bin.<name>]`,
+    /// since targets sharing it would overwrite each other's outputs.
+    filename: Option<String>,
+
+    /// Extra files to add to the zip written by `--bundle`, besides the outputs,
+    /// the icon and any `README` or `LICENSE` in the package directory.
+    #[serde(alias = "bundle-files")]
+    bundle_files: Option<Vec<Utf8PathBuf>>,
+
+    /// The SHA-256 hash the built SMDH must have, as 64 hexadecimal digits, to
+    /// verify that it's built reproducibly. The build fails if it doesn't match.
+    /// Since each target's SMDH differs, this can only be set for a single target.
+    #[serde(alias = "smdh-sha256")]
+    smdh_sha256: Option<String>,
+
+    /// The Title ID used when building a CIA with `makerom`, as 16 hexadecimal
+    /// digits (e.g. `000400000FF3FF00`). Defaults to a homebrew placeholder.
+    /// CIAs can't be built yet, so this and the other `makerom` codes are only
+    /// validated for now.
+    #[serde(alias = "title-id")]
+    title_id: Option<String>,
+
+    /// The Product Code used when building a CIA with `makerom`, made of up to
+    /// 16 printable ASCII characters (e.g. `CTR-P-CTAP`). Defaults to `CTR-P-CTAP`.
+    #[serde(alias = "product-code")]
+    product_code: Option<String>,
+
+    /// The Company Code used when building a CIA with `makerom`, made of exactly
+    /// 2 ASCII alphanumeric characters. Defaults to `00`.
+    #[serde(alias = "company-code")]
+    company_code: Option<String>,
+
+    /// Whether to encrypt the contents of a CIA built with `makerom`, which
+    /// requires signing it for dev units with `cia_dev_key_index`. Defaults to
+    /// an unencrypted homebrew CIA.
+    #[serde(alias = "cia-encrypted")]
+    cia_encrypted: Option<bool>,
+
+    /// Sign a CIA built with `makerom` for dev units, with the development common
+    /// key of this index (0 to 5) from `cia_key_path`. Defaults to an unsigned
+    /// homebrew CIA.
+    #[serde(alias = "cia-dev-key-index")]
+    cia_dev_key_index: Option<u8>,
+
+    /// The file with the development keys used by `cia_dev_key_index`. The keys
+    /// aren't distributed with devkitPro, so they must be supplied by the user.
+    #[serde(alias = "cia-key-path")]
+    cia_key_path: Option<Utf8PathBuf>,
+
+    /// The boolean application settings of the SMDH, such as whether the
+    /// application is visible in the HOME Menu. Unset flags use `smdhtool`'s defaults.
+    #[serde(default)]
+    flags: SmdhFlags,
+
+    /// Where `cargo 3ds run` and `cargo 3ds test` run executables by default,
+    /// either `"device"` or `"emulator"`. The `--device`/`--emulator` flags take
+    /// precedence, and this takes precedence over a configured cargo runner.
+    #[serde(alias = "test-runner")]
+    test_runner: Option<RunTarget>,
+
+    // Remaining fields come from cargo metadata / build artifact output and
+    // cannot be customized by users in `package.metadata.cargo-3ds`. I suppose
+    // in theory we could allow name to be customizable if we wanted...
+    #[serde(skip)]
+    name: String,
+    #[serde(skip)]
+    target_path: Utf8PathBuf,
+    #[serde(skip)]
+    manifest_dir: Utf8PathBuf,
+    #[serde(skip)]
+    workspace_root: Option<Utf8PathBuf>,
+    // Where `romfs_dir` was configured, and the package default it overrode (if any).
+    #[serde(skip)]
+    romfs_dir_source: ConfigSource,
+    #[serde(skip)]
+    package_romfs_dir: Option<Utf8PathBuf>,
+
+    // The romfs directories contributed by dependencies, and the name of the
+    // directory in the romfs each is placed in.
+    #[serde(skip)]
+    romfs_contributions: Vec<(String, Utf8PathBuf)>,
+
+    // A message for each field set in a table shared by several targets,
+    // although it may only be set per target, see `misplaced_fields`.
+    #[serde(skip)]
+    misplaced: Vec<String>,
+}
+
+impl CTRConfig {
+    /// Merge two configs, using the user-configurable fields of `self` if set,
+    /// or the ones from `fallback` otherwise.
+    fn or(self, fallback: Self) -> Self {
+        // `romfs_dir` and `romfs_manifest` are alternatives, so setting either overrides both
+        let (romfs_dir, romfs_manifest) =
+            if self.romfs_dir.is_some() || self.romfs_manifest.is_some() {
+                (self.romfs_dir, self.romfs_manifest)
+            } else {
+                (fallback.romfs_dir, fallback.romfs_manifest)
+            };
+
+        Self {
+            authors: self.authors.or(fallback.authors),
+            description: self.description.or(fallback.description),
+            short_description: self.short_description.or(fallback.short_description),
+            assets_root: self.assets_root.or(fallback.assets_root),
+            icon_path: self.icon_path.or(fallback.icon_path),
+            icon_base64: self.icon_base64.or(fallback.icon_base64),
+            romfs_dir,
+            romfs_manifest,
+            romfs_base: self.romfs_base.or(fallback.romfs_base),
+            romfs_files: self.romfs_files.or(fallback.romfs_files),
+            romfs_image: self.romfs_image.or(fallback.romfs_image),
+            smdh_path: self.smdh_path.or(fallback.smdh_path),
+            filename: self.filename.or(fallback.filename),
+            bundle_files: self.bundle_files.or(fallback.bundle_files),
+            smdh_sha256: self.smdh_sha256.or(fallback.smdh_sha256),
+            title_id: self.title_id.or(fallback.title_id),
+            product_code: self.product_code.or(fallback.product_code),
+            company_code: self.company_code.or(fallback.company_code),
+            cia_encrypted: self.cia_encrypted.or(fallback.cia_encrypted),
+            cia_dev_key_index: self.cia_dev_key_index.or(fallback.cia_dev_key_index),
+            cia_key_path: self.cia_key_path.or(fallback.cia_key_path),
+            flags: self.flags.or(fallback.flags),
+            test_runner: self.test_runner.or(fallback.test_runner),
+            ..fallback
+        }
+    }
+
+    /// Fill in the fields which default to the fields of `package` in `Cargo.toml`:
+    /// the `authors` (used as the SMDH publisher) and the `description`, unless
+    /// they were set in the metadata, and the package's directory.
+    fn with_package_defaults(self, package: &Package) -> Self {
+        Self {
+            authors: self.authors.or_else(|| Some(package.authors.clone())),
+            description: self.description.or_else(|| package.description.clone()),
+            manifest_dir: package.manifest_path.parent().unwrap().into(),
+            ..self
+        }
+    }
+
+    /// Resolve the relative paths of a config loaded from a `--config-file` from
+    /// `dir`, the directory containing the file. With an `assets_root`, set in this
+    /// config or another of the same file, the asset paths are relative to it instead.
+    fn resolve_paths(&mut self, dir: &Utf8Path, assets_root: bool) {
+        let resolve = |path: &mut Utf8PathBuf| {
+            if !remote::is_remote(path) {
+                *path = dir.join(&*path);
+            }
+        };
+
+        if !assets_root && self.assets_root.is_none() {
+            self.icon_path.as_mut().map(resolve);
+            self.romfs_dir.as_mut().map(resolve);
+            self.romfs_base.as_mut().map(resolve);
+        }
+        self.romfs_manifest.as_mut().map(resolve);
+        self.romfs_image.as_mut().map(resolve);
+        self.smdh_path.as_mut().map(resolve);
+        self.cia_key_path.as_mut().map(resolve);
+        self.romfs_files
+            .iter_mut()
+            .flat_map(BTreeMap::values_mut)
+            .for_each(resolve);
+        self.bundle_files
+            .iter_mut()
+            .flatten()
+            .for_each(|path| *path = dir.join(&*path));
+    }
+
+    /// Download `icon_path` and `romfs_dir` into `cache` if they are URLs, and use
+    /// the cached copies in their place. A remote `romfs_dir` must be an archive
+    /// that `tar` can extract.
+    pub(crate) fn fetch_remote(&mut self, cache: &RemoteCache) -> Result<(), String> {
+        self.check_not_git()?;
+
+        if let Some(url) = self.icon_path.as_deref().filter(|p| remote::is_remote(p)) {
+            info!("Fetching icon: {url}");
+            self.icon_path = Some(cache.fetch_file(url.as_str())?);
+        }
+
+        if let Some(url) = self.romfs_dir.as_deref().filter(|p| remote::is_remote(p)) {
+            info!("Fetching RomFS: {url}");
+            self.romfs_dir = Some(cache.fetch_dir(url.as_str())?);
+        }
+
+        Ok(())
+    }
+
+    /// Check that neither the icon nor the romfs is a git reference, which
+    /// can't be downloaded, see [`remote::check_not_git`].
+    fn check_not_git(&self) -> Result<(), String> {
+        if let Some(icon_path) = &self.icon_path {
+            remote::check_not_git("icon_path", icon_path)?;
+        }
+        if let Some(romfs_dir) = &self.romfs_dir {
+            remote::check_not_git("romfs_dir", romfs_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Get the path to an output next to the executable with the given extension,
+    /// named after the executable or `filename` if set. This is never derived
+    /// from the title, which may contain spaces.
+    fn path_output(&self, extension: &str) -> Utf8PathBuf {
+        match &self.filename {
+            Some(filename) => self
+                .target_path
+                .with_file_name(format!("{filename}.{extension}")),
+            None => self.target_path.with_extension(extension),
+        }
+    }
+
+    /// Start building the config for the executable at `target_path`, see
+    /// [`CTRConfigBuilder`].
+    pub fn builder(target_path: impl Into<Utf8PathBuf>) -> CTRConfigBuilder {
+        CTRConfigBuilder::new(target_path)
+    }
+
+    /// Get the path to the output `.3dsx` file.
+    pub fn path_3dsx(&self) -> Utf8PathBuf {
+        self.path_output("3dsx")
+    }
+
+    /// Get the path to the `.smdh` file embedded in the 3dsx, which is the
+    /// prebuilt one from `smdh_path` if set, or else the one that is built.
+    pub fn path_smdh(&self) -> Utf8PathBuf {
+        match &self.smdh_path {
+            Some(path) => self.manifest_dir.join(path),
+            None => self.path_output("smdh"),
+        }
+    }
+
+    /// Whether a prebuilt SMDH is used, rather than building one.
+    pub(crate) fn prebuilt_smdh(&self) -> bool {
+        self.smdh_path.is_some()
+    }
+
+    /// Get the files to add to the zip written by `--bundle`: the `.3dsx`, the
+    /// `.smdh` and icon if used, any `README` or `LICENSE` files in the package
+    /// directory, and `bundle_files`.
+    pub(crate) fn bundle_files(&self) -> Vec<Utf8PathBuf> {
+        let mut files = vec![self.path_3dsx()];
+        files.extend(
+            [self.path_smdh()]
+                .into_iter()
+                .chain(self.icon_path().ok())
+                .filter(|path| path.is_file()),
+        );
+
+        let mut package_files: Vec<_> = fs::read_dir(&self.manifest_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| Utf8PathBuf::from_path_buf(entry.path()).ok())
+            .filter(|path| {
+                path.is_file()
+                    && path.file_name().is_some_and(|name| {
+                        let name = name.to_ascii_uppercase();
+                        name.starts_with("README") || name.starts_with("LICENSE")
+                    })
+            })
+            .collect();
+        package_files.sort();
+        files.extend(package_files);
+
+        files.extend(
+            self.bundle_files
+                .iter()
+                .flatten()
+                .map(|path| self.manifest_dir.join(path)),
+        );
+        files
+    }
+
+    /// Get the path the icon from `icon_base64` is decoded to.
+    fn path_decoded_icon(&self) -> Utf8PathBuf {
+        self.target_path.with_extension("icon.png")
+    }
+
+    /// Decode the icon from `icon_base64`, unless `icon_path` takes precedence over it.
+    fn decoded_icon(&self) -> Option<Result<Vec<u8>, String>> {
+        if self.icon_path.is_some() {
+            return None;
+        }
+
+        let icon = decode_base64(self.icon_base64.as_deref()?).and_then(|icon| {
+            smdh::check_icon_data(&icon)?;
+            Ok(icon)
+        });
+        Some(icon.map_err(|err| format!("invalid `icon_base64`: {err}")))
+    }
+
+    /// Get the absolute path to the romfs directory, defaulting to `romfs` in
+    /// the package directory if not specified.
+    pub(crate) fn romfs_dir(&self) -> Utf8PathBuf {
+        match &self.romfs_dir {
+            Some(path) => self.resolve_romfs_dir(path),
+            None => self.manifest_dir.join("romfs"),
+        }
+    }
+
+    /// Resolve a configured `romfs_dir`. It is relative to the [`Self::assets_dir`],
+    /// unless it starts with `//`, in which case it is relative to the workspace
+    /// root. `//` always means the workspace root, even on Windows where it would
+    /// start a UNC path. Without a known workspace root, e.g. for a config from
+    /// [`Self::builder`], such a path is reported by [`Self::validate`].
+    fn resolve_romfs_dir(&self, path: &Utf8Path) -> Utf8PathBuf {
+        match path.as_str().strip_prefix("//") {
+            Some(path) => self
+                .workspace_root
+                .as_ref()
+                .unwrap_or(&self.manifest_dir)
+                .join(path),
+            None => self.assets_dir().join(path),
+        }
+    }
+
+    /// Get the directory that configured asset paths are resolved from: the
+    /// `assets_root` with its environment variables expanded, or else the
+    /// package directory.
+    fn assets_dir(&self) -> Utf8PathBuf {
+        match self.assets_root.as_deref().map(expand_env) {
+            Some(Ok(root)) => self.manifest_dir.join(root),
+            // An invalid `assets_root` is reported by `problems`
+            Some(Err(_)) | None => self.manifest_dir.clone(),
+        }
+    }
+
+    /// Get the absolute path to the shared base romfs directory, if one is configured.
+    pub(crate) fn romfs_base(&self) -> Option<Utf8PathBuf> {
+        self.romfs_base
+            .as_deref()
+            .map(|path| self.resolve_romfs_dir(path))
+    }
+
+    /// Get the absolute path to the prebuilt RomFS image, if one is configured.
+    pub(crate) fn romfs_image(&self) -> Option<Utf8PathBuf> {
+        self.romfs_image
+            .as_deref()
+            .map(|path| self.manifest_dir.join(path))
+    }
+
+    /// Get the absolute path to the romfs manifest, if one is configured.
+    pub(crate) fn romfs_manifest(&self) -> Option<Utf8PathBuf> {
+        self.romfs_manifest
+            .as_deref()
+            .map(|path| self.manifest_dir.join(path))
+    }
+
+    /// Get the path to the directory where a romfs is staged from its manifest.
+    pub(crate) fn path_staged_romfs(&self) -> Utf8PathBuf {
+        self.target_path.with_extension("romfs")
+    }
+
+    /// Get the path to the file recording the [`Self::build_fingerprint`] the
+    /// `.smdh` and `.3dsx` were last built with.
+    fn path_build_fingerprint(&self) -> Utf8PathBuf {
+        self.path_output("3ds-fingerprint")
+    }
+
+    /// Get a hash of everything besides the input files which affects the outputs:
+    /// the resolved config (so e.g. `--cfg-profile`, `--config-file` and
+    /// `assets_root` are included), the user config, which selects the tools,
+    /// and the `flags` of the build.
+    pub(crate) fn build_fingerprint(&self, flags: &str) -> String {
+        let settings = format!("{self:?}\n{:?}\n{flags}", UserConfig::get());
+        sha256_hex(settings.as_bytes())
+    }
+
+    /// Record the `fingerprint` of the settings the outputs were just built with,
+    /// so they are rebuilt if any of them change.
+    pub(crate) fn write_build_fingerprint(&self, fingerprint: &str) -> Result<(), String> {
+        let path = self.path_build_fingerprint();
+        fs::write(&path, fingerprint).map_err(|err| format!("unable to write {path}: {err}"))
+    }
+
+    /// Forget the settings the outputs were built with, so they are rebuilt the
+    /// next time, e.g. after `cargo 3ds smdh` replaced the `.smdh`.
+    pub(crate) fn clear_build_fingerprint(&self) {
+        let _ = fs::remove_file(self.path_build_fingerprint());
+    }
+
+    /// Get the path to the file marking that the `.3dsx` was built without its romfs.
+    pub(crate) fn path_loose_romfs_marker(&self) -> Utf8PathBuf {
+        self.target_path.with_extension("romfs-loose")
+    }
+
+    /// Whether the romfs must be staged from several sources, or filtered by
+    /// a [`romfs::IGNORE_FILE`], rather than used directly from `romfs_dir`.
+    fn needs_staged_romfs(&self) -> bool {
+        // A prebuilt image replaces the romfs entirely
+        if self.romfs_image.is_some() {
+            return false;
+        }
+
+        self.romfs_manifest.is_some()
+            || self.romfs_base.is_some()
+            || self.romfs_files.is_some()
+            || !self.romfs_contributions.is_empty()
+            || self.romfs_dir().join(romfs::IGNORE_FILE).is_file()
+    }
+
+    /// Get the manifest of the files to stage into the romfs, if the romfs can't
+    /// be used directly from `romfs_dir`: either because `romfs_manifest`,
+    /// `romfs_base` or `romfs_files` is set, because dependencies contribute files
+    /// to the romfs, or because some of its files are ignored by a [`romfs::IGNORE_FILE`].
+    /// Each dependency's files are placed in a directory named after the dependency,
+    /// and the files of `romfs_base` are only used where nothing else is placed.
+    pub(crate) fn staged_romfs_manifest(&self) -> Option<Result<RomfsManifest, String>> {
+        if !self.needs_staged_romfs() {
+            return None;
+        }
+
+        let mut manifest = if let Some(manifest_path) = self.romfs_manifest() {
+            match RomfsManifest::read(&manifest_path) {
+                Ok(manifest) => manifest,
+                Err(err) => return Some(Err(err)),
+            }
+        } else {
+            let mut manifest = RomfsManifest::default();
+            let romfs = self.romfs_dir();
+            // A configured romfs dir must exist, which is checked when staging
+            if self.romfs_dir.is_some() || romfs.is_dir() {
+                manifest.add(romfs, Utf8PathBuf::new());
+            }
+            manifest
+        };
+
+        for (dest, src) in self.romfs_files.iter().flatten() {
+            manifest.add(self.manifest_dir.join(src), dest.clone());
+        }
+
+        for (namespace, dir) in &self.romfs_contributions {
+            manifest.add(dir.clone(), namespace.into());
+        }
+
+        if let Some(base) = self.romfs_base() {
+            manifest.add_base(base, Utf8PathBuf::new());
+        }
+
+        Some(Ok(manifest))
+    }
+
+    /// List every file in the romfs as pairs of (source, destination), whether
+    /// it is staged or used directly from `romfs_dir`. A prebuilt romfs image
+    /// can't be listed, so it is an error.
+    pub(crate) fn romfs_contents(&self) -> Result<Vec<(Utf8PathBuf, Utf8PathBuf)>, String> {
+        if let Some(image) = &self.romfs_image {
+            return Err(format!(
+                "unable to list the files of the romfs image {image}"
+            ));
+        }
+
+        match self.staged_romfs_manifest() {
+            Some(manifest) => manifest?.files(),
+            None => {
+                let mut manifest = RomfsManifest::default();
+                let romfs = self.romfs_dir();
+                if romfs.is_dir() {
+                    manifest.add(romfs, Utf8PathBuf::new());
+                }
+                manifest.files()
+            }
+        }
+    }
+
+    /// Get the path to the record of the romfs files last sent to a device,
+    /// for listing the files which changed since.
+    pub(crate) fn path_romfs_deploy_state(&self) -> Utf8PathBuf {
+        self.path_output("romfs-deploy.json")
+    }
+
+    /// Stage the romfs to load separately from the `.3dsx` with `--romfs-loose`,
+    /// if it needs staging. This is done on every run, even when the `.3dsx`
+    /// is fresh, since it doesn't depend on the romfs files.
+    pub(crate) fn stage_loose_romfs(&self) -> Result<(), String> {
+        if let Some(manifest) = self.staged_romfs_manifest() {
+            let staged_romfs = self.path_staged_romfs();
+            info!("Staging RomFS into {staged_romfs} (--romfs-loose)");
+            manifest?.stage(&staged_romfs)?;
+        }
+        Ok(())
+    }
+
+    /// Get the romfs directory to load separately from the `.3dsx` with
+    /// `--romfs-loose`: the staged romfs if it needs staging, or the romfs
+    /// directory if it exists.
+    pub(crate) fn loose_romfs_dir(&self) -> Option<Utf8PathBuf> {
+        if self.romfs_image.is_some() {
+            None
+        } else if self.needs_staged_romfs() {
+            Some(self.path_staged_romfs())
+        } else {
+            Some(self.romfs_dir()).filter(|romfs| romfs.is_dir())
+        }
+    }
+
+    // as standard with the devkitPRO toolchain
+    const DEFAULT_AUTHOR: &'static str = "Unspecified Author";
+    const DEFAULT_DESCRIPTION: &'static str = "Homebrew Application";
+
+    // placeholders commonly used by homebrew CIA builds
+    const DEFAULT_TITLE_ID: &'static str = "000400000FF3FF00";
+    const DEFAULT_PRODUCT_CODE: &'static str = "CTR-P-CTAP";
+    const DEFAULT_COMPANY_CODE: &'static str = "00";
+
+    // the 3DS has 6 common keys, selected with `makerom -ckeyid`
+    const MAX_COMMON_KEY_INDEX: u8 = 5;
+
+    /// Check the built SMDH against `smdh_sha256`, if set.
+    pub(crate) fn check_smdh_hash(&self) -> Result<(), String> {
+        let Some(expected) = &self.smdh_sha256 else {
+            return Ok(());
+        };
+
+        let path = self.path_smdh();
+        let hash = sha256_file(&path)?;
+        if hash.eq_ignore_ascii_case(expected) {
+            Ok(())
+        } else {
+            Err(format!(
+                "{path} has SHA-256 {hash}, but `smdh_sha256` is {expected}"
+            ))
+        }
+    }
+
+    /// Get the short description for the SMDH, falling back to the executable's name.
+    fn short_description(&self) -> &str {
+        self.short_description.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Get the description for the SMDH, falling back to the devkitPro default.
+    fn description(&self) -> String {
+        self.description
+            .clone()
+            .unwrap_or_else(|| Self::DEFAULT_DESCRIPTION.to_string())
+    }
+
+    /// Get the publisher for the SMDH, made of all the authors joined by `", "`.
+    fn publisher(&self) -> String {
+        match &self.authors {
+            Some(authors) if !authors.is_empty() => authors.join(", "),
+            _ => Self::DEFAULT_AUTHOR.to_string(),
+        }
+    }
+
+    /// Check that the user-provided `makerom` codes are well-formed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message describing the expected format of each invalid field.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+
+    /// Get a message for each field set in this config which may only be set
+    /// for a single target, since it was configured in `source`, a table
+    /// shared by several targets.
+    fn misplaced_fields(&self, source: &ConfigSource) -> Vec<String> {
+        [
+            ("filename", self.filename.is_some()),
+            ("smdh_sha256", self.smdh_sha256.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(field, _)| {
+            format!(
+                "`{field}` is set in {source}, but can only be set for a single target, \
+                    e.g. in `[package.metadata.cargo-3ds.bin.<name>]`"
+            )
+        })
+        .collect()
+    }
+
+    /// Get a message for each invalid field of the config, see [`Self::validate`].
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.misplaced.clone();
+
+        if self.romfs_dir.is_some() && self.romfs_manifest.is_some() {
+            problems.push(String::from(
+                "only one of `romfs_dir` and `romfs_manifest` may be set",
+            ));
+        }
+
+        if let Some(Err(err)) = self.decoded_icon() {
+            problems.push(err);
+        }
+
+        if let Some(Err(err)) = self.assets_root.as_deref().map(expand_env) {
+            problems.push(format!("invalid `assets_root`: {err}"));
+        }
+
+        if let Err(err) = self.check_not_git() {
+            problems.push(err);
+        }
+
+        if self.workspace_root.is_none() {
+            for (field, path) in [
+                ("romfs_dir", &self.romfs_dir),
+                ("romfs_base", &self.romfs_base),
+            ] {
+                if let Some(path) = path.as_ref().filter(|path| path.as_str().starts_with("//")) {
+                    problems.push(format!(
+                        "`{field}` {path} is relative to the workspace root, \
+                        which isn't known outside of a cargo workspace"
+                    ));
+                }
+            }
+        }
+
+        if let Some(filename) = &self.filename {
+            if filename.is_empty() || filename.contains(['/', '\\']) {
+                problems.push(format!(
+                    "invalid `filename` {filename:?}: expected a file name without directories"
+                ));
+            }
+        }
+
+        for dest in self.romfs_files.iter().flat_map(BTreeMap::keys) {
+            if !romfs::is_romfs_path(dest) {
+                problems.push(format!(
+                    "invalid `romfs_files` destination `{dest}`: \
+                    expected a relative path within the romfs"
+                ));
+            }
+        }
+
+        if let Some(title_id) = &self.title_id {
+            if title_id.len() != 16 || !title_id.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(format!(
+                    "invalid `title_id` {title_id:?}: expected exactly 16 hexadecimal digits, \
+                    e.g. \"{}\"",
+                    Self::DEFAULT_TITLE_ID
+                ));
+            }
+        }
+
+        if let Some(hash) = &self.smdh_sha256 {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(format!(
+                    "invalid `smdh_sha256` {hash:?}: expected exactly 64 hexadecimal digits"
+                ));
+            }
+        }
+
+        if let Some(product_code) = &self.product_code {
+            if product_code.is_empty()
+                || product_code.len() > 16
+                || !product_code.chars().all(|c| c.is_ascii_graphic())
+            {
+                problems.push(format!(
+                    "invalid `product_code` {product_code:?}: expected 1 to 16 printable \
+                    ASCII characters without spaces, e.g. \"{}\"",
+                    Self::DEFAULT_PRODUCT_CODE
+                ));
+            }
+        }
+
+        if let Some(company_code) = &self.company_code {
+            if company_code.len() != 2 || !company_code.chars().all(|c| c.is_ascii_alphanumeric()) {
+                problems.push(format!(
+                    "invalid `company_code` {company_code:?}: expected exactly 2 ASCII \
+                    alphanumeric characters, e.g. \"{}\"",
+                    Self::DEFAULT_COMPANY_CODE
+                ));
+            }
+        }
+
+        match self.cia_dev_key_index {
+            Some(index) if index > Self::MAX_COMMON_KEY_INDEX => problems.push(format!(
+                "invalid `cia_dev_key_index` {index}: expected a common key index from 0 to {}",
+                Self::MAX_COMMON_KEY_INDEX
+            )),
+            Some(_) if self.cia_key_path.is_none() => problems.push(String::from(
+                "`cia_dev_key_index` requires the development keys supplied in `cia_key_path`",
+            )),
+            Some(_) => {}
+            None if self.cia_encrypted == Some(true) => problems.push(String::from(
+                "`cia_encrypted` requires signing the CIA for dev units with `cia_dev_key_index`",
+            )),
+            None => {}
+        }
+
+        problems
+    }
+
+    /// Get a message for each file referenced by the config which is missing or
+    /// invalid: the icon, and the sources of the romfs. Remote assets aren't
+    /// downloaded to be checked.
+    fn file_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.assets_root.is_some() && !self.assets_dir().is_dir() {
+            problems.push(format!("assets root {} does not exist", self.assets_dir()));
+        }
+
+        let icon = match &self.icon_path {
+            Some(path) if remote::is_remote(path) => None,
+            Some(path) => Some(self.assets_dir().join(path)),
+            // `icon_base64` is checked with the other fields
+            None if self.icon_base64.is_some() => None,
+            // The default icon from libctru is used if there is no `icon.png`
+            None => Some(self.manifest_dir.join("icon.png")).filter(|path| path.exists()),
+        };
+        if let Some(icon) = icon {
+            if let Err(err) = smdh::check_icon(&icon) {
+                problems.push(err);
+            }
+        }
+
+        if self.prebuilt_smdh() {
+            if let Err(err) = smdh::check_smdh(&self.path_smdh()) {
+                problems.push(err);
+            }
+        }
+
+        if let Some(path) = &self.cia_key_path {
+            let path = self.manifest_dir.join(path);
+            if !path.is_file() {
+                problems.push(format!("CIA key file {path} does not exist"));
+            }
+        }
+
+        for path in self.bundle_files.iter().flatten() {
+            if !self.manifest_dir.join(path).is_file() {
+                problems.push(format!(
+                    "bundle file {} does not exist",
+                    self.manifest_dir.join(path)
+                ));
+            }
+        }
+
+        // A prebuilt image replaces every other romfs setting
+        if let Some(image) = self.romfs_image() {
+            if let Err(err) = romfs::check_image(&image) {
+                problems.push(err);
+            }
+            return problems;
+        }
+
+        if let Some(base) = self.romfs_base().filter(|base| !base.is_dir()) {
+            problems.push(format!("RomFS base dir {base} does not exist"));
+            return problems;
+        }
+
+        if self.romfs_dir.as_deref().is_some_and(remote::is_remote) {
+            return problems;
+        }
+
+        if self.romfs_dir.is_some() && !self.romfs_dir().is_dir() {
+            problems.push(format!(
+                "RomFS dir {} does not exist, configured by `romfs_dir` in {}",
+                self.romfs_dir(),
+                self.romfs_dir_source
+            ));
+        } else if let Some(Err(err)) = self
+            .staged_romfs_manifest()
+            .map(|manifest| manifest.and_then(|manifest| manifest.files()))
+        {
+            problems.push(err);
+        }
+
+        problems
+    }
+
+    /// Builds the smdh using `smdhtool`.
+    /// This will fail if `smdhtool` is not in a directory found in $PATH. See [`tool_command`]
+    /// for the directory it is run in.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the text fields are too long for the SMDH and
+    /// `truncate` isn't set, if the icon doesn't exist, or if `smdhtool` can't
+    /// be run or fails.
+    pub fn build_smdh(&self, truncate: bool) -> Result<(), String> {
+        self.write_smdh(truncate).map_err(String::from)
+    }
+
+    /// Build the `.smdh` like [`Self::build_smdh`], keeping the exit code of
+    /// any error for the command line.
+    pub(crate) fn write_smdh(&self, truncate: bool) -> Result<(), PackageError> {
+        let fields = [
+            (
+                "short description",
+                self.short_description(),
+                smdh::SHORT_DESCRIPTION_LEN,
+            ),
+            (
+                "description",
+                &self.description(),
+                smdh::LONG_DESCRIPTION_LEN,
+            ),
+            ("publisher", &self.publisher(), smdh::PUBLISHER_LEN),
+        ]
+        .map(|(field, value, max_len)| smdh::fit_field(field, value, max_len, truncate));
+        let [short_description, description, publisher] = fields;
+        let (short_description, description, publisher) =
+            (short_description?, description?, publisher?);
+
+        // The decoded icon is reused if it's unchanged from a previous build
+        if let Some(icon) = self.decoded_icon() {
+            let path = self.path_decoded_icon();
+            let written = icon.and_then(|icon| {
+                write_if_changed(&path, &icon)
+                    .map_err(|err| format!("unable to write icon {path}: {err}"))
+            });
+            written?;
+        }
+
+        let icon_path = self
+            .icon_path()
+            .map_err(|err_path| format!("Icon at {err_path} does not exist"))?;
+
+        let mut command = tool_command("smdhtool", self);
+        command
+            .arg("--create")
+            .arg(short_description)
+            .arg(description)
+            .arg(publisher)
+            .arg(icon_path)
+            .arg(self.path_smdh())
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+
+        print_command(&command);
+        script::record(&command);
+
+        let _ = fs::remove_file(self.path_smdh());
+
+        let mut process = spawn_tool(&mut command)?;
+
+        let status = process.wait().unwrap();
+
+        if !status.success() {
+            return Err(PackageError::new(
+                format!("`smdhtool` failed ({status})"),
+                EXIT_TOOL,
+            ));
+        }
+
+        check_tool_output("smdhtool", &self.path_smdh())?;
+
+        if !self.flags.is_empty() {
+            self.flags.patch(&self.path_smdh())?;
+        }
+
+        Ok(())
+    }
+
+    /// Builds the 3dsx with `3dsxtool`, embedding the romfs and the `.smdh` from
+    /// [`Self::build_smdh`], as `cargo 3ds build` would.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the romfs can't be found or staged, or if `3dsxtool`
+    /// can't be run or fails.
+    pub fn build_3dsx(&self) -> Result<(), String> {
+        build_3dsx(self, false, None, true).map_err(String::from)
+    }
+
+    /// Whether the `.smdh` and `.3dsx` outputs are newer than all of their inputs:
+    /// the `.elf` executable, the icon, the package manifest, and every file in
+    /// the romfs directory. If any of the files can't be checked, the outputs are
+    /// assumed to be out of date.
+    ///
+    /// If `embed_romfs` is not set, the romfs files are not inputs, but the
+    /// `.3dsx` must also have been built without its romfs, and vice versa.
+    ///
+    /// The outputs must also have been built with the same settings, as recorded
+    /// with [`Self::write_build_fingerprint`].
+    pub(crate) fn is_fresh(&self, embed_romfs: bool, fingerprint: &str) -> bool {
+        if self.path_loose_romfs_marker().exists() == embed_romfs {
+            return false;
+        }
+
+        if fs::read_to_string(self.path_build_fingerprint())
+            .ok()
+            .as_deref()
+            != Some(fingerprint)
+        {
+            return false;
+        }
+
+        // A prebuilt SMDH is an input rather than an output
+        let outputs = if self.prebuilt_smdh() {
+            vec![self.path_3dsx()]
+        } else {
+            vec![self.path_smdh(), self.path_3dsx()]
+        };
+        let oldest_output = outputs
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()))
+            .collect::<io::Result<Vec<_>>>()
+            .ok()
+            .and_then(|times| times.into_iter().min());
+
+        let Some(oldest_output) = oldest_output else {
+            return false;
+        };
+
+        let Ok(icon_path) = self.icon_path() else {
+            return false;
+        };
+
+        let mut inputs = vec![
+            self.target_path.clone(),
+            icon_path,
+            self.manifest_dir.join("Cargo.toml"),
+        ];
+        if self.prebuilt_smdh() {
+            inputs.push(self.path_smdh());
+        }
+
+        // Directories are included too, to catch any removed files
+        let romfs = self.romfs_dir();
+        if let Some(image) = self.romfs_image() {
+            inputs.push(image);
+        } else if !embed_romfs {
+            // The romfs is loaded separately, so it doesn't affect the outputs
+        } else if let Some(manifest) = self.staged_romfs_manifest() {
+            match manifest.and_then(|manifest| manifest.files()) {
+                Ok(files) => inputs.extend(files.into_iter().map(|(src, _)| src)),
+                Err(_) => return false,
+            }
+            inputs.extend(self.romfs_manifest());
+            inputs.extend(self.romfs_base().filter(|base| base.is_dir()));
+            inputs.extend(self.romfs_contributions.iter().map(|(_, dir)| dir.clone()));
+            inputs.extend(Some(romfs.join(romfs::IGNORE_FILE)).filter(|ignore| ignore.is_file()));
+        } else if romfs.is_dir() {
+            match walk_files(&romfs) {
+                Ok(files) => inputs.extend(files),
+                Err(_) => return false,
+            }
+            inputs.push(romfs);
+        }
+
+        inputs.iter().all(|input| {
+            fs::metadata(input)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified < oldest_output)
+        })
+    }
+
+    /// Get the path to the icon to be used for the SMDH output.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the specified (or fallback) path does not exist.
+    /// The contained path is the path we tried to use.
+    fn icon_path(&self) -> Result<Utf8PathBuf, Utf8PathBuf> {
+        let path = if let Some(path) = &self.icon_path {
+            self.assets_dir().join(path)
+        } else if self.icon_base64.is_some() {
+            self.path_decoded_icon()
+        } else {
+            let path = self.manifest_dir.join("icon.png");
+            if path.exists() {
+                return Ok(path);
+            }
+
+            libctru_dir().join("default_icon.png")
+        };
+
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(path)
+        }
+    }
+}
+
+#[derive(Ord, PartialOrd, PartialEq, Eq, Debug)]
+pub struct CommitDate {
+    year: i32,
+    month: i32,
+    day: i32,
+}
+
+impl CommitDate {
+    fn parse(date: &str) -> Option<Self> {
+        let mut iter = date.split('-');
+
+        let year = iter.next()?.parse().ok()?;
+        let month = iter.next()?.parse().ok()?;
+        let day = iter.next()?.parse().ok()?;
+
+        Some(Self { year, month, day })
+    }
+
+    /// Get the same day `months` months later, which may not be a valid date
+    /// (e.g. February 31st), but still compares correctly.
+    const fn months_later(&self, months: i32) -> Self {
+        let month = self.month - 1 + months;
+        Self {
+            year: self.year + month / 12,
+            month: month % 12 + 1,
+            day: self.day,
+        }
+    }
+}
+
+impl fmt::Display for CommitDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+const MINIMUM_COMMIT_DATE: CommitDate = CommitDate {
+    year: 2023,
+    month: 5,
     day: 31,
 };
 const MINIMUM_RUSTC_VERSION: Version = Version::new(1, 70, 0);
+
+/// The newest nightly `cargo-3ds` was tested with, i.e. the newest nightly pinned
+/// in the `project-build` job of `.github/workflows/ci.yml`, which must be kept in
+/// sync. A warning is shown for nightlies more than a few months newer, which may
+/// have regressed.
+const LAST_TESTED_COMMIT_DATE: CommitDate = CommitDate {
+    year: 2026,
+    month: 9,
+    day: 1,
+};
+
+/// How many months newer than [`LAST_TESTED_COMMIT_DATE`] a nightly may be
+/// before it's considered untested.
+const UNTESTED_NIGHTLY_MONTHS: i32 = 3;
+
+/// The environment variable which hides the warning about untested nightlies.
+const ALLOW_UNTESTED_NIGHTLY_ENV: &str = "CARGO_3DS_ALLOW_UNTESTED_NIGHTLY";
+
+/// The environment variable which allows building with a non-nightly toolchain,
+/// like `--allow-stable`.
+const SKIP_TOOLCHAIN_CHECK_ENV: &str = "CARGO_3DS_SKIP_TOOLCHAIN_CHECK";
+
+/// The environment variable which may override the libctru install directory,
+/// like `--libctru-path`.
+const LIBCTRU_ENV: &str = "CARGO_3DS_LIBCTRU";
+
+/// The oldest versions of the devkitPro tools `cargo-3ds` supports, checked with
+/// `--tool-version-check`. `smdhtool` and `3dsxtool` are both provided by the
+/// `3dstools` package (<https://github.com/devkitPro/3dstools>), and versioned
+/// with it, and `3dslink` by its own (<https://github.com/devkitPro/3dslink>).
+///
+/// The arguments these must support are `--create` for `smdhtool`, `--smdh` and
+/// `--romfs` for `3dsxtool`, and `--address`, `--arg0`, `--retries` and `--server`
+/// for `3dslink`. The releases which introduced each of them aren't recorded, so
+/// these are conservative and older releases may work too. Raise them when
+/// passing an argument only newer releases support.
+const MINIMUM_TOOL_VERSIONS: [(&str, Version); 3] = [
+    ("smdhtool", Version::new(1, 3, 1)),
+    ("3dsxtool", Version::new(1, 3, 1)),
+    ("3dslink", Version::new(0, 6, 0)),
+];
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::command::Cargo;
+
+    /// The libctru to build with in tests, so that `DEVKITPRO` isn't needed.
+    const LIBCTRU_ARG: &str = "--libctru-path=/opt/devkitpro/libctru";
+
+    /// A temporary directory for a test, which is removed when dropped, even
+    /// if the test panics.
+    pub(crate) struct TestDir {
+        path: Utf8PathBuf,
+        _dir: tempfile::TempDir,
+    }
+
+    impl TestDir {
+        pub(crate) fn new() -> Self {
+            let dir = tempfile::Builder::new()
+                .prefix("cargo-3ds-")
+                .tempdir()
+                .unwrap();
+            Self {
+                path: Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap(),
+                _dir: dir,
+            }
+        }
+    }
+
+    impl std::ops::Deref for TestDir {
+        type Target = Utf8PathBuf;
+
+        fn deref(&self) -> &Utf8PathBuf {
+            &self.path
+        }
+    }
+
+    #[test]
+    fn custom_runner_args() {
+        let args = |input: &[&str], custom_runner| {
+            let Cargo::Input(Input { cmd, .. }) = Cargo::try_parse_from(input).unwrap();
+            cargo_command_args(&cmd, custom_runner)
+        };
+
+        assert_eq!(
+            args(&["cargo", "3ds", "run", "--", "--", "foo"], true),
+            ["--", "foo"]
+        );
+        assert_eq!(
+            args(
+                &["cargo", "3ds", "run", "--release", "--", "foo", "--", "bar"],
+                true
+            ),
+            ["--release", "--", "foo", "--", "bar"]
+        );
+
+        // Without a runner, the executable's arguments are passed by `3dslink`
+        assert!(args(&["cargo", "3ds", "run", "--", "--", "foo"], false).is_empty());
+        assert!(args(&["cargo", "3ds", "run"], true).is_empty());
+
+        // The `rustc` flags are passed through `cargo` with or without a runner
+        assert_eq!(
+            args(
+                &[
+                    "cargo",
+                    "3ds",
+                    "rustc",
+                    "--release",
+                    "--",
+                    "-C",
+                    "opt-level=s"
+                ],
+                false
+            ),
+            ["--release", "--", "-C", "opt-level=s"]
+        );
+    }
+
+    #[test]
+    fn tool_versions() {
+        for (output, expected) in [
+            (
+                "3dsxtool v1.3.1\nUsage: 3dsxtool input.elf output.3dsx",
+                Some((1, 3, 1)),
+            ),
+            ("smdhtool 2.0", Some((2, 0, 0))),
+            ("3dslink version 0.6.2, built 2023-09-18", Some((0, 6, 2))),
+            ("Usage: 3dslink [options] nds file", None),
+            ("", None),
+        ] {
+            let expected = expected.map(|(major, minor, patch)| Version::new(major, minor, patch));
+            assert_eq!(parse_tool_version(output), expected, "{output}");
+        }
+    }
+
+    #[test]
+    fn target_cpu_rustflags() {
+        let Cargo::Input(input) = Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            "build",
+            "--target-cpu",
+            "mpcore",
+            LIBCTRU_ARG,
+        ])
+        .unwrap();
+        let command = make_cargo_command(&input, &None, Some("ctru"));
+
+        // Depending on the environment, the flags are either in an env var or `--config`
+        let flags: Vec<_> = command
+            .get_envs()
+            .filter_map(|(_, value)| value)
+            .chain(command.get_args())
+            .map(|flags| flags.to_string_lossy().into_owned())
+            .collect();
+        assert!(
+            flags
+                .iter()
+                .any(|flags| flags.contains("-Ctarget-cpu=mpcore") && flags.contains("-lctru")),
+            "{flags:?}"
+        );
+    }
+
+    #[test]
+    fn config_rustflags() {
+        let parse = |value: &str| {
+            let table: toml::Table = toml::from_str(&format!("rustflags = {value}")).unwrap();
+            parse_config_rustflags(&table["rustflags"])
+        };
+
+        assert_eq!(
+            parse(r#""-Cdebuginfo=0  -Z macro-backtrace""#),
+            ["-Cdebuginfo=0", "-Z", "macro-backtrace"]
+        );
+        assert_eq!(parse(r#"["-C", "opt-level=s"]"#), ["-C", "opt-level=s"]);
+        assert!(parse("[]").is_empty());
+    }
+
+    #[test]
+    fn target_rustflags_precedence() {
+        let cfgs: Vec<Cfg> = ["unix", "target_os=\"horizon\"", "target_arch=\"arm\""]
+            .iter()
+            .map(|cfg| cfg.parse().unwrap())
+            .collect();
+        let has_rustflags = |config: &str| {
+            let config: toml::Table = toml::from_str(config).unwrap();
+            has_target_rustflags(config["target"].as_table().unwrap(), &cfgs)
+        };
+
+        assert!(has_rustflags(
+            "[target.armv6k-nintendo-3ds]\nrustflags = [\"-Cdebuginfo=0\"]"
+        ));
+        assert!(has_rustflags(
+            "[target.'cfg(target_os = \"horizon\")']\nrustflags = [\"-Cdebuginfo=0\"]"
+        ));
+        assert!(has_rustflags(
+            "[target.'cfg(all(unix, not(windows)))']\nrustflags = \"-Cdebuginfo=0\""
+        ));
+
+        // Tables for other targets, or without rustflags, leave `build.rustflags` in use
+        assert!(!has_rustflags(
+            "[target.x86_64-unknown-linux-gnu]\nrustflags = [\"-Cdebuginfo=0\"]"
+        ));
+        assert!(!has_rustflags(
+            "[target.'cfg(windows)']\nrustflags = [\"-Cdebuginfo=0\"]"
+        ));
+        assert!(!has_rustflags(
+            "[target.'cfg(target_os = \"horizon\")']\nrunner = \"3dslink\""
+        ));
+    }
+
+    #[test]
+    fn deterministic_rustflags() {
+        let command = || {
+            let Cargo::Input(input) = Cargo::try_parse_from([
+                "cargo",
+                "3ds",
+                "--target-cpu",
+                "mpcore",
+                LIBCTRU_ARG,
+                "build",
+                "--release",
+            ])
+            .unwrap();
+            let command = make_cargo_command(&input, &None, Some("ctrud"));
+
+            let envs: Vec<_> = command
+                .get_envs()
+                .map(|(key, value)| (key.to_owned(), value.map(OsStr::to_owned)))
+                .collect();
+            let args: Vec<_> = command.get_args().map(OsStr::to_owned).collect();
+            (envs, args)
+        };
+
+        assert_eq!(command(), command());
+    }
+
+    #[test]
+    fn rustc_wrapper_untouched() {
+        let Cargo::Input(input) = Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            "--config",
+            "build.rustc-wrapper=\"sccache\"",
+            LIBCTRU_ARG,
+            "build",
+        ])
+        .unwrap();
+        let command = make_cargo_command(&input, &None, Some("ctru"));
+
+        // The wrapper reaches cargo as configured, and is neither set nor removed
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(args[0], "--config=build.rustc-wrapper=\"sccache\"");
+        assert!(!command
+            .get_envs()
+            .any(|(key, _)| key == "RUSTC_WRAPPER" || key == "RUSTC_WORKSPACE_WRAPPER"));
+
+        // Only the flags for the 3DS target are added, in the order they're needed
+        let rustflags: Vec<_> = command
+            .get_envs()
+            .filter_map(|(_, value)| value)
+            .chain(command.get_args())
+            .map(|flags| flags.to_string_lossy().into_owned())
+            .filter(|flags| flags.contains("-L/opt/devkitpro/libctru/lib"))
+            .collect();
+        let [flags] = &rustflags[..] else {
+            panic!("{rustflags:?}");
+        };
+        assert!(
+            flags.find("-L/opt/devkitpro/libctru/lib").unwrap() < flags.find("-lctru").unwrap(),
+            "{flags}"
+        );
+    }
+
+    #[test]
+    fn passed_target_once() {
+        for args in [
+            &["build"][..],
+            &["build", "--", "--target", "armv6k-nintendo-3ds"],
+            &["check", "--target=armv6k-nintendo-3ds"],
+        ] {
+            let Cargo::Input(input) =
+                Cargo::try_parse_from(["cargo", "3ds", LIBCTRU_ARG].iter().chain(args)).unwrap();
+            let command = make_cargo_command(&input, &None, None);
+
+            let targets = command
+                .get_args()
+                .filter(|arg| arg.to_string_lossy().starts_with("--target"))
+                .count();
+            assert_eq!(targets, 1, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn gdb_attach_command() {
+        let config = CTRConfig {
+            target_path: Utf8PathBuf::from("target/armv6k-nintendo-3ds/debug/app.elf"),
+            ..Default::default()
+        };
+
+        let (_, device) = quote_command(&gdb_command(&config, "192.168.0.2:4003", true));
+        assert!(device.ends_with(
+            "-q target/armv6k-nintendo-3ds/debug/app.elf \
+            -ex 'set tcp connect-timeout 30' -ex 'target extended-remote 192.168.0.2:4003'"
+        ));
+
+        let (_, emulator) = quote_command(&gdb_command(&config, "localhost:24689", false));
+        assert!(emulator.ends_with("-ex 'target remote localhost:24689'"));
+    }
+
+    #[test]
+    fn emulator_screenshot() {
+        let parse = |args: &[&str]| {
+            Cargo::try_parse_from(["cargo", "3ds", "run"].iter().chain(args).copied())
+        };
+
+        assert!(parse(&["--screenshot-delay", "2"]).is_err());
+        assert!(parse(&["--device", "--capture-screenshot", "out.png"]).is_err());
+
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(run),
+            ..
+        }) = parse(&["--emulator", "--capture-screenshot", "out.png"]).unwrap()
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+        assert!(!run.use_custom_runner());
+
+        let (_, command) = emulator_command(&CTRConfig::default(), &run);
+        let (envs, _) = quote_command(&command);
+        assert!(envs.contains(&String::from("CARGO_3DS_SCREENSHOT=out.png")));
+        assert!(envs.contains(&String::from("CARGO_3DS_SCREENSHOT_DELAY=5")));
+    }
+
+    #[test]
+    fn emulator_console() {
+        let run = |args: &[&str]| {
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Run(run),
+                ..
+            }) =
+                Cargo::try_parse_from(["cargo", "3ds", "run"].iter().chain(args).copied()).unwrap()
+            else {
+                panic!("parsed as something other than `run` subcommand")
+            };
+            let (_, command) = emulator_command(&CTRConfig::default(), &run);
+            quote_command(&command).0
+        };
+
+        let show_console = String::from("CARGO_3DS_SHOW_CONSOLE=1");
+        assert!(run(&["--emulator", "--show-console"]).contains(&show_console));
+        assert!(!run(&["--emulator"]).contains(&show_console));
+        assert!(
+            Cargo::try_parse_from(["cargo", "3ds", "run", "--device", "--show-console"]).is_err()
+        );
+    }
+
+    #[test]
+    fn color_passed_to_cargo() {
+        let Cargo::Input(input) =
+            Cargo::try_parse_from(["cargo", "3ds", "--host", "build", "--color", "always"])
+                .unwrap();
+        let command = make_cargo_command(&input, &None, None);
+
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert!(
+            args.windows(2).any(|args| args == ["--color", "always"]),
+            "{args:?}"
+        );
+    }
+
+    #[test]
+    fn host_mode() {
+        let Cargo::Input(input) =
+            Cargo::try_parse_from(["cargo", "3ds", "--host", "clippy", "--release"]).unwrap();
+        let command = make_cargo_command(&input, &None, Some("ctru"));
+
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(args[0], "clippy");
+        assert!(!args.iter().any(|arg| arg == "--target"
+            || arg.starts_with("build-std")
+            || arg.contains("armv6k-nintendo-3ds")));
+        assert!(args.contains(&String::from("--release")));
+    }
+
+    #[test]
+    fn sysroot_override() {
+        let dir = TestDir::new();
+        let sysroot = dir.as_std_path();
+        fs::create_dir_all(sysroot.join("lib/rustlib/armv6k-nintendo-3ds")).unwrap();
+
+        let uses_build_std = |sysroot: &Path| {
+            let Cargo::Input(input) = Cargo::try_parse_from(
+                ["cargo", "3ds", LIBCTRU_ARG, "build", "--sysroot"]
+                    .map(OsStr::new)
+                    .into_iter()
+                    .chain([sysroot.as_os_str()]),
+            )
+            .unwrap();
+            assert_eq!(find_sysroot(input.sysroot.as_deref()), sysroot);
+
+            make_cargo_command(&input, &None, None)
+                .get_args()
+                .any(|arg| arg.to_string_lossy().starts_with("build-std="))
+        };
+
+        assert!(!uses_build_std(sysroot));
+        assert!(uses_build_std(&sysroot.join("missing")));
+    }
+
+    #[test]
+    fn manifest_path() {
+        let dir = TestDir::new();
+        fs::create_dir_all(dir.join("fixture/src")).unwrap();
+        fs::write(
+            dir.join("fixture/Cargo.toml"),
+            "[package]\n\
+            name = \"fixture\"\n\
+            version = \"0.1.0\"\n\
+            edition = \"2021\"\n\
+            \n\
+            [package.metadata.cargo-3ds]\n\
+            romfs_dir = \"assets\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("fixture/src/main.rs"), "fn main() {}\n").unwrap();
+        let manifest_path = dir.join("fixture/Cargo.toml");
+
+        let Cargo::Input(input) = Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            LIBCTRU_ARG,
+            "build",
+            "--manifest-path",
+            manifest_path.as_str(),
+        ])
+        .unwrap();
+        assert_eq!(input.manifest_path(), Some(manifest_path.clone()));
+        let args: Vec<_> = make_cargo_command(&input, &None, None)
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let build = args.iter().position(|arg| arg == "build").unwrap();
+        assert_eq!(
+            args[build..build + 3],
+            ["build", "--manifest-path", manifest_path.as_str()]
+        );
+
+        // The package is found from the manifest rather than the current directory,
+        // and its paths are resolved from its own directory
+        let metadata = metadata_command(Some(&manifest_path)).exec().unwrap();
+        let package = metadata.root_package().unwrap();
+        assert_eq!(package.name, "fixture");
+        let config = existing_artifact_config(
+            package,
+            "bin",
+            "fixture",
+            BuildType::Debug,
+            None,
+            None,
+            Utf8PathBuf::new(),
+        );
+        assert_eq!(config.romfs_dir(), dir.join("fixture/assets"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn server_timeout() {
+        let start = Instant::now();
+        let process = Command::new("sleep").arg("30").spawn().unwrap();
+        let status = wait_3dslink(process, None, None, Some(Duration::from_millis(200)));
+        assert!(status.success());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // A process which exits by itself keeps its own status
+        let process = Command::new("false").spawn().unwrap();
+        assert!(!wait_3dslink(process, None, None, Some(Duration::from_secs(10))).success());
+    }
+
+    #[test]
+    fn executable_exit_code() {
+        let scan = |output: &str| {
+            let mut scanner = ExitCodeScanner::default();
+            io::Write::write_all(&mut scanner, output.as_bytes()).unwrap();
+            scanner.finish()
+        };
+
+        assert_eq!(scan("hello\n"), None);
+        assert_eq!(scan("running\ncargo-3ds: exit code 0\n"), Some(0));
+        assert_eq!(scan("cargo-3ds: exit code 3"), Some(3));
+        assert_eq!(
+            scan("cargo-3ds: exit code 2\ncargo-3ds: exit code 0\n"),
+            Some(2)
+        );
+
+        // Only the marker counts, not what a test harness prints
+        assert_eq!(scan("test result: FAILED. 1 passed; 1 failed\n"), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn server_exit_code() {
+        let spawn = |script: &str| {
+            Command::new("sh")
+                .args(["-c", script])
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        };
+
+        let server = || Some(ExitCodeScanner::default());
+
+        let status = wait_3dslink(spawn("echo 'cargo-3ds: exit code 5'"), None, server(), None);
+        assert_eq!(status.code(), Some(5));
+
+        // A nonzero exit code is never truncated to a success
+        let status = wait_3dslink(
+            spawn("echo 'cargo-3ds: exit code 256'"),
+            None,
+            server(),
+            None,
+        );
+        assert!(!status.success());
+
+        // Without the server, the output isn't from the executable
+        let status = wait_3dslink(spawn("echo 'cargo-3ds: exit code 5'"), None, None, None);
+        assert!(status.success());
+
+        // A failure of 3dslink itself takes precedence
+        let status = wait_3dslink(
+            spawn("echo 'cargo-3ds: exit code 5'; exit 1"),
+            None,
+            server(),
+            None,
+        );
+        assert_eq!(status.code(), Some(1));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn probe_output_timeout() {
+        let start = Instant::now();
+        let err = output_with_timeout(Command::new("sleep").arg("30"), Duration::from_millis(200))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let output = output_with_timeout(
+            Command::new("sh").args(["-c", "echo out; echo err >&2; exit 3"]),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[test]
+    fn staging_dir_cleanup() {
+        let dir = TestDir::new();
+        drop(StagingDir::new(dir.clone(), true));
+        assert!(dir.is_dir());
+
+        drop(StagingDir::new(dir.clone(), false));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn validate_makerom_codes() {
+        let valid = CTRConfig {
+            title_id: Some("000400000ff3ff00".into()),
+            product_code: Some("CTR-P-ABCD".into()),
+            company_code: Some("01".into()),
+            ..Default::default()
+        };
+        assert_eq!(valid.validate(), Ok(()));
+        assert_eq!(CTRConfig::default().validate(), Ok(()));
+
+        for invalid in [
+            CTRConfig {
+                title_id: Some("0004000".into()),
+                ..Default::default()
+            },
+            CTRConfig {
+                title_id: Some("000400000FF3FFXX".into()),
+                ..Default::default()
+            },
+            CTRConfig {
+                product_code: Some("CTR-P-ABCDEFGHIJKL".into()),
+                ..Default::default()
+            },
+            CTRConfig {
+                product_code: Some("CTR P".into()),
+                ..Default::default()
+            },
+            CTRConfig {
+                company_code: Some("001".into()),
+                ..Default::default()
+            },
+            CTRConfig {
+                cia_encrypted: Some(true),
+                ..Default::default()
+            },
+            CTRConfig {
+                cia_dev_key_index: Some(1),
+                ..Default::default()
+            },
+            CTRConfig {
+                cia_dev_key_index: Some(6),
+                cia_key_path: Some("keys.bin".into()),
+                ..Default::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{invalid:?}");
+        }
+
+        let signed = CTRConfig {
+            cia_encrypted: Some(true),
+            cia_dev_key_index: Some(1),
+            cia_key_path: Some("keys.bin".into()),
+            ..Default::default()
+        };
+        assert_eq!(signed.validate(), Ok(()));
+    }
+
+    #[test]
+    fn target_config_overrides_package() {
+        let metadata = PackageMetadata::deserialize(serde_json::json!({
+            "romfs_dir": "romfs",
+            "description": "default description",
+            "example": {
+                "hello-world": { "romfs-dir": "examples/romfs" },
+            },
+        }))
+        .unwrap();
+
+        let config = metadata.target_config("example", "hello-world", BuildType::Debug, None);
+        assert_eq!(config.romfs_dir.as_deref(), Some("examples/romfs".into()));
+        assert_eq!(config.description.as_deref(), Some("default description"));
+        assert_eq!(config.package_romfs_dir.as_deref(), Some("romfs".into()));
+        assert_eq!(
+            config.romfs_dir_source.to_string(),
+            "`[package.metadata.cargo-3ds.example.hello-world]`"
+        );
+
+        let metadata = PackageMetadata::deserialize(serde_json::json!({
+            "romfs_dir": "romfs",
+        }))
+        .unwrap();
+
+        let config = metadata.target_config("bin", "app", BuildType::Debug, None);
+        assert_eq!(config.romfs_dir.as_deref(), Some("romfs".into()));
+        assert_eq!(config.romfs_dir_source, ConfigSource::Package);
+    }
+
+    #[test]
+    fn romfs_files() {
+        let dir = TestDir::new();
+        for file in ["romfs/data.bin", "LICENSE"] {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let metadata = PackageMetadata::deserialize(serde_json::json!({
+            "romfs_files": { "legal/LICENSE": "LICENSE" },
+        }))
+        .unwrap();
+        let config = CTRConfig {
+            manifest_dir: dir.clone(),
+            ..metadata.target_config("bin", "app", BuildType::Debug, None)
+        };
+        assert!(config.validate().is_ok());
+
+        // The files are added on top of the default romfs directory
+        let mut dests: Vec<_> = config
+            .staged_romfs_manifest()
+            .unwrap()
+            .and_then(|manifest| manifest.files())
+            .unwrap()
+            .into_iter()
+            .map(|(_, dest)| dest)
+            .collect();
+        dests.sort();
+        assert_eq!(dests, ["data.bin", "legal/LICENSE"]);
+        assert_eq!(config.loose_romfs_dir(), Some(config.path_staged_romfs()));
+
+        let invalid = PackageMetadata::deserialize(serde_json::json!({
+            "romfs_files": { "../LICENSE": "LICENSE" },
+        }))
+        .unwrap()
+        .target_config("bin", "app", BuildType::Debug, None);
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn prebuilt_smdh() {
+        let dir = TestDir::new();
+
+        let mut data = vec![0; smdh::SMDH_SIZE];
+        data[..4].copy_from_slice(smdh::MAGIC);
+        fs::write(dir.join("custom.smdh"), &data).unwrap();
+        fs::write(dir.join("short.smdh"), &data[..0x100]).unwrap();
+
+        let config = |smdh_path: Option<&str>| CTRConfig {
+            target_path: dir.join("target/app.elf"),
+            manifest_dir: dir.clone(),
+            smdh_path: smdh_path.map(Utf8PathBuf::from),
+            ..Default::default()
+        };
+
+        assert!(!config(None).prebuilt_smdh());
+        assert_eq!(config(None).path_smdh(), dir.join("target/app.smdh"));
+        assert_eq!(
+            config(Some("custom.smdh")).path_smdh(),
+            dir.join("custom.smdh")
+        );
+
+        assert!(config(Some("custom.smdh")).file_problems().is_empty());
+        assert_eq!(config(Some("short.smdh")).file_problems().len(), 1);
+        assert_eq!(config(Some("missing.smdh")).file_problems().len(), 1);
+        fs::write(dir.join("not.smdh"), b"PNG").unwrap();
+        assert_eq!(config(Some("not.smdh")).file_problems().len(), 1);
+    }
+
+    #[test]
+    fn file_problems() {
+        let dir = TestDir::new();
+        fs::create_dir_all(dir.join("romfs")).unwrap();
+
+        let mut icon = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        icon.extend(48u32.to_be_bytes());
+        icon.extend(48u32.to_be_bytes());
+        fs::write(dir.join("icon.png"), &icon).unwrap();
+        fs::write(dir.join("small.png"), &icon[..20]).unwrap();
+
+        let config = |metadata| CTRConfig {
+            manifest_dir: dir.clone(),
+            ..PackageMetadata::deserialize(metadata)
+                .unwrap()
+                .target_config("bin", "app", BuildType::Debug, None)
+        };
+
+        assert!(config(serde_json::json!({})).file_problems().is_empty());
+        assert!(config(serde_json::json!({ "romfs_dir": "romfs" }))
+            .file_problems()
+            .is_empty());
+
+        // Every problem is reported, not only the first
+        let problems = config(serde_json::json!({
+            "icon_path": "small.png",
+            "romfs_dir": "missing",
+        }))
+        .file_problems();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+
+        let problems = config(serde_json::json!({
+            "icon_path": "missing.png",
+            "romfs_files": { "data.bin": "missing.bin" },
+        }))
+        .file_problems();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+
+        let keys = config(serde_json::json!({ "cia_key_path": "missing-keys.bin" }));
+        assert_eq!(keys.file_problems().len(), 1);
+    }
+
+    #[test]
+    fn workspace_romfs_dir() {
+        let config = |romfs_dir: &str, workspace_root: Option<&str>| CTRConfig {
+            romfs_dir: Some(romfs_dir.into()),
+            manifest_dir: "/workspace/crates/app".into(),
+            workspace_root: workspace_root.map(Into::into),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config("//assets/romfs", Some("/workspace")).romfs_dir(),
+            "/workspace/assets/romfs"
+        );
+        assert_eq!(
+            config("romfs", Some("/workspace")).romfs_dir(),
+            "/workspace/crates/app/romfs"
+        );
+        assert_eq!(
+            config("../shared", Some("/workspace")).romfs_dir(),
+            "/workspace/crates/app/../shared"
+        );
+        assert!(config("//assets/romfs", Some("/workspace"))
+            .validate()
+            .is_ok());
+        assert!(config("//assets/romfs", None).validate().is_err());
+    }
+
+    #[test]
+    fn assets_root() {
+        env::set_var("CARGO_3DS_TEST_ASSETS", "/monorepo/assets");
+        env::remove_var("CARGO_3DS_TEST_UNSET");
+
+        let config = |assets_root: Option<&str>| CTRConfig {
+            assets_root: assets_root.map(String::from),
+            icon_path: Some("app/icon.png".into()),
+            romfs_dir: Some("app/romfs".into()),
+            manifest_dir: "/monorepo/crates/app".into(),
+            workspace_root: Some("/monorepo".into()),
+            ..Default::default()
+        };
+
+        let config_from_env = config(Some("${CARGO_3DS_TEST_ASSETS}/3ds"));
+        assert!(config_from_env.validate().is_ok());
+        assert_eq!(
+            config_from_env.romfs_dir(),
+            "/monorepo/assets/3ds/app/romfs"
+        );
+        assert_eq!(
+            config_from_env.icon_path(),
+            Err("/monorepo/assets/3ds/app/icon.png".into())
+        );
+
+        // A relative root is resolved from the package, and `//` from the workspace
+        assert_eq!(
+            config(Some("../../assets")).romfs_dir(),
+            "/monorepo/crates/app/../../assets/app/romfs"
+        );
+        let workspace_romfs = CTRConfig {
+            romfs_dir: Some("//shared/romfs".into()),
+            ..config(Some("/monorepo/assets"))
+        };
+        assert_eq!(workspace_romfs.romfs_dir(), "/monorepo/shared/romfs");
+
+        // Without a root, paths are relative to the package as usual
+        assert_eq!(config(None).romfs_dir(), "/monorepo/crates/app/app/romfs");
+
+        assert!(config(Some("${CARGO_3DS_TEST_UNSET}")).validate().is_err());
+        assert!(config(Some("${CARGO_3DS_TEST_ASSETS")).validate().is_err());
+    }
+
+    #[test]
+    fn artifact_dir_outputs() {
+        let dir = TestDir::new();
+        fs::create_dir_all(dir.join("debug")).unwrap();
+        fs::write(dir.join("debug/app.3dsx"), b"3DSX").unwrap();
+        fs::write(dir.join("debug/app.smdh"), b"SMDH").unwrap();
+
+        let config = CTRConfig {
+            target_path: dir.join("debug/app.elf"),
+            ..Default::default()
+        };
+        copy_outputs(&config, &dir.join("out"));
+
+        assert_eq!(fs::read(dir.join("out/app.3dsx")).unwrap(), b"3DSX");
+        assert_eq!(fs::read(dir.join("out/app.smdh")).unwrap(), b"SMDH");
+        assert!(!dir.join("out/app.elf").exists());
+    }
+
+    #[test]
+    fn base64_icon() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVs\n bG8").unwrap(), b"hello");
+        assert!(decode_base64("aGVsb").is_err());
+        assert!(decode_base64("aG=Vs").is_err());
+        assert!(decode_base64("aGV*").is_err());
+
+        // The PNG signature and header of a 48x48 and a 16x16 image
+        let config = |icon_base64: &str, icon_path: Option<&str>| CTRConfig {
+            icon_base64: Some(icon_base64.to_string()),
+            icon_path: icon_path.map(Into::into),
+            target_path: "/target/app.elf".into(),
+            ..Default::default()
+        };
+        let valid = config("iVBORw0KGgoAAAANSUhEUgAAADAAAAAw", None);
+        assert!(valid.validate().is_ok());
+        assert_eq!(valid.icon_path().unwrap_err(), "/target/app.icon.png");
+
+        assert_eq!(
+            config("iVBORw0KGgoAAAANSUhEUgAAABAAAAAQ", None).validate(),
+            Err(String::from(
+                "invalid `icon_base64`: must be 48x48 pixels, got 16x16"
+            ))
+        );
+        // `icon_path` takes precedence, so the icon isn't decoded
+        assert!(config("iVBORw0KGgoAAAANSUhEUgAAABAAAAAQ", Some("icon.png"))
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn transient_metadata_errors() {
+        let cargo_error = |stderr: &str| cargo_metadata::Error::CargoMetadata {
+            stderr: stderr.to_string(),
+        };
+
+        assert!(is_transient_metadata_error(&cargo_error(
+            "error: failed to download from `https://index.crates.io/...`\n\nCaused by:\n  [28] Timeout was reached"
+        )));
+        assert!(is_transient_metadata_error(&cargo_error(
+            "warning: spurious network error (3 tries remaining): [6] Couldn't resolve host name"
+        )));
+        assert!(!is_transient_metadata_error(&cargo_error(
+            "error: failed to parse manifest at `/project/Cargo.toml`"
+        )));
+        assert!(!is_transient_metadata_error(&cargo_metadata::Error::NoJson));
+        assert!(!is_transient_metadata_error(&cargo_metadata::Error::Io(
+            io::ErrorKind::NotFound.into()
+        )));
+        assert!(!is_transient_metadata_error(&cargo_metadata::Error::Io(
+            io::ErrorKind::TimedOut.into()
+        )));
+    }
+
+    #[test]
+    fn profile_overrides_target() {
+        let metadata = || {
+            PackageMetadata::deserialize(serde_json::json!({
+                "icon_path": "icon.png",
+                "bin": {
+                    "app": { "icon_path": "app.png", "romfs_dir": "app-romfs" },
+                },
+                "profile": {
+                    "release-dist": { "icon_path": "dist.png" },
+                },
+            }))
+            .unwrap()
+        };
+
+        let config =
+            metadata().target_config("bin", "app", BuildType::Release, Some("release-dist"));
+        assert_eq!(config.icon_path.as_deref(), Some("dist.png".into()));
+        assert_eq!(config.romfs_dir.as_deref(), Some("app-romfs".into()));
+
+        let config = metadata().target_config("bin", "app", BuildType::Debug, None);
+        assert_eq!(config.icon_path.as_deref(), Some("app.png".into()));
+    }
+
+    #[test]
+    fn config_file_overrides_metadata() {
+        let dir = TestDir::new();
+        let path = dir.join("ci.toml");
+        fs::write(
+            &path,
+            "icon_path = \"ci.png\"\nromfs_dir = \"/abs/romfs\"\n[romfs_files]\n\"a.bin\" = \"a.bin\"\n",
+        )
+        .unwrap();
+
+        let metadata = PackageMetadata::deserialize(serde_json::json!({
+            "description": "Package",
+            "profile": {
+                "release-dist": { "icon_path": "dist.png" },
+            },
+        }))
+        .unwrap()
+        .with_config_file(Some(&path));
+        let config = metadata.target_config("bin", "app", BuildType::Release, Some("release-dist"));
+
+        // Relative paths are resolved from the directory of the config file
+        let dir = dir.canonicalize_utf8().unwrap();
+        assert_eq!(config.icon_path, Some(dir.join("ci.png")));
+        assert_eq!(config.romfs_dir.as_deref(), Some("/abs/romfs".into()));
+        assert_eq!(
+            config.romfs_files.unwrap()[Utf8Path::new("a.bin")],
+            dir.join("a.bin")
+        );
+        assert_eq!(config.romfs_dir_source, ConfigSource::File(path.clone()));
+        assert_eq!(config.description.as_deref(), Some("Package"));
+
+        fs::write(dir.join("bad.toml"), "icon_path = 1").unwrap();
+        assert!(PackageMetadata::load(&dir.join("bad.toml")).is_err());
+        assert!(PackageMetadata::load(&dir.join("missing.toml")).is_err());
+
+        // The file has the same tables as the package metadata
+        fs::write(
+            &path,
+            "[release]\ndescription = \"Release\"\n\
+            [example.demo]\nicon_path = \"demo.png\"\n\
+            [profile.ci]\nauthors = [\"CI\"]\n",
+        )
+        .unwrap();
+        let metadata = || {
+            PackageMetadata::deserialize(serde_json::json!({ "description": "Package" }))
+                .unwrap()
+                .with_config_file(Some(&path))
+        };
+        let config = metadata().target_config("example", "demo", BuildType::Release, Some("ci"));
+        assert_eq!(config.description.as_deref(), Some("Release"));
+        assert_eq!(config.icon_path, Some(dir.join("demo.png")));
+        assert_eq!(config.authors, Some(vec![String::from("CI")]));
+        let config = metadata().target_config("bin", "app", BuildType::Debug, None);
+        assert_eq!(config.description.as_deref(), Some("Package"));
+        assert_eq!(config.icon_path, None);
+    }
+
+    #[test]
+    fn build_type_overrides() {
+        let metadata = || {
+            PackageMetadata::deserialize(serde_json::json!({
+                "description": "An app",
+                "icon_path": "icon.png",
+                "debug": { "description": "An app [dev]" },
+                "release": { "icon_path": "release.png" },
+                "bin": {
+                    "app": { "icon_path": "app.png" },
+                },
+            }))
+            .unwrap()
+        };
+
+        let config = metadata().target_config("bin", "app", BuildType::Debug, None);
+        assert_eq!(config.description.as_deref(), Some("An app [dev]"));
+        assert_eq!(config.icon_path.as_deref(), Some("app.png".into()));
+
+        let config = metadata().target_config("bin", "other", BuildType::Release, None);
+        assert_eq!(config.description.as_deref(), Some("An app"));
+        assert_eq!(config.icon_path.as_deref(), Some("release.png".into()));
+    }
+
+    #[test]
+    fn freshness() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = TestDir::new();
+        fs::create_dir_all(dir.join("romfs")).unwrap();
+
+        let config = CTRConfig {
+            icon_path: Some(dir.join("icon.png")),
+            manifest_dir: dir.clone(),
+            target_path: dir.join("app.elf"),
+            ..Default::default()
+        };
+
+        let touch = |path: Utf8PathBuf, secs_ago: u64| {
+            let file = File::create(path).unwrap();
+            let time = SystemTime::now() - Duration::from_secs(secs_ago);
+            file.set_modified(time).unwrap();
+        };
+
+        for input in ["app.elf", "icon.png", "Cargo.toml", "romfs/data.bin"] {
+            touch(dir.join(input), 60);
+        }
+        let romfs = File::open(dir.join("romfs")).unwrap();
+        romfs
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        let fingerprint = config.build_fingerprint("");
+        assert!(!config.is_fresh(true, &fingerprint));
+
+        touch(config.path_smdh(), 30);
+        touch(config.path_3dsx(), 30);
+        // Outputs without a recorded fingerprint may have been built with other settings
+        assert!(!config.is_fresh(true, &fingerprint));
+        config.write_build_fingerprint(&fingerprint).unwrap();
+        assert!(config.is_fresh(true, &fingerprint));
+        assert!(!config.is_fresh(false, &fingerprint));
+
+        // Any change to the settings makes the outputs stale
+        assert!(!config.is_fresh(true, &config.build_fingerprint("--truncate-smdh")));
+
+        touch(dir.join("romfs/data.bin"), 0);
+        assert!(!config.is_fresh(true, &fingerprint));
+
+        // A 3dsx built without its romfs doesn't depend on the romfs files
+        touch(config.path_loose_romfs_marker(), 30);
+        assert!(config.is_fresh(false, &fingerprint));
+        assert!(!config.is_fresh(true, &fingerprint));
+
+        config.clear_build_fingerprint();
+        assert!(!config.is_fresh(false, &fingerprint));
+    }
+
+    #[test]
+    fn package_defaults() {
+        let package = |metadata: serde_json::Value| -> Package {
+            serde_json::from_value(serde_json::json!({
+                "name": "foo",
+                "version": "0.1.0",
+                "id": "foo 0.1.0 (path+file:///project)",
+                "authors": ["Jane Doe <jane@example.com>", "John Doe"],
+                "description": "A 3DS app",
+                "dependencies": [],
+                "targets": [],
+                "features": {},
+                "manifest_path": "/project/Cargo.toml",
+                "metadata": { "cargo-3ds": metadata },
+            }))
+            .unwrap()
+        };
+
+        let config = |package: &Package, build_type| {
+            existing_artifact_config(
+                package,
+                "bin",
+                "foo",
+                build_type,
+                None,
+                None,
+                "foo.elf".into(),
+            )
+        };
+
+        let config_from_package = config(&package(serde_json::json!({})), BuildType::Debug);
+        assert_eq!(
+            config_from_package.publisher(),
+            "Jane Doe <jane@example.com>, John Doe"
+        );
+        assert_eq!(config_from_package.description(), "A 3DS app");
+        assert_eq!(config_from_package.manifest_dir, "/project");
+
+        // Explicit metadata, including overrides for a target, still takes precedence
+        let overridden = package(serde_json::json!({
+            "authors": ["Rust3DS"],
+            "release": { "description": "A 3DS app [release]" },
+        }));
+        assert_eq!(config(&overridden, BuildType::Debug).publisher(), "Rust3DS");
+        assert_eq!(
+            config(&overridden, BuildType::Debug).description(),
+            "A 3DS app"
+        );
+        assert_eq!(
+            config(&overridden, BuildType::Release).description(),
+            "A 3DS app [release]"
+        );
+    }
+
+    #[test]
+    fn unchanged_file_reused() {
+        let dir = TestDir::new();
+        let path = dir.join("icon.png");
+
+        assert!(write_if_changed(&path, b"icon").unwrap());
+        assert!(!write_if_changed(&path, b"icon").unwrap());
+        assert!(write_if_changed(&path, b"new icon").unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"new icon");
+    }
+
+    #[test]
+    fn commit_date_months_later() {
+        let date = |date| CommitDate::parse(date).unwrap();
+
+        assert_eq!(date("2026-09-01").months_later(3), date("2026-12-01"));
+        assert_eq!(date("2026-10-15").months_later(3), date("2027-01-15"));
+        assert_eq!(date("2026-11-30").months_later(15), date("2028-02-30"));
+    }
+
+    #[test]
+    fn smdh_hash() {
+        let dir = TestDir::new();
+        fs::write(dir.join("app.smdh"), "abc").unwrap();
+
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256_file(&dir.join("app.smdh")).unwrap(), abc);
+
+        let config = |hash: Option<&str>| CTRConfig {
+            target_path: dir.join("app.elf"),
+            smdh_sha256: hash.map(String::from),
+            ..Default::default()
+        };
+        assert!(config(None).check_smdh_hash().is_ok());
+        assert!(config(Some(&abc.to_uppercase())).check_smdh_hash().is_ok());
+        assert!(config(Some(&"0".repeat(64))).check_smdh_hash().is_err());
+
+        assert!(config(Some(abc)).problems().is_empty());
+        assert_eq!(config(Some("abc")).problems().len(), 1);
+
+        // Each target's SMDH differs, so the hash can't be shared
+        let metadata = |metadata| {
+            PackageMetadata::deserialize(metadata)
+                .unwrap()
+                .target_config("bin", "app", BuildType::Debug, None)
+        };
+        let per_target = metadata(serde_json::json!({ "bin": { "app": { "smdh_sha256": abc } } }));
+        assert!(per_target.problems().is_empty());
+        let shared = metadata(serde_json::json!({ "smdh_sha256": abc }));
+        assert_eq!(shared.problems().len(), 1);
+    }
+
+    #[test]
+    fn short_description_default() {
+        let config = |metadata| CTRConfig {
+            name: String::from("app"),
+            ..PackageMetadata::deserialize(metadata)
+                .unwrap()
+                .target_config("bin", "app", BuildType::Debug, None)
+        };
+
+        assert_eq!(config(serde_json::json!({})).short_description(), "app");
+        assert_eq!(
+            config(serde_json::json!({
+                "description": "A longer description of My App",
+                "short-description": "My App",
+            }))
+            .short_description(),
+            "My App"
+        );
+    }
+
+    #[test]
+    fn publisher_from_authors() {
+        let publisher = |authors: &[&str]| {
+            CTRConfig {
+                authors: Some(authors.iter().map(ToString::to_string).collect()),
+                ..Default::default()
+            }
+            .publisher()
+        };
+
+        assert_eq!(
+            publisher(&["Jane Doe <jane@example.com>", "John Doe"]),
+            "Jane Doe <jane@example.com>, John Doe"
+        );
+        assert_eq!(publisher(&[]), CTRConfig::DEFAULT_AUTHOR);
+        assert_eq!(CTRConfig::default().publisher(), CTRConfig::DEFAULT_AUTHOR);
+    }
+
+    /// Artifacts of a `foo` package with a `foo` binary, a `foo` example, an
+    /// `integration` test and a `speed` bench, as built with `--all-targets`.
+    fn all_targets_artifacts() -> Vec<Artifact> {
+        let artifact = |kind: &str, name: &str, test: bool, path: &str| {
+            serde_json::from_value::<Artifact>(serde_json::json!({
+                "package_id": "foo 0.1.0 (path+file:///foo)",
+                "target": {
+                    "name": name,
+                    "kind": [kind],
+                    "crate_types": ["bin"],
+                    "src_path": "/foo/src/main.rs",
+                },
+                "profile": {
+                    "opt_level": "0",
+                    "debuginfo": 2,
+                    "debug_assertions": true,
+                    "overflow_checks": true,
+                    "test": test,
+                },
+                "features": [],
+                "filenames": [path],
+                "executable": path,
+                "fresh": false,
+            }))
+            .unwrap()
+        };
+
+        let out = "/foo/target/armv6k-nintendo-3ds/debug";
+        vec![
+            artifact("bin", "foo", false, &format!("{out}/foo.elf")),
+            artifact("bin", "foo", true, &format!("{out}/deps/foo-1a2b3c.elf")),
+            artifact("example", "foo", false, &format!("{out}/examples/foo.elf")),
+            artifact(
+                "example",
+                "foo",
+                true,
+                &format!("{out}/examples/foo-4d5e6f.elf"),
+            ),
+            artifact(
+                "test",
+                "integration",
+                true,
+                &format!("{out}/deps/integration-7a8b9c.elf"),
+            ),
+            artifact(
+                "bench",
+                "speed",
+                true,
+                &format!("{out}/deps/speed-0d1e2f.elf"),
+            ),
+        ]
+    }
+
+    #[test]
+    fn target_naming() {
+        for (kind, name, harness, expected) in [
+            ("bin", "app", false, "app"),
+            ("bin", "app", true, "app tests"),
+            ("example", "demo", false, "demo - foo example"),
+            ("example", "demo", true, "demo - foo example tests"),
+            ("lib", "foo", true, "foo tests"),
+            ("test", "integration", true, "integration"),
+            ("bench", "speed", true, "speed bench"),
+        ] {
+            assert_eq!(target_title("foo", kind, name, harness), expected);
+        }
+    }
+
+    #[test]
+    fn output_filename() {
+        let config = |kind, name| CTRConfig {
+            name: target_title("foo", kind, name, false),
+            target_path: format!("/target/{name}.elf").into(),
+            ..PackageMetadata::deserialize(serde_json::json!({
+                "example": { "demo": { "filename": "demo-3ds" } },
+            }))
+            .unwrap()
+            .target_config(kind, name, BuildType::Debug, None)
+        };
+
+        // The title has spaces, but the file names don't
+        let example = config("example", "demo");
+        assert_eq!(example.name, "demo - foo example");
+        assert_eq!(example.path_3dsx(), "/target/demo-3ds.3dsx");
+        assert_eq!(example.path_smdh(), "/target/demo-3ds.smdh");
+
+        let bin = config("bin", "app");
+        assert_eq!(bin.path_3dsx(), "/target/app.3dsx");
+        assert_eq!(bin.path_smdh(), "/target/app.smdh");
+
+        let invalid = PackageMetadata::deserialize(serde_json::json!({
+            "bin": { "app": { "filename": "out/app" } },
+        }))
+        .unwrap()
+        .target_config("bin", "app", BuildType::Debug, None);
+        assert_eq!(invalid.problems().len(), 1);
+
+        // Shared by several targets, which would overwrite each other's outputs
+        for metadata in [
+            serde_json::json!({ "filename": "app" }),
+            serde_json::json!({ "release": { "filename": "app" } }),
+            serde_json::json!({ "profile": { "dist": { "filename": "app" } } }),
+        ] {
+            let shared = PackageMetadata::deserialize(metadata)
+                .unwrap()
+                .target_config("bin", "app", BuildType::Release, Some("dist"));
+            assert_eq!(shared.problems().len(), 1, "{:?}", shared.misplaced);
+        }
+    }
+
+    #[test]
+    fn all_targets_naming() {
+        let artifacts = all_targets_artifacts();
+
+        let titles: Vec<_> = artifacts
+            .iter()
+            .map(|artifact| artifact_title("foo", artifact))
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                "foo",
+                "foo tests",
+                "foo - foo example",
+                "foo - foo example tests",
+                "integration",
+                "speed bench",
+            ]
+        );
+
+        let mut paths: Vec<_> = artifacts
+            .into_iter()
+            .map(|artifact| {
+                CTRConfig {
+                    target_path: artifact.executable.unwrap(),
+                    ..Default::default()
+                }
+                .path_3dsx()
+            })
+            .collect();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), titles.len());
+    }
+
+    #[test]
+    fn libctru_path() {
+        let flag = Utf8PathBuf::from("/opt/libctru-flag");
+        assert_eq!(libctru_override(None, None), None);
+        assert_eq!(libctru_override(None, Some(String::new())), None);
+        assert_eq!(
+            libctru_override(None, Some("/opt/libctru-env".into())),
+            Some("/opt/libctru-env".into())
+        );
+        assert_eq!(
+            libctru_override(Some(&flag), Some("/opt/libctru-env".into())),
+            Some(flag)
+        );
+
+        let relative = libctru_override(None, Some("libctru".into())).unwrap();
+        assert!(relative.is_absolute());
+        assert!(relative.ends_with("libctru"));
+    }
+
+    #[test]
+    fn allow_stable_toolchain() {
+        assert!(!allow_stable(false, None));
+        assert!(!allow_stable(false, Some("".into())));
+        assert!(!allow_stable(false, Some("0".into())));
+        assert!(allow_stable(false, Some("1".into())));
+        assert!(allow_stable(true, None));
+
+        let Cargo::Input(input) =
+            Cargo::try_parse_from(["cargo", "3ds", "build", "--skip-toolchain-check"]).unwrap();
+        assert!(input.allow_stable);
+    }
+}
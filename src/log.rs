@@ -0,0 +1,230 @@
+//! Minimal leveled logging for `cargo-3ds`'s own output.
+//!
+//! All messages are written to stderr, since stdout may be used to forward
+//! JSON messages from `cargo`. The maximum level is chosen once at startup by
+//! [`init`] from the `--quiet`/`--verbose` flags (`-v` for debug output, `-vv`
+//! for trace output), or the `RUST_LOG` environment variable if neither flag was given.
+//!
+//! The `error: ` and `warning: ` prefixes are colored like cargo's, as chosen by
+//! [`init_color`] from `--color` and the usual environment variables.
+
+use std::env;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// The severity of a log message. Messages are only printed if their level is
+/// at most the current [`max_level`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    /// Don't print anything at all.
+    Off = 0,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    /// The raw output of external tools, e.g. cargo's JSON messages.
+    Trace,
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+impl Level {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Self::Off,
+            1 => Self::Error,
+            2 => Self::Warn,
+            3 => Self::Info,
+            4 => Self::Debug,
+            _ => Self::Trace,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            other => Err(format!("unknown log level `{other}`")),
+        }
+    }
+}
+
+/// Set the maximum level of messages that will be printed.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Get the maximum level of messages that will be printed.
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Whether messages at the given level should be printed.
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= max_level()
+}
+
+/// Initialize the maximum log level. Command-line flags take precedence over
+/// `RUST_LOG`, which in turn takes precedence over the default [`Level::Info`].
+/// `verbose` is the number of times `--verbose` was given.
+pub fn init(verbose: u8, quiet: bool) {
+    let level = if verbose >= 2 {
+        Level::Trace
+    } else if verbose == 1 {
+        Level::Debug
+    } else if quiet {
+        Level::Warn
+    } else {
+        env::var("RUST_LOG")
+            .ok()
+            .and_then(|var| var.parse().ok())
+            .unwrap_or(Level::Info)
+    };
+
+    set_max_level(level);
+}
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Choose whether messages are colored, from `--color` (`auto`, `always` or
+/// `never`) or else cargo's `CARGO_TERM_COLOR`. With `auto`, the `NO_COLOR`,
+/// `CLICOLOR_FORCE` and `CLICOLOR` conventions are respected, and otherwise
+/// messages are colored if stderr is a terminal.
+pub fn init_color(color: Option<&str>) {
+    let color = use_color(color, |var| env::var(var).ok(), io::stderr().is_terminal());
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+fn use_color(color: Option<&str>, var: impl Fn(&str) -> Option<String>, is_terminal: bool) -> bool {
+    match color
+        .map(String::from)
+        .or_else(|| var("CARGO_TERM_COLOR"))
+        .as_deref()
+    {
+        Some("always") => return true,
+        Some("never") => return false,
+        _ => {}
+    }
+
+    if var("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        false
+    } else if var("CLICOLOR_FORCE").is_some_and(|value| !value.is_empty() && value != "0") {
+        true
+    } else if var("CLICOLOR").as_deref() == Some("0") {
+        false
+    } else {
+        is_terminal
+    }
+}
+
+/// Get the prefix of messages at the given level, e.g. `error: `, colored if
+/// enabled by [`init_color`].
+pub fn prefix(level: Level) -> &'static str {
+    let color = COLOR.load(Ordering::Relaxed);
+    match (level, color) {
+        (Level::Error, true) => "\x1b[1;31merror\x1b[0m\x1b[1m:\x1b[0m ",
+        (Level::Error, false) => "error: ",
+        (Level::Warn, true) => "\x1b[1;33mwarning\x1b[0m\x1b[1m:\x1b[0m ",
+        (Level::Warn, false) => "warning: ",
+        _ => "",
+    }
+}
+
+/// Log a message at the given [`Level`](crate::log::Level).
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            eprintln!($($arg)+);
+        }
+    };
+}
+
+/// Log an error, prefixed with `error: `.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log!(
+            $crate::log::Level::Error,
+            "{}{}",
+            $crate::log::prefix($crate::log::Level::Error),
+            format_args!($($arg)+)
+        )
+    };
+}
+
+/// Log a warning, prefixed with `warning: `.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log!(
+            $crate::log::Level::Warn,
+            "{}{}",
+            $crate::log::prefix($crate::log::Level::Warn),
+            format_args!($($arg)+)
+        )
+    };
+}
+
+/// Log an informational message, shown by default.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Info, $($arg)+)
+    };
+}
+
+/// Log a debug message, shown only with `--verbose`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Debug, $($arg)+)
+    };
+}
+
+/// Log a trace message, shown only with `-vv`.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Trace, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_choice() {
+        let color = |flag: Option<&str>, vars: &[(&str, &str)], is_terminal: bool| {
+            let var = |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            };
+            use_color(flag, var, is_terminal)
+        };
+
+        assert!(color(None, &[], true));
+        assert!(!color(None, &[], false));
+        assert!(color(Some("always"), &[("NO_COLOR", "1")], false));
+        assert!(!color(Some("never"), &[("CLICOLOR_FORCE", "1")], true));
+        assert!(!color(Some("auto"), &[], false));
+        assert!(!color(None, &[("CARGO_TERM_COLOR", "never")], true));
+        assert!(color(Some("auto"), &[("CARGO_TERM_COLOR", "never")], true));
+        assert!(!color(None, &[("NO_COLOR", "1")], true));
+        assert!(color(None, &[("NO_COLOR", "")], true));
+        assert!(color(None, &[("CLICOLOR_FORCE", "1")], false));
+        assert!(!color(None, &[("CLICOLOR", "0")], true));
+    }
+}
@@ -1,31 +1,102 @@
 use std::process;
 
-use cargo_3ds::command::Cargo;
-use cargo_3ds::{check_rust_version, run_cargo};
-use clap::Parser;
+use cargo_3ds::command::{Cargo, CargoCmd};
+use cargo_3ds::{
+    cargo_metadata, check_rust_version, check_tool_versions, error, info, log, print_sysroot,
+    run_cargo, set_libctru_path, set_manifest_path,
+};
 
 fn main() {
-    let Cargo::Input(mut input) = Cargo::parse();
+    let Cargo::Input(mut input) = Cargo::parse_args();
+
+    log::init(input.verbose, input.quiet);
+    log::init_color(input.color.as_deref());
+
+    if let Some(manifest_path) = input.manifest_path() {
+        if let CargoCmd::New(_) = input.cmd {
+            error!("`--manifest-path` can't be used with `new`");
+            process::exit(1);
+        }
+        set_manifest_path(manifest_path);
+    }
+
+    if let Some(libctru_path) = input.libctru_path() {
+        set_libctru_path(libctru_path);
+    }
+
+    if let CargoCmd::Test(test) = &mut input.cmd {
+        test.route_harness_args();
+    }
+
+    let run_args = match &mut input.cmd {
+        CargoCmd::Run(run) => Some(run),
+        CargoCmd::Test(test) => Some(&mut test.run_args),
+        _ => None,
+    };
+    if let Some(Err(err)) = run_args.map(|run_args| run_args.load_args_file()) {
+        error!("{err}");
+        process::exit(1);
+    }
+
+    if input.print_sysroot {
+        print_sysroot(&input);
+        return;
+    }
+
+    match &input.cmd {
+        // `inspect` only reads an existing file, and `validate` and `smdh` only
+        // the metadata, without running a cargo build at all
+        CargoCmd::Inspect(inspect) => {
+            inspect.run();
+            return;
+        }
+        CargoCmd::Validate(validate) => {
+            validate.run();
+            return;
+        }
+        CargoCmd::Smdh(smdh) => {
+            smdh.run();
+            return;
+        }
+        // Neither does `run --link-only`, which runs the 3dsx from a previous build
+        CargoCmd::Run(run) if run.link_only => {
+            run.link_existing();
+            return;
+        }
+        CargoCmd::Test(test) if test.run_args.link_only => {
+            error!("`--link-only` is only supported by `cargo 3ds run`");
+            process::exit(1);
+        }
+        CargoCmd::Test(test) if test.run_args.gdb => {
+            error!("`--gdb` is only supported by `cargo 3ds run`");
+            process::exit(1);
+        }
+        // Host executables run natively, so there is nothing left for cargo-3ds to do
+        CargoCmd::Run(_) | CargoCmd::Test(_) if input.host => {
+            error!("`--host` can't be used with `run` or `test`, use plain `cargo` instead");
+            process::exit(1);
+        }
+        _ => {}
+    }
 
     // Depending on the command, we might have different base requirements for the Rust version.
     check_rust_version(&input);
+    // In host mode, no 3dsx is built, so none of the devkitPro tools are used
+    if !input.host {
+        check_tool_versions(&input);
+    }
 
     let message_format = match input.cmd.extract_message_format() {
         Ok(fmt) => fmt,
         Err(msg) => {
-            eprintln!("{msg}");
+            error!("{msg}");
             process::exit(1)
         }
     };
 
-    let metadata = if input.cmd.should_build_3dsx() {
-        match cargo_metadata::MetadataCommand::new().no_deps().exec() {
-            Ok(metadata) => Some(metadata),
-            Err(err) => {
-                eprintln!("Warning: failed to gather cargo metadata for the project: {err}");
-                None
-            }
-        }
+    let builds_3dsx = input.cmd.should_build_3dsx() && !input.host;
+    let metadata = if builds_3dsx || input.cmd.timings_requested() {
+        cargo_metadata()
     } else {
         None
     };
@@ -36,5 +107,11 @@ fn main() {
         process::exit(status.code().unwrap_or(1));
     }
 
-    input.cmd.run_callbacks(&messages, metadata.as_ref());
+    if let Some(report) = metadata.and_then(|metadata| input.cmd.timings_report_path(metadata)) {
+        info!("Timing report saved to {report}");
+    }
+
+    if !input.host {
+        input.cmd.run_callbacks(&messages, metadata);
+    }
 }
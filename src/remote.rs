@@ -0,0 +1,210 @@
+use std::fs;
+use std::process::{Command, Stdio};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{print_command, sha256_hex};
+
+/// Whether a configured asset path is actually a URL to download.
+pub(crate) fn is_remote(path: &Utf8Path) -> bool {
+    let path = path.as_str();
+    path.starts_with("https://") || path.starts_with("http://")
+}
+
+/// Check that the asset path of `field` isn't a git reference, e.g.
+/// `git+https://...` or the URL of a `.git` repository. Only files and archives
+/// can be downloaded, so a repository must be given as the URL of an archive of
+/// it instead, such as a release tarball.
+pub(crate) fn check_not_git(field: &str, path: &Utf8Path) -> Result<(), String> {
+    let url = path.as_str();
+    let is_git = url.starts_with("git+")
+        || url.starts_with("git://")
+        || url.starts_with("git@")
+        || (is_remote(path) && url.trim_end_matches('/').ends_with(".git"));
+
+    if is_git {
+        Err(format!(
+            "`{field}` can't be a git reference ({url}), only an `https://` URL of a file \
+            or archive, e.g. a release tarball of the repository"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// A cache of assets downloaded with `curl`. Each URL is downloaded into its own
+/// directory, along with its `ETag` so that unchanged assets aren't downloaded again.
+pub(crate) struct RemoteCache {
+    dir: Utf8PathBuf,
+    offline: bool,
+}
+
+impl RemoteCache {
+    /// Create a cache in `dir`. If `offline` is set, only previously downloaded
+    /// assets can be used.
+    pub(crate) fn new(dir: Utf8PathBuf, offline: bool) -> Self {
+        Self { dir, offline }
+    }
+
+    /// Get the cache directory for a single URL, named after its hash so it
+    /// stays the same across builds and toolchains.
+    fn entry_dir(&self, url: &str) -> Utf8PathBuf {
+        self.dir.join(&sha256_hex(url.as_bytes())[..16])
+    }
+
+    /// Download a single file, returning the path to the cached copy.
+    pub(crate) fn fetch_file(&self, url: &str) -> Result<Utf8PathBuf, String> {
+        let entry = self.entry_dir(url);
+        let file_name = url
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .unwrap_or("download");
+        let path = entry.join(file_name);
+
+        if self.offline {
+            return if path.exists() {
+                Ok(path)
+            } else {
+                Err(format!(
+                    "{url} has not been downloaded yet, and cannot be downloaded offline"
+                ))
+            };
+        }
+
+        fs::create_dir_all(&entry).map_err(|err| format!("unable to create {entry}: {err}"))?;
+
+        // If the ETag still matches, curl leaves the cached file untouched
+        let etag = entry.join("etag");
+        let mut command = Command::new("curl");
+        command
+            .args(["--fail", "--silent", "--show-error", "--location"])
+            .arg("--etag-compare")
+            .arg(&etag)
+            .arg("--etag-save")
+            .arg(&etag)
+            .arg("--output")
+            .arg(&path)
+            .arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+
+        print_command(&command);
+
+        let status = command
+            .status()
+            .map_err(|err| format!("unable to run `curl` to download {url}: {err}"))?;
+
+        if !status.success() {
+            return Err(format!("failed to download {url} ({status})"));
+        }
+
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(format!("downloading {url} did not produce any file"))
+        }
+    }
+
+    /// Download an archive (anything `tar` can extract) and extract it, returning
+    /// the path to the extracted directory. The archive is only extracted again
+    /// if it changed since it was last extracted.
+    pub(crate) fn fetch_dir(&self, url: &str) -> Result<Utf8PathBuf, String> {
+        let archive = self.fetch_file(url)?;
+        let dir = archive.parent().unwrap().join("contents");
+
+        let modified = |path: &Utf8Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        if dir.is_dir() && modified(&dir) >= modified(&archive) {
+            return Ok(dir);
+        }
+
+        if dir.exists() {
+            fs::remove_dir_all(&dir).map_err(|err| format!("unable to clean {dir}: {err}"))?;
+        }
+        fs::create_dir_all(&dir).map_err(|err| format!("unable to create {dir}: {err}"))?;
+
+        let mut command = Command::new("tar");
+        command
+            .arg("-xf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+
+        print_command(&command);
+
+        let status = command
+            .status()
+            .map_err(|err| format!("unable to run `tar` to extract {archive}: {err}"))?;
+
+        if status.success() {
+            Ok(dir)
+        } else {
+            let _ = fs::remove_dir_all(&dir);
+            Err(format!("failed to extract {archive} downloaded from {url}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    #[test]
+    fn remote_paths() {
+        assert!(is_remote("https://example.com/icon.png".into()));
+        assert!(is_remote("http://example.com/romfs.tar.gz".into()));
+        assert!(!is_remote("icon.png".into()));
+        assert!(!is_remote("/abs/https://icon.png".into()));
+    }
+
+    #[test]
+    fn git_references() {
+        for path in [
+            "git+https://github.com/org/branding#v1",
+            "git://example.com/branding",
+            "git@github.com:org/branding.git",
+            "https://github.com/org/branding.git",
+        ] {
+            assert!(check_not_git("romfs_dir", path.into()).is_err(), "{path}");
+        }
+
+        for path in ["https://example.com/branding.tar.gz", "assets/romfs"] {
+            assert!(check_not_git("romfs_dir", path.into()).is_ok(), "{path}");
+        }
+    }
+
+    #[test]
+    fn stable_cache_dirs() {
+        let cache = RemoteCache::new("cache".into(), false);
+        assert_eq!(
+            cache.entry_dir("https://example.com/assets/icon.png"),
+            cache.entry_dir("https://example.com/assets/icon.png")
+        );
+        assert_eq!(
+            cache
+                .entry_dir("https://example.com/icon.png")
+                .as_str()
+                .len(),
+            22
+        );
+    }
+
+    #[test]
+    fn offline_requires_cache() {
+        let dir = TestDir::new();
+        let cache = RemoteCache::new(dir.clone(), true);
+        let url = "https://example.com/assets/icon.png";
+
+        assert!(cache.fetch_file(url).is_err());
+
+        let cached = cache.entry_dir(url).join("icon.png");
+        fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        fs::write(&cached, b"png").unwrap();
+        assert_eq!(cache.fetch_file(url), Ok(cached));
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Read;
+
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cargo_metadata::{DependencyKind, Metadata, PackageId};
+
+use crate::inspect::{EXTENDED_HEADER_SIZE, MAGIC_3DSX};
+use crate::walk_files;
+
+/// A romfs manifest, describing where files should be placed in the romfs
+/// regardless of their location on disk. Each non-empty line of the manifest
+/// maps a source path (relative to the manifest) to a destination path (relative
+/// to the romfs root), and lines starting with `#` are ignored:
+///
+/// ```txt
+/// # Directories are copied recursively
+/// assets/sprites -> gfx
+/// ../shared/font.bcfnt -> fonts/main.bcfnt
+/// ```
+#[derive(Default, Debug, PartialEq, Eq)]
+pub(crate) struct RomfsManifest {
+    mappings: Vec<Mapping>,
+    /// Shared files placed underneath the mappings, which override them.
+    base: Vec<Mapping>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Mapping {
+    src: Utf8PathBuf,
+    dest: Utf8PathBuf,
+}
+
+impl Mapping {
+    /// List the files placed by this mapping, as pairs of (source, destination).
+    fn files(&self) -> Result<Vec<(Utf8PathBuf, Utf8PathBuf)>, String> {
+        let Self { src, dest } = self;
+
+        let entries = if src.is_dir() {
+            let ignore = IgnoreRules::read(src)?;
+            walk_files(src)
+                .map_err(|err| format!("unable to read romfs source {src}: {err}"))?
+                .into_iter()
+                .filter(|path| !path.is_dir())
+                .map(|path| {
+                    let relative = path.strip_prefix(src).unwrap().to_path_buf();
+                    (path, relative)
+                })
+                .filter(|(_, relative)| match &ignore {
+                    Some(ignore) => relative != IGNORE_FILE && !ignore.is_ignored(relative),
+                    None => true,
+                })
+                .map(|(path, relative)| (path, dest.join(relative)))
+                .collect()
+        } else if src.is_file() {
+            vec![(src.clone(), dest.clone())]
+        } else {
+            return Err(format!("romfs source {src} does not exist"));
+        };
+
+        Ok(entries)
+    }
+}
+
+impl RomfsManifest {
+    /// Read and parse the manifest at `path`.
+    pub(crate) fn read(path: &Utf8Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("unable to read romfs manifest {path}: {err}"))?;
+
+        Self::parse(&contents, path.parent().unwrap_or(Utf8Path::new(".")))
+            .map_err(|err| format!("invalid romfs manifest {path}: {err}"))
+    }
+
+    /// Parse the contents of a manifest. Source paths are resolved relative to `base_dir`.
+    fn parse(contents: &str, base_dir: &Utf8Path) -> Result<Self, String> {
+        let mut mappings = Vec::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((src, dest)) = line.split_once("->") else {
+                return Err(format!("line {}: expected `src -> dest`", i + 1));
+            };
+
+            let dest = Utf8PathBuf::from(dest.trim());
+            if !is_romfs_path(&dest) {
+                return Err(format!(
+                    "line {}: destination `{dest}` must be a relative path within the romfs",
+                    i + 1
+                ));
+            }
+
+            mappings.push(Mapping {
+                src: base_dir.join(src.trim()),
+                dest,
+            });
+        }
+
+        Ok(Self {
+            mappings,
+            base: Vec::new(),
+        })
+    }
+
+    /// Place the file or directory `src` at `dest` within the romfs. An empty
+    /// `dest` places the contents of a directory at the root of the romfs.
+    pub(crate) fn add(&mut self, src: Utf8PathBuf, dest: Utf8PathBuf) {
+        self.mappings.push(Mapping { src, dest });
+    }
+
+    /// Place the file or directory `src` at `dest` as a base layer, underneath
+    /// everything else: any file placed at the same destination by another
+    /// mapping overrides the base file instead of conflicting with it.
+    pub(crate) fn add_base(&mut self, src: Utf8PathBuf, dest: Utf8PathBuf) {
+        self.base.push(Mapping { src, dest });
+    }
+
+    /// List every file to be placed in the romfs, as pairs of (source, destination),
+    /// expanding directories recursively. Files matched by a directory's
+    /// [`IGNORE_FILE`] are left out, along with the ignore file itself.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a source path does not exist, or if more than one
+    /// source would be placed at the same destination, outside of the base layer.
+    pub(crate) fn files(&self) -> Result<Vec<(Utf8PathBuf, Utf8PathBuf)>, String> {
+        let mut files = Vec::new();
+        let mut destinations = HashMap::new();
+
+        for mapping in &self.mappings {
+            for (src, dest) in mapping.files()? {
+                if let Some(other) = destinations.insert(dest.clone(), src.clone()) {
+                    return Err(format!(
+                        "romfs destination `{dest}` is mapped from both {other} and {src}"
+                    ));
+                }
+                files.push((src, dest));
+            }
+        }
+
+        // Base files are only used where nothing else is placed
+        let mut base_files = Vec::new();
+        for mapping in &self.base {
+            for (src, dest) in mapping.files()? {
+                if !destinations.contains_key(&dest) {
+                    base_files.push((src, dest));
+                }
+            }
+        }
+        base_files.extend(files);
+
+        Ok(base_files)
+    }
+
+    /// Copy all the files described by the manifest into a fresh romfs directory at `dir`.
+    pub(crate) fn stage(&self, dir: &Utf8Path) -> Result<(), String> {
+        let files = self.files()?;
+
+        if dir.exists() {
+            fs::remove_dir_all(dir).map_err(|err| format!("unable to clean {dir}: {err}"))?;
+        }
+        fs::create_dir_all(dir).map_err(|err| format!("unable to create {dir}: {err}"))?;
+
+        for (src, dest) in files {
+            let dest = dir.join(dest);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|err| format!("unable to create {parent}: {err}"))?;
+            }
+            fs::copy(&src, &dest)
+                .map_err(|err| format!("unable to copy {src} to {dest}: {err}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The size of the header of a RomFS image as embedded in a 3dsx, which is
+/// stored as its first field. Such an image is only the level 3 of the IVFC tree
+/// found in a CXI's RomFS, without the IVFC header or hash levels.
+const IMAGE_HEADER_SIZE: u32 = 0x28;
+
+/// The offset of the romfs offset in the extended header of a 3dsx.
+const ROMFS_OFFSET_FIELD: usize = 0x28;
+
+/// Check that `path` looks like a RomFS image which can be embedded in a 3dsx.
+pub(crate) fn check_image(path: &Utf8Path) -> Result<(), String> {
+    let mut header = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .map_err(|err| format!("unable to read RomFS image {path}: {err}"))?;
+
+    if u32::from_le_bytes(header) == IMAGE_HEADER_SIZE {
+        Ok(())
+    } else {
+        Err(format!(
+            "{path} is not a RomFS image for a 3dsx, which must be a level 3 RomFS \
+            rather than a full IVFC image"
+        ))
+    }
+}
+
+/// Append the RomFS image at `image` to the 3dsx at `path_3dsx`, which must have
+/// an extended header but no romfs yet, and point the header at it.
+pub(crate) fn embed_image(path_3dsx: &Utf8Path, image: &Utf8Path) -> Result<(), String> {
+    check_image(image)?;
+
+    let mut data =
+        fs::read(path_3dsx).map_err(|err| format!("unable to read {path_3dsx}: {err}"))?;
+    let header_size = data
+        .get(4..6)
+        .map(|bytes| usize::from(u16::from_le_bytes([bytes[0], bytes[1]])));
+    if !data.starts_with(MAGIC_3DSX) || header_size.is_none_or(|size| size < EXTENDED_HEADER_SIZE) {
+        return Err(format!(
+            "{path_3dsx} has no extended header to embed a RomFS in"
+        ));
+    }
+
+    let offset_field = ROMFS_OFFSET_FIELD..ROMFS_OFFSET_FIELD + 4;
+    if data[offset_field.clone()] != [0; 4] {
+        return Err(format!("{path_3dsx} already has an embedded RomFS"));
+    }
+    let offset = u32::try_from(data.len())
+        .map_err(|_| format!("{path_3dsx} is too large to embed a RomFS in"))?;
+    data[offset_field].copy_from_slice(&offset.to_le_bytes());
+
+    let image_data = fs::read(image).map_err(|err| format!("unable to read {image}: {err}"))?;
+    data.extend(image_data);
+
+    fs::write(path_3dsx, data).map_err(|err| format!("unable to write {path_3dsx}: {err}"))
+}
+
+/// The name of the file listing patterns of files to leave out of a romfs
+/// directory, like a `.gitignore` file.
+pub(crate) const IGNORE_FILE: &str = ".3dsxignore";
+
+/// The patterns of an [`IGNORE_FILE`], which follow the `.gitignore` syntax:
+/// `*` and `?` match within a path component and `**` across components,
+/// a leading `/` (or any `/` but a trailing one) anchors the pattern to the
+/// romfs directory, a trailing `/` only matches directories, and `!` re-includes
+/// files matched by an earlier pattern. Lines starting with `#` are comments.
+#[derive(Debug)]
+struct IgnoreRules {
+    rules: Vec<IgnoreRule>,
+}
+
+#[derive(Debug)]
+struct IgnoreRule {
+    pattern: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl IgnoreRules {
+    /// Read the ignore file of the romfs directory `dir`, if it has one.
+    fn read(dir: &Utf8Path) -> Result<Option<Self>, String> {
+        let path = dir.join(IGNORE_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        fs::read_to_string(&path)
+            .map(|contents| Some(Self::parse(&contents)))
+            .map_err(|err| format!("unable to read {path}: {err}"))
+    }
+
+    fn parse(contents: &str) -> Self {
+        let rules = contents
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(line) => (true, line),
+                    None => (false, line),
+                };
+                let (dir_only, line) = match line.strip_suffix('/') {
+                    Some(line) => (true, line),
+                    None => (false, line),
+                };
+
+                IgnoreRule {
+                    pattern: line.trim_start_matches('/').chars().collect(),
+                    negated,
+                    dir_only,
+                    anchored: line.contains('/'),
+                }
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Whether the file at `path` (relative to the romfs directory) is ignored,
+    /// either itself or because one of its parent directories is ignored.
+    fn is_ignored(&self, path: &Utf8Path) -> bool {
+        let mut ancestors: Vec<_> = path
+            .ancestors()
+            .filter(|p| !p.as_str().is_empty())
+            .collect();
+        ancestors.reverse();
+
+        let Some((file, dirs)) = ancestors.split_last() else {
+            return false;
+        };
+
+        dirs.iter().any(|dir| self.matches(dir, true)) || self.matches(file, false)
+    }
+
+    /// Whether the last pattern matching `path` ignores it.
+    fn matches(&self, path: &Utf8Path, is_dir: bool) -> bool {
+        let name = path.file_name().unwrap_or_default();
+        let path: Vec<char> = path.as_str().chars().collect();
+        let name: Vec<char> = name.chars().collect();
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.dir_only)
+                    && glob_match(&rule.pattern, if rule.anchored { &path } else { &name })
+            })
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+/// Match `text` against a glob `pattern`, where `*` and `?` don't match `/`
+/// but `**` does, and `**/` also matches no directories at all.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => (0..=text.len())
+            .filter(|&i| i == 0 || text[i - 1] == '/')
+            .any(|i| glob_match(rest, &text[i..])),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(rest, &text[i..])),
+        ['?', rest @ ..] => {
+            matches!(text, [c, text @ ..] if *c != '/' && glob_match(rest, text))
+        }
+        [p, rest @ ..] => matches!(text, [c, text @ ..] if c == p && glob_match(rest, text)),
+    }
+}
+
+/// Whether `dest` is a valid destination for a file in the romfs: a non-empty
+/// relative path which stays within the romfs root.
+pub(crate) fn is_romfs_path(dest: &Utf8Path) -> bool {
+    !dest.as_str().is_empty()
+        && dest
+            .components()
+            .all(|c| matches!(c, Utf8Component::Normal(_) | Utf8Component::CurDir))
+}
+
+/// Find the romfs directories contributed by the (transitive, non-dev) dependencies
+/// of `package`: any dependency with `romfs_contribution` set in its own
+/// `[package.metadata.cargo-3ds]`. Each is paired with the dependency's name,
+/// which is the directory it is placed in within the final romfs.
+///
+/// A dependency's `romfs_dir` is only its own romfs, e.g. for its examples, so
+/// it is never contributed.
+///
+/// # Errors
+///
+/// Returns an error if two dependencies with the same name contribute to the romfs.
+pub(crate) fn dependency_romfs_dirs(
+    metadata: &Metadata,
+    package: &PackageId,
+) -> Result<Vec<(String, Utf8PathBuf)>, String> {
+    // Without dependency information, e.g. from `cargo metadata --no-deps`
+    let Some(resolve) = &metadata.resolve else {
+        return Ok(Vec::new());
+    };
+
+    let nodes: HashMap<_, _> = resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+    let mut seen = HashSet::new();
+    let mut queue = vec![package];
+    let mut names: HashMap<&str, &PackageId> = HashMap::new();
+    let mut contributions = Vec::new();
+
+    while let Some(id) = queue.pop() {
+        let Some(node) = nodes.get(id) else {
+            continue;
+        };
+
+        for dep in &node.deps {
+            let is_normal = dep.dep_kinds.is_empty()
+                || dep
+                    .dep_kinds
+                    .iter()
+                    .any(|info| info.kind == DependencyKind::Normal);
+            if !is_normal || !seen.insert(&dep.pkg) {
+                continue;
+            }
+            queue.push(&dep.pkg);
+
+            let dep_package = &metadata[&dep.pkg];
+            let Some(romfs_dir) = romfs_contribution(&dep_package.metadata) else {
+                continue;
+            };
+
+            if let Some(other) = names.insert(&dep_package.name, &dep.pkg) {
+                return Err(format!(
+                    "dependencies {other} and {} would both contribute to the `{}` romfs directory",
+                    dep.pkg, dep_package.name
+                ));
+            }
+
+            // As with `romfs_dir`, `//` is relative to the workspace root
+            let dir = match romfs_dir.strip_prefix("//") {
+                Some(romfs_dir) => metadata.workspace_root.join(romfs_dir),
+                None => dep_package.manifest_path.parent().unwrap().join(romfs_dir),
+            };
+            contributions.push((dep_package.name.clone(), dir));
+        }
+    }
+
+    contributions.sort();
+    Ok(contributions)
+}
+
+/// Get the directory a package contributes to the romfs of its dependents, from
+/// `romfs_contribution` in its `[package.metadata.cargo-3ds]`.
+fn romfs_contribution(metadata: &serde_json::Value) -> Option<&str> {
+    metadata
+        .get("cargo-3ds")
+        .and_then(|config| {
+            config
+                .get("romfs_contribution")
+                .or_else(|| config.get("romfs-contribution"))
+        })
+        .and_then(|dir| dir.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = RomfsManifest::parse(
+            "# comment\n\nassets/sprites -> gfx\n ../font.bcfnt ->  fonts/main.bcfnt \n",
+            Utf8Path::new("/project"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            manifest.mappings,
+            [
+                Mapping {
+                    src: "/project/assets/sprites".into(),
+                    dest: "gfx".into(),
+                },
+                Mapping {
+                    src: "/project/../font.bcfnt".into(),
+                    dest: "fonts/main.bcfnt".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dependency_contribution() {
+        let metadata = serde_json::json!({
+            "cargo-3ds": { "romfs_dir": "examples/romfs", "romfs_contribution": "assets" }
+        });
+        assert_eq!(romfs_contribution(&metadata), Some("assets"));
+
+        // A library's own romfs, e.g. for its examples, isn't contributed
+        let metadata = serde_json::json!({ "cargo-3ds": { "romfs_dir": "examples/romfs" } });
+        assert_eq!(romfs_contribution(&metadata), None);
+        assert_eq!(romfs_contribution(&serde_json::Value::Null), None);
+    }
+
+    #[test]
+    fn merge_dependency_romfs() {
+        let dir = TestDir::new();
+        for file in ["app/data.bin", "fonts/main.bcfnt"] {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let mut manifest = RomfsManifest::default();
+        manifest.add(dir.join("app"), Utf8PathBuf::new());
+        manifest.add(dir.join("fonts"), "fonts".into());
+
+        let mut dests: Vec<_> = manifest
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|(_, dest)| dest)
+            .collect();
+        dests.sort();
+        assert_eq!(dests, ["data.bin", "fonts/main.bcfnt"]);
+
+        // The app's own romfs collides with the `fonts` dependency
+        fs::create_dir_all(dir.join("app/fonts")).unwrap();
+        fs::write(dir.join("app/fonts/main.bcfnt"), b"").unwrap();
+        assert!(manifest.files().is_err());
+    }
+
+    #[test]
+    fn overlay_romfs_base() {
+        let dir = TestDir::new();
+        for file in [
+            "base/config.toml",
+            "base/gfx/logo.t3x",
+            "overlay/config.toml",
+            "overlay/level.bin",
+        ] {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let mut manifest = RomfsManifest::default();
+        manifest.add(dir.join("overlay"), Utf8PathBuf::new());
+        manifest.add_base(dir.join("base"), Utf8PathBuf::new());
+
+        // The overlay's `config.toml` replaces the base's, rather than conflicting
+        let mut files = manifest.files().unwrap();
+        files.sort_by(|(_, a), (_, b)| a.cmp(b));
+        assert_eq!(
+            files,
+            [
+                (dir.join("overlay/config.toml"), "config.toml".into()),
+                (dir.join("base/gfx/logo.t3x"), "gfx/logo.t3x".into()),
+                (dir.join("overlay/level.bin"), "level.bin".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn embed_romfs_image() {
+        let dir = TestDir::new();
+
+        let mut image = IMAGE_HEADER_SIZE.to_le_bytes().to_vec();
+        image.extend([0xAB; 0x24]);
+        fs::write(dir.join("app.romfs"), &image).unwrap();
+        // A full IVFC image, as found in a CXI, can't be embedded
+        fs::write(dir.join("ivfc.romfs"), b"IVFC\0\0\x01\0").unwrap();
+
+        let mut data = vec![0; EXTENDED_HEADER_SIZE];
+        data[..4].copy_from_slice(MAGIC_3DSX);
+        data[4..6].copy_from_slice(&(EXTENDED_HEADER_SIZE as u16).to_le_bytes());
+        fs::write(dir.join("app.3dsx"), &data).unwrap();
+
+        assert!(embed_image(&dir.join("app.3dsx"), &dir.join("ivfc.romfs")).is_err());
+        embed_image(&dir.join("app.3dsx"), &dir.join("app.romfs")).unwrap();
+
+        let embedded = fs::read(dir.join("app.3dsx")).unwrap();
+        assert_eq!(
+            embedded[ROMFS_OFFSET_FIELD..ROMFS_OFFSET_FIELD + 4],
+            (EXTENDED_HEADER_SIZE as u32).to_le_bytes()
+        );
+        assert_eq!(embedded[EXTENDED_HEADER_SIZE..], image);
+
+        // The romfs can only be embedded once
+        assert!(embed_image(&dir.join("app.3dsx"), &dir.join("app.romfs")).is_err());
+    }
+
+    #[test]
+    fn ignore_rules() {
+        let ignore = IgnoreRules::parse(
+            "# editor files\n*.psd\n!keep.psd\n/build/\ndocs/**/*.md\n**/cache\n",
+        );
+
+        for (path, expected) in [
+            ("art/sprite.psd", true),
+            ("art/keep.psd", false),
+            ("art/sprite.png", false),
+            ("build/out.bin", true),
+            ("art/build/out.bin", false),
+            ("build", false),
+            ("docs/readme.md", true),
+            ("docs/a/b/notes.md", true),
+            ("docs/readme.txt", false),
+            ("levels/cache/1.bin", true),
+            ("cache", true),
+        ] {
+            assert_eq!(ignore.is_ignored(Utf8Path::new(path)), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn parse_manifest_err() {
+        for contents in ["no arrow", "a -> /abs", "a -> ../outside", "a -> "] {
+            assert!(
+                RomfsManifest::parse(contents, Utf8Path::new(".")).is_err(),
+                "{contents:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::sync::Mutex;
+
+use camino::Utf8Path;
+
+use crate::quote_command;
+
+/// The commands recorded so far, quoted for a shell script.
+static COMMANDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Record a command run to build the 3dsx, for `--emit-script`. It is run from
+/// its own directory if set, and otherwise from the current directory.
+pub(crate) fn record(command: &Command) {
+    let dir = command
+        .get_current_dir()
+        .map(|dir| dir.to_path_buf())
+        .or_else(|| env::current_dir().ok());
+
+    let mut lines = Vec::new();
+    if let Some(dir) = dir {
+        lines.push(format!(
+            "cd {}",
+            shlex::try_quote(&dir.to_string_lossy()).unwrap()
+        ));
+    }
+
+    let (envs, cmd_str) = quote_command(command);
+    lines.push(
+        envs.into_iter()
+            .chain([cmd_str])
+            .collect::<Vec<_>>()
+            .join(" \\\n    "),
+    );
+
+    COMMANDS.lock().unwrap().push(lines.join("\n"));
+}
+
+/// Write every recorded command to an executable shell script at `path`.
+pub(crate) fn write(path: &Utf8Path) -> Result<(), String> {
+    let script = script(&COMMANDS.lock().unwrap());
+    fs::write(path, script).map_err(|err| format!("unable to write script {path}: {err}"))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .map_err(|err| format!("unable to make script {path} executable: {err}"))?;
+    }
+
+    Ok(())
+}
+
+fn script(commands: &[String]) -> String {
+    let mut script = String::from(
+        "#!/bin/sh\n\
+        # The commands run by `cargo 3ds` to build the 3dsx, written by `--emit-script`.\n\
+        set -e\n",
+    );
+    for command in commands {
+        script.push('\n');
+        script.push_str(command);
+        script.push('\n');
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_commands() {
+        let mut command = Command::new("3dsxtool");
+        command
+            .current_dir("/my project")
+            .env("RUSTFLAGS", "-C opt-level=s")
+            .args(["app.elf", "app.3dsx", "--smdh=app.smdh"]);
+        record(&command);
+
+        let script = script(&COMMANDS.lock().unwrap());
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.ends_with(
+            "\ncd '/my project'\n\
+            RUSTFLAGS='-C opt-level=s' \\\n    \
+            3dsxtool app.elf app.3dsx '--smdh=app.smdh'\n"
+        ));
+    }
+}
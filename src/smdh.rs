@@ -0,0 +1,436 @@
+use std::fs;
+
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+
+/// The magic number at the start of every SMDH file.
+pub(crate) const MAGIC: &[u8] = b"SMDH";
+
+/// The size of an SMDH file, including both icons.
+pub(crate) const SMDH_SIZE: usize = 0x36C0;
+
+/// Offset of the application titles, of which there is one per language, and
+/// the index of the English one. See <https://www.3dbrew.org/wiki/SMDH#Application_Titles>.
+const TITLES_OFFSET: usize = 0x8;
+const TITLE_SIZE: usize = 0x200;
+const ENGLISH_TITLE: usize = 1;
+
+/// Offset of the region lockout bitmask (a little-endian `u32`) in an SMDH file.
+/// See <https://www.3dbrew.org/wiki/SMDH#Region_Lockout>.
+const REGION_LOCKOUT_OFFSET: usize = 0x2018;
+
+/// Offset of the application settings flags (a little-endian `u32`) in an SMDH file.
+/// See <https://www.3dbrew.org/wiki/SMDH#Flags>.
+const FLAGS_OFFSET: usize = 0x2028;
+
+/// Offset of the small and large icons, which fill the rest of the SMDH.
+/// See <https://www.3dbrew.org/wiki/SMDH#Icon_graphics>.
+const ICONS_OFFSET: usize = 0x2040;
+
+/// The region lockout value for a region free application.
+const REGION_FREE: u32 = 0x7FFF_FFFF;
+
+/// The name and bit of each region in the region lockout bitmask.
+const REGION_BITS: [(&str, u32); 7] = [
+    ("Japan", 0x01),
+    ("North America", 0x02),
+    ("Europe", 0x04),
+    ("Australia", 0x08),
+    ("China", 0x10),
+    ("Korea", 0x20),
+    ("Taiwan", 0x40),
+];
+
+/// The name (as in `[package.metadata.cargo-3ds.flags]`) and bit of each
+/// application settings flag.
+const FLAG_BITS: [(&str, u32); 11] = [
+    ("visible", 0x0001),
+    ("auto_boot", 0x0002),
+    ("allow_3d", 0x0004),
+    ("require_eula", 0x0008),
+    ("autosave", 0x0010),
+    ("extended_banner", 0x0020),
+    ("rating_required", 0x0040),
+    ("uses_save_data", 0x0080),
+    ("record_usage", 0x0100),
+    ("no_save_backups", 0x0400),
+    ("new_3ds", 0x1000),
+];
+
+/// Maximum lengths of the SMDH text fields, in UTF-16 code units.
+/// See <https://www.3dbrew.org/wiki/SMDH#Application_Titles>.
+pub(crate) const SHORT_DESCRIPTION_LEN: usize = 0x40;
+pub(crate) const LONG_DESCRIPTION_LEN: usize = 0x80;
+pub(crate) const PUBLISHER_LEN: usize = 0x40;
+
+/// Ensure `value` fits in an SMDH text field of `max_len` UTF-16 code units.
+///
+/// # Errors
+///
+/// Returns an error describing the problem if `value` is too long, unless
+/// `truncate` is set, in which case it is shortened with a warning instead.
+pub(crate) fn fit_field(
+    field: &str,
+    value: &str,
+    max_len: usize,
+    truncate: bool,
+) -> Result<String, String> {
+    let len = value.encode_utf16().count();
+    if len <= max_len {
+        return Ok(value.to_string());
+    }
+
+    if !truncate {
+        return Err(format!(
+            "SMDH {field} is {len} UTF-16 code units long, but at most {max_len} are allowed: \
+            {value:?}\nShorten it, or pass `--truncate-smdh` to truncate it automatically"
+        ));
+    }
+
+    let mut units = 0;
+    let truncated: String = value
+        .chars()
+        .take_while(|c| {
+            units += c.len_utf16();
+            units <= max_len
+        })
+        .collect();
+
+    warn!("SMDH {field} truncated to {max_len} UTF-16 code units: {truncated:?}");
+    Ok(truncated)
+}
+
+/// The width and height of the icon given to `smdhtool`, in pixels.
+pub(crate) const ICON_SIZE: u32 = 48;
+
+/// The signature at the start of every PNG file, which is followed by the
+/// `IHDR` chunk holding the image size. See <https://www.w3.org/TR/png/#11IHDR>.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_SIZE_OFFSET: usize = 16;
+
+/// Check that the icon at `path` is a PNG image of [`ICON_SIZE`] pixels square.
+pub(crate) fn check_icon(path: &Utf8Path) -> Result<(), String> {
+    let data = fs::read(path).map_err(|err| format!("unable to read icon {path}: {err}"))?;
+    check_icon_data(&data).map_err(|err| format!("icon {path} {err}"))
+}
+
+/// Check that the file at `path` looks like an SMDH, with the right magic number
+/// and size, e.g. for a prebuilt SMDH passed to `3dsxtool` as is.
+pub(crate) fn check_smdh(path: &Utf8Path) -> Result<(), String> {
+    let data = fs::read(path).map_err(|err| format!("unable to read SMDH {path}: {err}"))?;
+    if !data.starts_with(MAGIC) {
+        Err(format!(
+            "{path} is not an SMDH file, it's missing the SMDH magic number"
+        ))
+    } else if data.len() != SMDH_SIZE {
+        Err(format!(
+            "SMDH {path} is {} bytes, but it must be {SMDH_SIZE} bytes",
+            data.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that `data` is a PNG image of [`ICON_SIZE`] pixels square.
+pub(crate) fn check_icon_data(data: &[u8]) -> Result<(), String> {
+    match png_size(data) {
+        Some((ICON_SIZE, ICON_SIZE)) => Ok(()),
+        Some((width, height)) => Err(format!(
+            "must be {ICON_SIZE}x{ICON_SIZE} pixels, got {width}x{height}"
+        )),
+        None => Err(String::from("is not a PNG image")),
+    }
+}
+
+/// Get the width and height of a PNG image from its header.
+fn png_size(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(PNG_SIGNATURE) || data.get(12..16) != Some(b"IHDR") {
+        return None;
+    }
+
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    Some((u32_at(PNG_SIZE_OFFSET)?, u32_at(PNG_SIZE_OFFSET + 4)?))
+}
+
+/// The boolean application settings of the SMDH. `smdhtool` doesn't accept these
+/// as arguments, so they are patched into its output instead. Any flag which is
+/// not set keeps the default value written by `smdhtool`.
+#[derive(Default, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub(crate) struct SmdhFlags {
+    /// Whether the application is visible in the HOME Menu.
+    visible: Option<bool>,
+
+    /// Whether the gamecard is automatically booted when inserted.
+    #[serde(alias = "auto-boot")]
+    auto_boot: Option<bool>,
+
+    /// Whether the application is allowed to use the 3D effect.
+    #[serde(alias = "allow-3d")]
+    allow_3d: Option<bool>,
+
+    /// Whether the user must accept the CTR EULA before launching the application.
+    #[serde(alias = "require-eula")]
+    require_eula: Option<bool>,
+
+    /// Whether the application should be saved when closed from the HOME Menu.
+    autosave: Option<bool>,
+
+    /// Whether the application uses an extended banner.
+    #[serde(alias = "extended-banner")]
+    extended_banner: Option<bool>,
+
+    /// Whether a region game rating is required.
+    #[serde(alias = "rating-required")]
+    rating_required: Option<bool>,
+
+    /// Whether the application uses save data.
+    #[serde(alias = "uses-save-data")]
+    uses_save_data: Option<bool>,
+
+    /// Whether application usage is recorded in the Activity Log.
+    #[serde(alias = "record-usage")]
+    record_usage: Option<bool>,
+
+    /// Whether SD card save data backups are disabled.
+    #[serde(alias = "no-save-backups")]
+    no_save_backups: Option<bool>,
+
+    /// Whether the application is exclusive to the New 3DS.
+    #[serde(alias = "new-3ds")]
+    new_3ds: Option<bool>,
+}
+
+impl SmdhFlags {
+    /// Each configured flag and its bit in the SMDH flags field.
+    fn bits(&self) -> impl Iterator<Item = (u32, bool)> {
+        // In the same order as `FLAG_BITS`
+        let values = [
+            self.visible,
+            self.auto_boot,
+            self.allow_3d,
+            self.require_eula,
+            self.autosave,
+            self.extended_banner,
+            self.rating_required,
+            self.uses_save_data,
+            self.record_usage,
+            self.no_save_backups,
+            self.new_3ds,
+        ];
+
+        FLAG_BITS
+            .into_iter()
+            .zip(values)
+            .filter_map(|((_, bit), value)| Some((bit, value?)))
+    }
+
+    /// Merge two sets of flags, using each flag from `self` if set, or from `fallback` otherwise.
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        Self {
+            visible: self.visible.or(fallback.visible),
+            auto_boot: self.auto_boot.or(fallback.auto_boot),
+            allow_3d: self.allow_3d.or(fallback.allow_3d),
+            require_eula: self.require_eula.or(fallback.require_eula),
+            autosave: self.autosave.or(fallback.autosave),
+            extended_banner: self.extended_banner.or(fallback.extended_banner),
+            rating_required: self.rating_required.or(fallback.rating_required),
+            uses_save_data: self.uses_save_data.or(fallback.uses_save_data),
+            record_usage: self.record_usage.or(fallback.record_usage),
+            no_save_backups: self.no_save_backups.or(fallback.no_save_backups),
+            new_3ds: self.new_3ds.or(fallback.new_3ds),
+        }
+    }
+
+    /// Whether any flags are configured at all.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bits().next().is_none()
+    }
+
+    /// Apply the configured flags on top of existing flags.
+    fn apply(&self, mut flags: u32) -> u32 {
+        for (bit, value) in self.bits() {
+            if value {
+                flags |= bit;
+            } else {
+                flags &= !bit;
+            }
+        }
+
+        flags
+    }
+
+    /// Overwrite the flags of the SMDH file at `path` with the configured ones.
+    pub(crate) fn patch(&self, path: &Utf8Path) -> Result<(), String> {
+        let mut smdh = fs::read(path).map_err(|err| format!("unable to read {path}: {err}"))?;
+
+        let Some(field) = smdh.get_mut(FLAGS_OFFSET..FLAGS_OFFSET + 4) else {
+            return Err(format!("{path} is too small to be a valid SMDH file"));
+        };
+
+        let flags = u32::from_le_bytes(field.try_into().unwrap());
+        field.copy_from_slice(&self.apply(flags).to_le_bytes());
+
+        fs::write(path, smdh).map_err(|err| format!("unable to write {path}: {err}"))
+    }
+}
+
+/// The metadata of an existing SMDH file, as shown by `cargo 3ds inspect`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct Smdh {
+    pub(crate) version: u16,
+    /// The English title, which `smdhtool` uses for every language.
+    pub(crate) title: String,
+    pub(crate) description: String,
+    pub(crate) publisher: String,
+    /// The regions the application is locked to, or `["region free"]`.
+    pub(crate) regions: Vec<&'static str>,
+    /// The names of the application settings flags which are set.
+    pub(crate) flags: Vec<&'static str>,
+    /// Whether the icons contain any image data.
+    pub(crate) has_icon: bool,
+}
+
+impl Smdh {
+    /// Parse the contents of an SMDH file.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(MAGIC) {
+            return Err(String::from("missing SMDH magic number"));
+        }
+        if data.len() < SMDH_SIZE {
+            return Err(format!(
+                "expected {SMDH_SIZE} bytes of SMDH data, found {}",
+                data.len()
+            ));
+        }
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let title = TITLES_OFFSET + ENGLISH_TITLE * TITLE_SIZE;
+        let long_description = title + 2 * SHORT_DESCRIPTION_LEN;
+        let publisher = long_description + 2 * LONG_DESCRIPTION_LEN;
+
+        let region_lockout = u32_at(REGION_LOCKOUT_OFFSET);
+        let regions = if region_lockout == REGION_FREE {
+            vec!["region free"]
+        } else {
+            REGION_BITS
+                .into_iter()
+                .filter(|&(_, bit)| region_lockout & bit != 0)
+                .map(|(name, _)| name)
+                .collect()
+        };
+
+        let flags = u32_at(FLAGS_OFFSET);
+
+        Ok(Self {
+            version: u16::from_le_bytes([data[4], data[5]]),
+            title: read_utf16(&data[title..long_description]),
+            description: read_utf16(&data[long_description..publisher]),
+            publisher: read_utf16(&data[publisher..publisher + 2 * PUBLISHER_LEN]),
+            regions,
+            flags: FLAG_BITS
+                .into_iter()
+                .filter(|&(_, bit)| flags & bit != 0)
+                .map(|(name, _)| name)
+                .collect(),
+            has_icon: data[ICONS_OFFSET..SMDH_SIZE].iter().any(|&b| b != 0),
+        })
+    }
+}
+
+/// Read a NUL-terminated UTF-16LE string.
+fn read_utf16(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn icon_png_size() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend(13u32.to_be_bytes());
+        png.extend(b"IHDR");
+        png.extend(48u32.to_be_bytes());
+        png.extend(32u32.to_be_bytes());
+        assert_eq!(png_size(&png), Some((48, 32)));
+
+        assert_eq!(png_size(&png[..20]), None);
+        assert_eq!(png_size(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn fit_fields() {
+        assert_eq!(fit_field("publisher", "abc", 3, false).unwrap(), "abc");
+        assert!(fit_field("publisher", "abcd", 3, false).is_err());
+        assert_eq!(fit_field("publisher", "abcd", 3, true).unwrap(), "abc");
+        // U+1F980 takes two UTF-16 code units, so it can't fit in the last one
+        assert_eq!(
+            fit_field("publisher", "ab\u{1F980}", 3, true).unwrap(),
+            "ab"
+        );
+    }
+
+    #[test]
+    fn parse_smdh() {
+        let mut data = vec![0; SMDH_SIZE];
+        data[..4].copy_from_slice(MAGIC);
+
+        let mut write_utf16 = |offset: usize, value: &str| {
+            for (i, unit) in value.encode_utf16().enumerate() {
+                data[offset + 2 * i..][..2].copy_from_slice(&unit.to_le_bytes());
+            }
+        };
+        let title = TITLES_OFFSET + ENGLISH_TITLE * TITLE_SIZE;
+        write_utf16(title, "Hello");
+        write_utf16(title + 0x80, "A greeting");
+        write_utf16(title + 0x180, "Jane Doe");
+
+        data[REGION_LOCKOUT_OFFSET..][..4].copy_from_slice(&0x06u32.to_le_bytes());
+        data[FLAGS_OFFSET..][..4].copy_from_slice(&0x1081u32.to_le_bytes());
+
+        let smdh = Smdh::parse(&data).unwrap();
+        assert_eq!(
+            smdh,
+            Smdh {
+                version: 0,
+                title: "Hello".into(),
+                description: "A greeting".into(),
+                publisher: "Jane Doe".into(),
+                regions: vec!["North America", "Europe"],
+                flags: vec!["visible", "uses_save_data", "new_3ds"],
+                has_icon: false,
+            }
+        );
+
+        assert!(Smdh::parse(&data[..0x100]).is_err());
+        assert!(Smdh::parse(&[0; SMDH_SIZE]).is_err());
+    }
+
+    #[test]
+    fn apply_flags() {
+        let flags = SmdhFlags {
+            visible: Some(false),
+            allow_3d: Some(true),
+            new_3ds: Some(true),
+            ..Default::default()
+        };
+
+        assert_eq!(flags.apply(0x0001 | 0x0080), 0x0004 | 0x0080 | 0x1000);
+        assert!(!flags.is_empty());
+        assert!(SmdhFlags::default().is_empty());
+        assert_eq!(SmdhFlags::default().apply(0x0081), 0x0081);
+    }
+}
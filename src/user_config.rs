@@ -0,0 +1,178 @@
+//! The user-level configuration file, with defaults for settings a user wants
+//! in every project, e.g. their emulator or the address of their device.
+//!
+//! These are the lowest-priority settings: command-line flags, environment
+//! variables and the package metadata all take precedence over them.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+/// The environment variable which may override the location of the user config.
+pub(crate) const PATH_ENV: &str = "CARGO_3DS_USER_CONFIG";
+
+/// The tools whose paths may be set in `tool_paths`.
+const TOOLS: &[&str] = &["smdhtool", "3dsxtool", "3dslink", "arm-none-eabi-gdb"];
+
+#[derive(Default, Debug, Deserialize, PartialEq, Eq)]
+pub(crate) struct UserConfig {
+    /// The emulator to run executables in with `--emulator`, instead of `azahar`.
+    #[serde(alias = "emulator-path")]
+    pub(crate) emulator_path: Option<String>,
+
+    /// The address of the device to send executables to when neither
+    /// `--address` nor `--device-name` is given, instead of discovering it.
+    #[serde(alias = "default-address")]
+    pub(crate) default_address: Option<Ipv4Addr>,
+
+    /// The message format to use when none is passed on the command line.
+    #[serde(alias = "message-format")]
+    pub(crate) message_format: Option<String>,
+
+    /// The paths to the tools used by `cargo-3ds`, keyed by their usual name:
+    /// `smdhtool`, `3dsxtool`, `3dslink` or `arm-none-eabi-gdb`.
+    #[serde(alias = "tool-paths", default)]
+    pub(crate) tool_paths: BTreeMap<String, PathBuf>,
+}
+
+impl UserConfig {
+    /// Get the user config, which is read once from [`path`]. A missing file is
+    /// the same as an empty one, and an invalid one is ignored with a warning.
+    /// Tests never read it, so they don't depend on the machine they run on.
+    pub(crate) fn get() -> &'static Self {
+        static CONFIG: OnceLock<UserConfig> = OnceLock::new();
+
+        CONFIG.get_or_init(|| {
+            let Some(path) = path().filter(|_| !cfg!(test)) else {
+                return Self::default();
+            };
+
+            match fs::read_to_string(&path) {
+                Ok(contents) => match toml::from_str::<Self>(&contents) {
+                    Ok(config) => {
+                        for tool in config.unknown_tools() {
+                            warn!(
+                                "ignoring unknown tool `{tool}` in the `tool_paths` of user config \
+                                {}, expected one of: {}",
+                                path.display(),
+                                TOOLS.join(", ")
+                            );
+                        }
+                        config
+                    }
+                    Err(err) => {
+                        warn!("ignoring invalid user config {}: {err}", path.display());
+                        Self::default()
+                    }
+                },
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+                Err(err) => {
+                    warn!("unable to read user config {}: {err}", path.display());
+                    Self::default()
+                }
+            }
+        })
+    }
+
+    /// Get the names in `tool_paths` which aren't tools used by `cargo-3ds`,
+    /// e.g. misspelled ones.
+    fn unknown_tools(&self) -> impl Iterator<Item = &str> {
+        self.tool_paths
+            .keys()
+            .map(String::as_str)
+            .filter(|tool| !TOOLS.contains(tool))
+    }
+
+    /// Get the program to run for `tool`: the path set in `tool_paths`, or else
+    /// the tool's own name, to be looked up in `$PATH`.
+    pub(crate) fn tool(&self, tool: &str) -> OsString {
+        self.tool_paths.get(tool).map_or_else(
+            || OsString::from(tool),
+            |path| path.clone().into_os_string(),
+        )
+    }
+}
+
+/// Get the path to the user config: the one in [`PATH_ENV`] if set, or else
+/// `cargo-3ds/config.toml` in the user's configuration directory.
+pub(crate) fn path() -> Option<PathBuf> {
+    match env::var_os(PATH_ENV) {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => config_dir(|var| env::var_os(var)).map(|dir| dir.join("cargo-3ds/config.toml")),
+    }
+}
+
+/// Get the user's configuration directory from the environment variables
+/// given by `var`: `%APPDATA%` on Windows, `~/Library/Application Support` on
+/// macOS, and `$XDG_CONFIG_HOME` or `~/.config` elsewhere.
+fn config_dir(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    let var = |name| {
+        var(name)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    };
+
+    if cfg!(windows) {
+        var("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        var("XDG_CONFIG_HOME").or_else(|| var("HOME").map(|home| home.join(".config")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_user_config() {
+        let config: UserConfig = toml::from_str(
+            r#"
+            emulator-path = "/opt/azahar/azahar"
+            default_address = "192.168.0.2"
+
+            [tool_paths]
+            3dslink = "/opt/devkitpro/tools/bin/3dslink"
+            3dsxtools = "/opt/devkitpro/tools/bin/3dsxtool"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.emulator_path.as_deref(), Some("/opt/azahar/azahar"));
+        assert_eq!(config.default_address, Some(Ipv4Addr::new(192, 168, 0, 2)));
+        assert_eq!(config.message_format, None);
+        assert_eq!(config.tool("3dslink"), "/opt/devkitpro/tools/bin/3dslink");
+        assert_eq!(config.tool("smdhtool"), "smdhtool");
+        assert_eq!(config.unknown_tools().collect::<Vec<_>>(), ["3dsxtools"]);
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn user_config_dir() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        };
+
+        assert_eq!(
+            config_dir(env(&[("XDG_CONFIG_HOME", "/xdg"), ("HOME", "/home/me")])),
+            Some(PathBuf::from("/xdg"))
+        );
+        assert_eq!(
+            config_dir(env(&[("XDG_CONFIG_HOME", ""), ("HOME", "/home/me")])),
+            Some(PathBuf::from("/home/me/.config"))
+        );
+        assert_eq!(config_dir(env(&[])), None);
+    }
+}